@@ -0,0 +1,66 @@
+//! Times `encode_batch` sequentially and on a pool for growing batch sizes and prints the first
+//! size at which the pool wins. Run with
+//! `cargo bench -p text-embeddings-core --bench batch_parallelism`.
+use hf_hub::api::sync::ApiBuilder;
+use std::time::{Duration, Instant};
+use text_embeddings_core::tokenization::{EncodingInput, Tokenization};
+use tokenizers::{Tokenizer, TruncationDirection, TruncationStrategy};
+
+const ITERATIONS: u32 = 20;
+
+fn time(tokenization: &Tokenization, inputs: &[EncodingInput]) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        futures::executor::block_on(tokenization.encode_batch(
+            inputs.to_vec(),
+            false,
+            TruncationDirection::Right,
+            TruncationStrategy::LongestFirst,
+            None,
+            None,
+            false,
+            false,
+        ))
+        .unwrap();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let api = ApiBuilder::from_env().build().unwrap();
+    let filename = api
+        .model("BAAI/bge-m3".to_string())
+        .get("tokenizer.json")
+        .unwrap();
+    let tokenizer = Tokenizer::from_file(filename).unwrap();
+
+    let build = |batch_parallelism| {
+        Tokenization::builder(tokenizer.clone(), 1, 512)
+            .batch_parallelism(batch_parallelism)
+            .metrics(false)
+            .build()
+            .unwrap()
+    };
+    let sequential = build(1);
+    let parallel = build(4);
+
+    let mut crossover = None;
+    for batch_size in [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048] {
+        // Inputs are distinct so that deduplication does not shrink the batch
+        let inputs: Vec<EncodingInput> = (0..batch_size)
+            .map(|i| EncodingInput::from(format!("What is Deep Learning? ({i})")))
+            .collect();
+        // Warm up both paths
+        time(&sequential, &inputs);
+        time(&parallel, &inputs);
+        let sequential_time = time(&sequential, &inputs);
+        let parallel_time = time(&parallel, &inputs);
+        println!(
+            "{batch_size:>5} inputs: sequential {sequential_time:?}, batch_parallelism 4 {parallel_time:?}"
+        );
+        if crossover.is_none() && parallel_time < sequential_time {
+            crossover = Some(batch_size);
+        }
+    }
+    println!("crossover batch size: {crossover:?}");
+}
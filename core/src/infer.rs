@@ -1,5 +1,5 @@
 use crate::queue::{Entry, Metadata, NextBatch, Queue};
-use crate::tokenization::{EncodingInput, RawEncoding, Tokenization};
+use crate::tokenization::{EncodeOptions, EncodingInput, RequestId, Tokenization, Tokenized};
 use crate::TextEmbeddingsError;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -61,9 +61,18 @@ impl Infer {
         inputs: I,
         add_special_tokens: bool,
         prompt_name: Option<String>,
-    ) -> Result<(Option<String>, RawEncoding), TextEmbeddingsError> {
+        request_id: Option<RequestId>,
+    ) -> Result<Tokenized, TextEmbeddingsError> {
         self.tokenization
-            .tokenize(inputs.into(), add_special_tokens, prompt_name)
+            .tokenize(
+                inputs.into(),
+                add_special_tokens,
+                prompt_name,
+                None,
+                None,
+                request_id,
+                None,
+            )
             .await
             .map_err(|err| {
                 let counter = metrics::counter!("te_request_failure", "err" => "tokenization");
@@ -80,7 +89,7 @@ impl Infer {
         skip_special_tokens: bool,
     ) -> Result<String, TextEmbeddingsError> {
         self.tokenization
-            .decode(ids, skip_special_tokens)
+            .decode(ids, skip_special_tokens, None)
             .await
             .map_err(|err| {
                 let counter = metrics::counter!("te_request_failure", "err" => "tokenization");
@@ -121,6 +130,7 @@ impl Infer {
         truncate: bool,
         truncation_direction: TruncationDirection,
         prompt_name: Option<String>,
+        request_id: Option<RequestId>,
         permit: OwnedSemaphorePermit,
     ) -> Result<AllEmbeddingsInferResponse, TextEmbeddingsError> {
         let start_time = Instant::now();
@@ -143,6 +153,7 @@ impl Infer {
                 prompt_name,
                 false,
                 &start_time,
+                request_id,
                 permit,
             )
             .await?;
@@ -176,6 +187,7 @@ impl Infer {
         truncate: bool,
         truncation_direction: TruncationDirection,
         prompt_name: Option<String>,
+        request_id: Option<RequestId>,
         permit: OwnedSemaphorePermit,
     ) -> Result<PooledEmbeddingsInferResponse, TextEmbeddingsError> {
         let start_time = Instant::now();
@@ -198,6 +210,7 @@ impl Infer {
                 prompt_name,
                 true,
                 &start_time,
+                request_id,
                 permit,
             )
             .await?;
@@ -232,6 +245,7 @@ impl Infer {
         truncation_direction: TruncationDirection,
         prompt_name: Option<String>,
         normalize: bool,
+        request_id: Option<RequestId>,
         permit: OwnedSemaphorePermit,
     ) -> Result<PooledEmbeddingsInferResponse, TextEmbeddingsError> {
         let start_time = Instant::now();
@@ -254,6 +268,7 @@ impl Infer {
                 prompt_name,
                 true,
                 &start_time,
+                request_id,
                 permit,
             )
             .await?;
@@ -306,6 +321,7 @@ impl Infer {
         prompt_name: Option<String>,
         pooling: bool,
         start_time: &Instant,
+        request_id: Option<RequestId>,
         _permit: OwnedSemaphorePermit,
     ) -> Result<InferResult, TextEmbeddingsError> {
         if self.is_classifier() {
@@ -324,7 +340,16 @@ impl Infer {
         // Tokenization
         let encoding = self
             .tokenization
-            .encode(inputs.into(), truncate, truncation_direction, prompt_name)
+            .encode(
+                inputs.into(),
+                EncodeOptions {
+                    overflow_policy: truncate.into(),
+                    truncation_direction,
+                    prompt_name,
+                    request_id,
+                    ..Default::default()
+                },
+            )
             .await
             .map_err(|err| {
                 let counter = metrics::counter!("te_request_failure", "err" => "tokenization");
@@ -372,6 +397,7 @@ impl Infer {
         truncate: bool,
         truncation_direction: TruncationDirection,
         raw_scores: bool,
+        request_id: Option<RequestId>,
         _permit: OwnedSemaphorePermit,
     ) -> Result<ClassificationInferResponse, TextEmbeddingsError> {
         if !self.is_classifier() {
@@ -390,7 +416,15 @@ impl Infer {
         // Tokenization
         let encoding = self
             .tokenization
-            .encode(inputs.into(), truncate, truncation_direction, None)
+            .encode(
+                inputs.into(),
+                EncodeOptions {
+                    overflow_policy: truncate.into(),
+                    truncation_direction,
+                    request_id,
+                    ..Default::default()
+                },
+            )
             .await
             .map_err(|err| {
                 let counter = metrics::counter!("te_request_failure", "err" => "tokenization");
@@ -489,7 +523,7 @@ impl Infer {
 
     #[instrument(skip(self))]
     pub async fn health(&self) -> bool {
-        self.backend.health().await.is_ok()
+        self.tokenization.is_healthy() && self.backend.health().await.is_ok()
     }
 
     #[instrument(skip(self))]
@@ -12,9 +12,64 @@ pub enum TextEmbeddingsError {
     #[error("tokenizer error {0}")]
     Tokenizer(#[from] tokenizers::Error),
     #[error("Input validation error: {0}")]
-    Validation(String),
+    Validation(ValidationError),
     #[error("Model is overloaded")]
     Overloaded(#[from] TryAcquireError),
     #[error("Backend error: {0}")]
     Backend(#[from] BackendError),
+    #[error("Tokenization workers are not running")]
+    WorkersGone,
+    #[error("Tokenization did not finish in time")]
+    Timeout,
+}
+
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    #[error("`inputs` cannot be empty")]
+    Empty,
+    #[error("`inputs` must have less than {limit} characters. Given: {given}")]
+    TooManyChars { limit: usize, given: usize },
+    /// `truncation_would_fit` is `true` when truncation was disabled and the input would fit
+    /// once truncated
+    #[error("{}", too_many_tokens_message(*.limit, *.given, *.truncation_would_fit))]
+    TooManyTokens {
+        limit: usize,
+        given: usize,
+        truncation_would_fit: bool,
+    },
+    #[error("`inputs` must have at least {minimum} tokens besides the special and prompt tokens. Given: {given}")]
+    TooFewTokens { minimum: usize, given: usize },
+    /// `segment` is the index of the empty segment of a `Dual` input, the other one is not empty
+    #[error("Segment {segment} of the pair is empty")]
+    EmptySegment { segment: usize },
+    /// `available` is `None` when no prompts are configured. `suggestion` is the available prompt
+    /// closest to `name`, if any is close enough; the message only lists all of them otherwise.
+    #[error("{}", unknown_prompt_message(.name, .available.as_deref(), .suggestion.as_deref()))]
+    UnknownPrompt {
+        name: String,
+        available: Option<Vec<String>>,
+        suggestion: Option<String>,
+    },
+    #[error("{0}")]
+    Other(String),
+}
+
+fn too_many_tokens_message(limit: usize, given: usize, truncation_would_fit: bool) -> String {
+    let message = format!("`inputs` must have less than {limit} tokens. Given: {given}");
+    match truncation_would_fit {
+        true => format!("{message}. Truncation is disabled: set `truncate` to `true` to truncate the input instead"),
+        false => message,
+    }
+}
+
+fn unknown_prompt_message(
+    name: &str,
+    available: Option<&[String]>,
+    suggestion: Option<&str>,
+) -> String {
+    match (available, suggestion) {
+        (None, _) => format!("`default-prompt-name` is set to `{name}` but no prompts were found in the Sentence Transformers configuration"),
+        (Some(_), Some(suggestion)) => format!("`default-prompt-name` is set to `{name}` but it was not found in the Sentence Transformers prompts. Did you mean `{suggestion}`?"),
+        (Some(available), None) => format!("`default-prompt-name` is set to `{name}` but it was not found in the Sentence Transformers prompts. Available prompts: {available:?}"),
+    }
 }
@@ -1,19 +1,109 @@
 /// Payload tokenization logic
-use crate::TextEmbeddingsError;
-use std::collections::HashMap;
+use crate::{TextEmbeddingsError, ValidationError};
+#[cfg(feature = "clap")]
+use clap::ValueEnum;
+use futures::{Stream, StreamExt};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::tokenizer::normalizer::Range as NormalizedRange;
 use tokenizers::tokenizer::Tokenizer;
+use tokenizers::utils::truncation::truncate_encodings;
 pub use tokenizers::Encoding as RawEncoding;
-use tokenizers::{TruncationDirection, TruncationParams, TruncationStrategy};
-use tokio::sync::oneshot;
+use tokenizers::{
+    Decoder, DecoderWrapper, EncodeInput, NormalizedString, PaddingDirection, PaddingParams,
+    PostProcessor, PostProcessorWrapper, Token, TruncationDirection, TruncationParams,
+    TruncationStrategy,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::span::EnteredSpan;
 use tracing::{instrument, Span};
 
-static MAX_CHAR_MULTIPLIER: usize = 250;
+/// Default maximum number of characters per token allowed before tokenizing
+pub static MAX_CHAR_MULTIPLIER: usize = 250;
+
+/// Time given to the workers to answer `ping`
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Encoded and decoded by `build`: words, digits, punctuation and accents go through most of the normalizer
+/// and pre-tokenizer rules
+const WARMUP_INPUT: &str = "Warm-up: What is Deep Learning? Café, naïve 12.5% (2024) déjà vu!";
+
+/// One request in `INPUT_CHARS_SAMPLING` gets its chars counted for `te_request_input_chars`
+const INPUT_CHARS_SAMPLING: usize = 16;
+static INPUT_CHARS_SAMPLES: AtomicUsize = AtomicUsize::new(0);
 
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Tokenization {
     /// Channel to communicate with the background tokenization task
     sender: async_channel::Sender<TokenizerRequest>,
+    /// Handles of the worker threads
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Number of workers that did not panic
+    live_workers: Arc<AtomicUsize>,
+    /// Maximum number of tokens of the inputs, read by the workers on every request
+    max_input_length: Arc<AtomicUsize>,
+    /// Channel to the workers dedicated to the decode requests, if any
+    decode_sender: Option<async_channel::Sender<TokenizerRequest>>,
+    /// Handles of the decode worker threads
+    decode_workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Number of decode workers that did not panic
+    live_decode_workers: Arc<AtomicUsize>,
+    /// Cache of the most recent `encode` results
+    cache: Option<Arc<Mutex<EncodingCache>>>,
+    /// Ids of the special tokens of the tokenizer
+    special_tokens: SpecialTokens,
+    /// Whether to emit metrics
+    metrics: bool,
+}
+
+/// Ids of the special tokens of the tokenizer, `None` for the ones it does not define
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpecialTokens {
+    pub pad: Option<u32>,
+    pub bos: Option<u32>,
+    pub eos: Option<u32>,
+    pub unk: Option<u32>,
+    pub cls: Option<u32>,
+    pub sep: Option<u32>,
+}
+
+impl SpecialTokens {
+    /// `tokenizer.json` has no roles for its special tokens: they are found by their conventional
+    /// names among the tokens marked as special. The padding configuration is used first for `pad`.
+    fn from_tokenizer(tokenizer: &Tokenizer) -> Self {
+        let find = |names: &[&str]| {
+            let added_vocabulary = tokenizer.get_added_vocabulary();
+            names
+                .iter()
+                .filter(|name| added_vocabulary.is_special_token(name))
+                .find_map(|name| tokenizer.token_to_id(name))
+        };
+        Self {
+            pad: tokenizer
+                .get_padding()
+                .map(|padding| padding.pad_id)
+                .or_else(|| find(&["[PAD]", "<pad>", "<|pad|>"])),
+            bos: find(&["<s>", "<bos>", "<|begin_of_text|>", "<|startoftext|>"]),
+            eos: find(&["</s>", "<eos>", "<|end_of_text|>", "<|endoftext|>"]),
+            unk: find(&["[UNK]", "<unk>", "<|unk|>"]),
+            cls: find(&["[CLS]", "<s>"]),
+            sep: find(&["[SEP]", "</s>"]),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -24,626 +114,8231 @@ pub struct SimpleToken {
     pub special: bool,
     pub start: Option<usize>,
     pub stop: Option<usize>,
+    /// Index of the word this token belongs to. `None` for special tokens
+    pub word_id: Option<u32>,
+    /// Segment of the input the token belongs to, `1` for the second input of a pair
+    pub type_id: u32,
 }
 
-impl Tokenization {
-    pub fn new(
-        workers: usize,
-        tokenizer: Tokenizer,
-        max_input_length: usize,
-        position_offset: usize,
-        default_prompt: Option<String>,
-        prompts: Option<HashMap<String, String>>,
-    ) -> Self {
+impl SimpleToken {
+    /// Whether the token comes from the input rather than being a special token
+    pub fn is_content(&self) -> bool {
+        !self.special
+    }
+}
+
+/// Configuration of the tokenization workers
+#[derive(Debug)]
+pub struct TokenizationBuilder {
+    tokenizer: Tokenizer,
+    workers: usize,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    position_offset: usize,
+    default_prompt: Option<String>,
+    prompts: Option<HashMap<String, String>>,
+    cache_capacity: usize,
+    batch_parallelism: usize,
+    normalization: Option<UnicodeNormalization>,
+    stack_size: Option<usize>,
+    queue_capacity: Option<usize>,
+    truncator: Option<Box<dyn Truncator>>,
+    min_content_tokens: usize,
+    dual_split: Option<DualSplit>,
+    half_empty_dual: HalfEmptyDual,
+    count_prompt_in_limit: bool,
+    max_model_length: Option<usize>,
+    decode_workers: usize,
+    metrics: bool,
+}
+
+impl TokenizationBuilder {
+    pub fn new(tokenizer: Tokenizer, workers: usize, max_input_length: usize) -> Self {
+        Self {
+            tokenizer,
+            workers,
+            max_input_length,
+            max_char_multiplier: MAX_CHAR_MULTIPLIER,
+            position_offset: 0,
+            default_prompt: None,
+            prompts: None,
+            cache_capacity: 0,
+            batch_parallelism: 1,
+            normalization: None,
+            stack_size: None,
+            queue_capacity: None,
+            truncator: None,
+            min_content_tokens: 0,
+            dual_split: None,
+            half_empty_dual: HalfEmptyDual::default(),
+            count_prompt_in_limit: true,
+            max_model_length: None,
+            decode_workers: 0,
+            metrics: true,
+        }
+    }
+
+    /// Maximum number of characters per token allowed before tokenizing. Must be greater than 0.
+    pub fn max_char_multiplier(mut self, max_char_multiplier: usize) -> Self {
+        self.max_char_multiplier = max_char_multiplier;
+        self
+    }
+
+    /// First value of the `position_ids`
+    pub fn position_offset(mut self, position_offset: usize) -> Self {
+        self.position_offset = position_offset;
+        self
+    }
+
+    /// Prompt applied when the request does not select one
+    pub fn default_prompt(mut self, default_prompt: Option<String>) -> Self {
+        self.default_prompt = default_prompt;
+        self
+    }
+
+    /// Prompts selectable by name
+    pub fn prompts(mut self, prompts: Option<HashMap<String, String>>) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Number of `encode` results kept in cache. `0` disables the cache.
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Number of threads encoding a batch. `1` encodes batches sequentially.
+    ///
+    /// The batch size at which the pool starts to pay off depends on the hardware and the
+    /// tokenizer; the `batch_parallelism` bench of this crate measures it.
+    pub fn batch_parallelism(mut self, batch_parallelism: usize) -> Self {
+        self.batch_parallelism = batch_parallelism;
+        self
+    }
+
+    /// Unicode normalization applied to the text inputs before tokenization
+    pub fn normalization(mut self, normalization: Option<UnicodeNormalization>) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Stack size in bytes of the tokenization threads
+    pub fn stack_size(mut self, stack_size: Option<usize>) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// Number of requests waiting for a worker, `workers * 4` by default.
+    /// A small queue applies backpressure to the callers sooner, a large one uses more memory
+    /// and lets requests wait longer, increasing the tail latency. Must be greater than 0.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = Some(queue_capacity);
+        self
+    }
+
+    /// Custom truncation of the single sequence inputs, longest first by default
+    pub fn truncator(mut self, truncator: Box<dyn Truncator>) -> Self {
+        self.truncator = Some(truncator);
+        self
+    }
+
+    /// Reject the inputs with fewer tokens besides the special and prompt tokens, such as
+    /// whitespace only inputs. Tokens decoding to whitespace do not count. `0` disables the check.
+    pub fn min_content_tokens(mut self, min_content_tokens: usize) -> Self {
+        self.min_content_tokens = min_content_tokens;
+        self
+    }
+
+    /// Share of the length budget of each segment of the `Dual` inputs truncated with
+    /// `TruncationStrategy::LongestFirst`. `OnlyFirst` and `OnlySecond` are unaffected.
+    pub fn dual_split(mut self, dual_split: DualSplit) -> Self {
+        self.dual_split = Some(dual_split);
+        self
+    }
+
+    /// What to do with the `Dual` inputs with exactly one empty segment, `HalfEmptyDual::Collapse`
+    /// by default
+    pub fn half_empty_dual(mut self, half_empty_dual: HalfEmptyDual) -> Self {
+        self.half_empty_dual = half_empty_dual;
+        self
+    }
+
+    /// Whether the prompt tokens count against `max_input_length`, `true` by default. When `false`,
+    /// the prompt is fixed overhead: the input alone must fit in `max_input_length` tokens, or is
+    /// truncated to it, so the encodings can exceed `max_input_length` by the prompt length.
+    /// `max_model_length` bounds them.
+    pub fn count_prompt_in_limit(mut self, count_prompt_in_limit: bool) -> Self {
+        self.count_prompt_in_limit = count_prompt_in_limit;
+        self
+    }
+
+    /// Hard limit of the number of tokens of an encoding, the prompt included, for when the prompt
+    /// tokens do not count against `max_input_length`. Unbounded by default, at least
+    /// `max_input_length`.
+    pub fn max_model_length(mut self, max_model_length: usize) -> Self {
+        self.max_model_length = Some(max_model_length);
+        self
+    }
+
+    /// Number of workers dedicated to the decode requests, on their own channel, so that the
+    /// decode latency does not depend on the encode traffic. `0` sends them to the encode workers.
+    pub fn decode_workers(mut self, decode_workers: usize) -> Self {
+        self.decode_workers = decode_workers;
+        self
+    }
+
+    /// Whether to emit metrics
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Validate the configuration and start the workers
+    pub fn build(self) -> Result<Tokenization, TextEmbeddingsError> {
+        let TokenizationBuilder {
+            tokenizer,
+            workers,
+            max_input_length,
+            max_char_multiplier,
+            position_offset,
+            default_prompt,
+            prompts,
+            cache_capacity,
+            batch_parallelism,
+            normalization,
+            stack_size,
+            queue_capacity,
+            truncator,
+            min_content_tokens,
+            dual_split,
+            half_empty_dual,
+            count_prompt_in_limit,
+            max_model_length,
+            decode_workers,
+            metrics,
+        } = self;
+
+        for (name, value) in [
+            ("workers", workers),
+            ("max_input_length", max_input_length),
+            ("max_char_multiplier", max_char_multiplier),
+            ("batch_parallelism", batch_parallelism),
+            ("queue_capacity", queue_capacity.unwrap_or(workers * 4)),
+        ] {
+            if value == 0 {
+                return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                    format!("`{name}` must be greater than 0"),
+                )));
+            }
+        }
+        if let Some(max_model_length) = max_model_length.filter(|&len| len < max_input_length) {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                format!(
+                    "`max_model_length` must be at least {max_input_length}. Given: {max_model_length}"
+                ),
+            )));
+        }
+
+        // Fail fast on malformed templates instead of on every request
+        for prompt in default_prompt
+            .iter()
+            .chain(prompts.iter().flat_map(|p| p.values()))
+        {
+            validate_prompt(prompt)?;
+        }
+
+        let special_tokens = SpecialTokens::from_tokenizer(&tokenizer);
+        if tokenizer.get_decoder().is_none() {
+            tracing::warn!("`tokenizer.json` has no decoder: decoding requests will be rejected");
+        }
+
         tracing::info!("Starting {workers} tokenization workers");
 
         // Create channel
-        let (sender, receiver) = async_channel::bounded(workers * 4);
+        let queue_capacity = queue_capacity.unwrap_or(workers * 4);
+        let (sender, receiver) = async_channel::bounded(queue_capacity);
+        if metrics {
+            let gauge = metrics::gauge!("te_tokenization_queue_capacity");
+            gauge.set(queue_capacity as f64);
+        }
+
+        // All workers share the same tokenizer
+        let mut tokenizer = SharedTokenizer {
+            normalization,
+            metrics,
+            truncator,
+            min_content_tokens,
+            dual_split,
+            half_empty_dual,
+            count_prompt_in_limit,
+            max_model_length,
+            cls_id: special_tokens.cls,
+            ..SharedTokenizer::new(tokenizer)
+        };
+        // The prompts are fixed: their token ids are computed once for the `Ids` inputs
+        for prompt in default_prompt
+            .iter()
+            .chain(prompts.iter().flat_map(|p| p.values()))
+        {
+            let (prefix, suffix) = split_prompt(prompt);
+            let prefix_ids = tokenizer
+                .tokenizer
+                .encode(prefix, false)?
+                .get_ids()
+                .to_vec();
+            let suffix_ids = tokenizer
+                .tokenizer
+                .encode(suffix, false)?
+                .get_ids()
+                .to_vec();
+            tokenizer
+                .prompt_ids
+                .insert(prompt.clone(), (prefix_ids, suffix_ids));
+        }
+        // The tokenizer state is shared by every worker: warming it up once is enough. The
+        // metrics are left out of it.
+        tokenizer.metrics = false;
+        warm_up(
+            &tokenizer,
+            max_input_length,
+            max_char_multiplier,
+            default_prompt.clone(),
+            prompts.as_ref(),
+        )?;
+        tokenizer.metrics = metrics;
+        let tokenizer = Arc::new(tokenizer);
+        let max_input_length = Arc::new(AtomicUsize::new(max_input_length));
+
+        // Batches are encoded sequentially by a single worker unless a pool is available
+        let batch_pool = (batch_parallelism > 1).then(|| {
+            let mut builder = rayon::ThreadPoolBuilder::new()
+                .num_threads(batch_parallelism)
+                .thread_name(|i| format!("tokenization-batch-{i}"));
+            if let Some(stack_size) = stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            Arc::new(
+                builder
+                    .build()
+                    .expect("Failed to build the tokenization batch thread pool"),
+            )
+        });
 
         // Create workers
-        for _ in 0..workers {
+        let spawn_worker = |name: String,
+                            receiver: async_channel::Receiver<TokenizerRequest>,
+                            live_workers: Arc<AtomicUsize>| {
             let tokenizer_clone = tokenizer.clone();
-            let receiver_clone = receiver.clone();
+            let max_input_length_clone = max_input_length.clone();
+            let batch_pool_clone = batch_pool.clone();
             let default_prompt_clone = default_prompt.clone();
             let prompts_clone = prompts.clone();
+            let worker_id = name.clone();
             // Spawn worker
-            std::thread::spawn(move || {
-                tokenizer_worker(
-                    tokenizer_clone,
-                    max_input_length,
-                    position_offset,
-                    default_prompt_clone,
-                    prompts_clone,
-                    receiver_clone,
+            let mut builder = std::thread::Builder::new().name(name);
+            if let Some(stack_size) = stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            builder
+                .spawn(move || {
+                    // Span of the request being processed, reported if the worker panics
+                    let in_flight = RefCell::new(Span::none());
+                    let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        tokenizer_worker(
+                            tokenizer_clone,
+                            batch_pool_clone,
+                            max_input_length_clone,
+                            max_char_multiplier,
+                            position_offset,
+                            default_prompt_clone,
+                            prompts_clone,
+                            receiver,
+                            worker_id,
+                            &in_flight,
+                        )
+                    }));
+                    if let Err(panic) = outcome {
+                        worker_panicked(&panic, &in_flight.into_inner(), &live_workers, metrics);
+                    }
+                })
+                .expect("Failed to spawn a tokenization worker")
+        };
+        let live_workers = Arc::new(AtomicUsize::new(workers));
+        let handles = (0..workers)
+            .map(|i| {
+                spawn_worker(
+                    format!("tokenizer-worker-{i}"),
+                    receiver.clone(),
+                    live_workers.clone(),
                 )
-            });
+            })
+            .collect();
+
+        // Decode requests get their own channel so that they do not wait behind encode requests
+        let live_decode_workers = Arc::new(AtomicUsize::new(decode_workers));
+        let (decode_sender, decode_handles) = if decode_workers > 0 {
+            let (decode_sender, decode_receiver) = async_channel::bounded(decode_workers * 4);
+            let decode_handles = (0..decode_workers)
+                .map(|i| {
+                    spawn_worker(
+                        format!("tokenizer-decode-worker-{i}"),
+                        decode_receiver.clone(),
+                        live_decode_workers.clone(),
+                    )
+                })
+                .collect();
+            (Some(decode_sender), decode_handles)
+        } else {
+            (None, Vec::new())
+        };
+
+        // A capacity of 0 disables the cache
+        let cache =
+            (cache_capacity > 0).then(|| Arc::new(Mutex::new(EncodingCache::new(cache_capacity))));
+
+        Ok(Tokenization {
+            sender,
+            workers: Arc::new(Mutex::new(handles)),
+            live_workers,
+            max_input_length,
+            decode_sender,
+            decode_workers: Arc::new(Mutex::new(decode_handles)),
+            live_decode_workers,
+            cache,
+            special_tokens,
+            metrics,
+        })
+    }
+}
+
+impl Tokenization {
+    /// Positional version of `TokenizationBuilder`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        workers: usize,
+        tokenizer: Tokenizer,
+        max_input_length: usize,
+        max_char_multiplier: usize,
+        position_offset: usize,
+        default_prompt: Option<String>,
+        prompts: Option<HashMap<String, String>>,
+        cache_capacity: usize,
+        batch_parallelism: usize,
+        normalization: Option<UnicodeNormalization>,
+        stack_size: Option<usize>,
+        queue_capacity: Option<usize>,
+        metrics: bool,
+    ) -> Result<Self, TextEmbeddingsError> {
+        let mut builder = TokenizationBuilder::new(tokenizer, workers, max_input_length)
+            .max_char_multiplier(max_char_multiplier)
+            .position_offset(position_offset)
+            .default_prompt(default_prompt)
+            .prompts(prompts)
+            .cache_capacity(cache_capacity)
+            .batch_parallelism(batch_parallelism)
+            .normalization(normalization)
+            .stack_size(stack_size)
+            .metrics(metrics);
+        if let Some(queue_capacity) = queue_capacity {
+            builder = builder.queue_capacity(queue_capacity);
+        }
+        builder.build()
+    }
+
+    /// Start building a `Tokenization` with `workers` threads
+    pub fn builder(
+        tokenizer: Tokenizer,
+        workers: usize,
+        max_input_length: usize,
+    ) -> TokenizationBuilder {
+        TokenizationBuilder::new(tokenizer, workers, max_input_length)
+    }
+
+    /// Read the `default_prompt_name` and `prompts` of a Sentence Transformers
+    /// `config_sentence_transformers.json`
+    ///
+    /// A config without `prompts` returns an empty map.
+    pub fn load_prompts_from_st_config(
+        path: &Path,
+    ) -> Result<(Option<String>, HashMap<String, String>), TextEmbeddingsError> {
+        let invalid = |message: String| {
+            TextEmbeddingsError::Validation(ValidationError::Other(format!(
+                "Failed to load prompts from `{}`: {message}",
+                path.display()
+            )))
+        };
+        let config = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let config: serde_json::Value =
+            serde_json::from_str(&config).map_err(|err| invalid(err.to_string()))?;
+
+        let default_prompt_name = match config.get("default_prompt_name") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(name)) => Some(name.clone()),
+            Some(_) => {
+                return Err(invalid(
+                    "`default_prompt_name` must be a string".to_string(),
+                ))
+            }
+        };
+        let prompts = match config.get("prompts") {
+            None | Some(serde_json::Value::Null) => HashMap::new(),
+            Some(serde_json::Value::Object(prompts)) => prompts
+                .iter()
+                .map(|(name, prompt)| match prompt {
+                    serde_json::Value::String(prompt) => Ok((name.clone(), prompt.clone())),
+                    _ => Err(invalid(format!("prompt `{name}` must be a string"))),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(invalid("`prompts` must be an object".to_string())),
+        };
+        Ok((default_prompt_name, prompts))
+    }
+
+    /// Stop the tokenization workers
+    ///
+    /// Closes the channel so that the workers exit once the pending requests are processed, then
+    /// waits up to `timeout` for them to finish. Returns `false` if some workers are still running.
+    ///
+    /// The workers are shared by every clone of this `Tokenization`: shutting down one clone stops
+    /// them for all the others, whose requests then fail with `WorkersGone`. Only the owner of the
+    /// last clone, e.g. the server on exit, should call it.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        self.sender.close();
+        if let Some(decode_sender) = &self.decode_sender {
+            decode_sender.close();
+        }
+
+        let take_handles = |workers: &Mutex<Vec<JoinHandle<()>>>| {
+            std::mem::take(
+                &mut *workers
+                    .lock()
+                    .expect("Tokenization workers lock is poisoned. This is a bug."),
+            )
+        };
+        let handles = take_handles(&self.workers)
+            .into_iter()
+            .chain(take_handles(&self.decode_workers));
+
+        let deadline = Instant::now() + timeout;
+        for handle in handles {
+            while !handle.is_finished() {
+                if Instant::now() >= deadline {
+                    tracing::warn!("Tokenization workers did not stop after {timeout:?}");
+                    return false;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let _ = handle.join();
+        }
+        true
+    }
+
+    /// Ids of the special tokens of the tokenizer, to build `Ids` and `PreTokenized` inputs
+    pub fn special_token_ids(&self) -> SpecialTokens {
+        self.special_tokens
+    }
+
+    /// Whether at least one worker is still running, and one decode worker if there is a decode
+    /// pool. A worker that panics while processing a request is not restarted: once all of them
+    /// have panicked, every request of their pool fails.
+    pub fn is_healthy(&self) -> bool {
+        let decode_healthy =
+            self.decode_sender.is_none() || self.live_decode_workers.load(Ordering::Acquire) > 0;
+        self.live_workers.load(Ordering::Acquire) > 0 && decode_healthy
+    }
+
+    /// Check that the workers are consuming requests by tokenizing a sentinel input.
+    /// Returns `WorkersGone` if no worker answers within `PING_TIMEOUT`.
+    #[instrument(skip_all)]
+    pub async fn ping(&self) -> Result<(), TextEmbeddingsError> {
+        tokio::time::timeout(PING_TIMEOUT, self.count_tokens("ping".into(), None))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+            .map(|_| ())
+    }
+
+    /// Change the maximum number of tokens of the inputs without restarting the workers
+    ///
+    /// The workers read the limit when they pick a request up: requests already being processed
+    /// keep the previous limit, the following ones use `new_len`.
+    /// The encoding cache is cleared as its entries were computed with the previous limit.
+    #[instrument(skip(self))]
+    pub fn set_max_input_length(&self, new_len: usize) -> Result<(), TextEmbeddingsError> {
+        if new_len == 0 {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                "`max_input_length` must be greater than 0".to_string(),
+            )));
+        }
+
+        self.max_input_length.store(new_len, Ordering::Release);
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .expect("Tokenization cache lock is poisoned. This is a bug.")
+                .clear();
         }
+        Ok(())
+    }
 
-        Self { sender }
+    /// Channel of the decode requests: the dedicated one if any, else the shared one
+    fn decode_channel(&self) -> &async_channel::Sender<TokenizerRequest> {
+        self.decode_sender.as_ref().unwrap_or(&self.sender)
+    }
+
+    /// Report how full the channel to the workers is
+    fn record_queue_depth(&self) {
+        if self.metrics {
+            let gauge = metrics::gauge!("te_tokenization_queue_depth");
+            gauge.set(self.sender.len() as f64);
+        }
     }
 
     #[instrument(skip_all)]
     pub async fn encode(
         &self,
         inputs: EncodingInput,
+        options: EncodeOptions,
+    ) -> Result<ValidEncoding, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        let cache_key = self.cache.as_ref().map(|cache| {
+            let generation = cache
+                .lock()
+                .expect("Tokenization cache lock is poisoned. This is a bug.")
+                .generation;
+            (cache, encoding_cache_key(&inputs, &options), generation)
+        });
+
+        if let Some((cache, key, _)) = &cache_key {
+            let cached = cache
+                .lock()
+                .expect("Tokenization cache lock is poisoned. This is a bug.")
+                .get(key);
+            if let Some(encoding) = cached {
+                if self.metrics {
+                    let counter = metrics::counter!("te_tokenization_cache_hits");
+                    counter.increment(1);
+                }
+                return Ok(encoding);
+            }
+        }
+
+        self.record_queue_depth();
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        let encoding = with_timeout(options.timeout, async {
+            // Send request to the background validation task
+            self.sender
+                .send(TokenizerRequest::Encode(
+                    inputs,
+                    options,
+                    response_sender,
+                    Span::current(),
+                ))
+                .await
+                .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+            // Await on response channel
+            response_receiver
+                .await
+                .map_err(|_| TextEmbeddingsError::WorkersGone)?
+        })
+        .await?;
+
+        if let Some((cache, key, generation)) = cache_key {
+            let mut cache = cache
+                .lock()
+                .expect("Tokenization cache lock is poisoned. This is a bug.");
+            // The limit was changed while this request was in flight
+            if cache.generation == generation {
+                cache.insert(key, encoding.clone());
+            }
+        }
+        Ok(encoding)
+    }
+
+    /// Blocking version of `encode` for callers outside of an async runtime, such as scripts and
+    /// benchmarks. The encoding cache is not used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an async runtime.
+    #[instrument(skip_all)]
+    pub fn encode_blocking(
+        &self,
+        inputs: EncodingInput,
+        options: EncodeOptions,
+    ) -> Result<ValidEncoding, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send_blocking(TokenizerRequest::Encode(
+                inputs,
+                options,
+                response_sender,
+                Span::current(),
+            ))
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Wait on response channel
+        response_receiver
+            .blocking_recv()
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Returns the encodings and the length of the longest one. `pad_to_longest` pads every
+    /// encoding to that length, not to `max_input_length`, so that the batch can be stacked as is.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip_all)]
+    pub async fn encode_batch(
+        &self,
+        inputs: Vec<EncodingInput>,
         truncate: bool,
         truncation_direction: TruncationDirection,
+        truncation_strategy: TruncationStrategy,
         prompt_name: Option<String>,
-    ) -> Result<ValidEncoding, TextEmbeddingsError> {
+        prompt_text: Option<String>,
+        with_offsets: bool,
+        pad_to_longest: bool,
+    ) -> Result<(Vec<ValidEncoding>, usize), TextEmbeddingsError> {
         // Check if inputs is empty
-        if inputs.is_empty() {
-            return Err(TextEmbeddingsError::Validation(
-                "`inputs` cannot be empty".to_string(),
-            ));
+        if inputs.is_empty() || inputs.iter().any(|input| input.is_empty()) {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
         }
 
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
         // Send request to the background validation task
-        // Unwrap is safe here
         self.sender
-            .send(TokenizerRequest::Encode(
+            .send(TokenizerRequest::EncodeBatch(
                 inputs,
                 truncate,
                 truncation_direction,
+                truncation_strategy,
                 prompt_name,
+                prompt_text,
+                with_offsets,
+                pad_to_longest,
                 response_sender,
                 Span::current(),
             ))
             .await
-            .expect("Tokenization background task dropped the receiver. This is a bug.");
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
 
         // Await on response channel
-        // Unwrap is safe here
-        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
     }
 
+    /// Encode `inputs` and greedily keep the ones that fit in `max_total_tokens` tokens.
+    /// Returns the kept encodings, in order, and the indices of the deferred inputs.
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all)]
-    pub async fn tokenize(
+    pub async fn encode_batch_budgeted(
+        &self,
+        inputs: Vec<EncodingInput>,
+        max_total_tokens: usize,
+        truncate: bool,
+        truncation_direction: TruncationDirection,
+        truncation_strategy: TruncationStrategy,
+        prompt_name: Option<String>,
+        prompt_text: Option<String>,
+        with_offsets: bool,
+    ) -> Result<(Vec<ValidEncoding>, Vec<usize>), TextEmbeddingsError> {
+        let (encodings, _) = self
+            .encode_batch(
+                inputs,
+                truncate,
+                truncation_direction,
+                truncation_strategy,
+                prompt_name,
+                prompt_text,
+                with_offsets,
+                false,
+            )
+            .await?;
+        Ok(pack_encodings(encodings, max_total_tokens))
+    }
+
+    /// Encode `inputs` as they are produced, yielding the results in order. At most the queue
+    /// capacity of requests are in flight: a slow consumer stops pulling from `inputs`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode_stream<'a, S>(
+        &'a self,
+        inputs: S,
+        truncate: bool,
+        truncation_direction: TruncationDirection,
+        truncation_strategy: TruncationStrategy,
+        prompt_name: Option<String>,
+        prompt_text: Option<String>,
+        with_offsets: bool,
+    ) -> impl Stream<Item = Result<ValidEncoding, TextEmbeddingsError>> + 'a
+    where
+        S: Stream<Item = EncodingInput> + 'a,
+    {
+        let max_in_flight = self.sender.capacity().unwrap_or(1);
+        inputs
+            .map(move |input| {
+                self.encode(
+                    input,
+                    EncodeOptions {
+                        overflow_policy: truncate.into(),
+                        truncation_direction,
+                        truncation_strategy,
+                        prompt_name: prompt_name.clone(),
+                        prompt_text: prompt_text.clone(),
+                        with_offsets,
+                        ..Default::default()
+                    },
+                )
+            })
+            .buffered(max_in_flight)
+    }
+
+    /// Encode `inputs` truncated to `max_input_length` and return the tokens that did not fit as
+    /// overflowing windows sharing `stride` tokens with the previous one
+    #[instrument(skip_all)]
+    pub async fn encode_with_overflow(
         &self,
         inputs: EncodingInput,
-        add_special_tokens: bool,
+        stride: usize,
+        truncation_direction: TruncationDirection,
+        prompt_name: Option<String>,
+        with_offsets: bool,
+    ) -> Result<(ValidEncoding, Vec<ValidEncoding>), TextEmbeddingsError> {
+        let mut encoding = self
+            .encode(
+                inputs,
+                EncodeOptions {
+                    overflow_policy: OverflowPolicy::Chunk { stride },
+                    truncation_direction,
+                    prompt_name,
+                    with_offsets,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let overflowing = std::mem::take(&mut encoding.chunks);
+        Ok((encoding, overflowing))
+    }
+
+    /// Split `inputs` into overlapping windows of at most `max_input_length` tokens, each sharing
+    /// `stride` tokens with the previous one. `position_mode` sets how the `position_ids` of the
+    /// windows are numbered.
+    #[instrument(skip_all)]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn encode_chunks(
+        &self,
+        inputs: EncodingInput,
+        max_input_length: usize,
+        stride: usize,
+        position_mode: PositionMode,
         prompt_name: Option<String>,
-    ) -> Result<(Option<String>, RawEncoding), TextEmbeddingsError> {
+        with_offsets: bool,
+    ) -> Result<Vec<ChunkEncoding>, TextEmbeddingsError> {
         // Check if inputs is empty
         if inputs.is_empty() {
-            return Err(TextEmbeddingsError::Validation(
-                "`inputs` cannot be empty".to_string(),
-            ));
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
         }
 
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
         // Send request to the background validation task
-        // Unwrap is safe here
         self.sender
-            .send(TokenizerRequest::Tokenize(
+            .send(TokenizerRequest::EncodeChunks(
                 inputs,
-                add_special_tokens,
+                max_input_length,
+                stride,
+                position_mode,
                 prompt_name,
+                with_offsets,
                 response_sender,
                 Span::current(),
             ))
             .await
-            .expect("Tokenization background task dropped the receiver. This is a bug.");
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
 
         // Await on response channel
-        // Unwrap is safe here
-        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
     }
 
+    /// Split `input` on sentence boundaries and greedily pack the sentences into chunks of at
+    /// most `max_input_length` tokens, returning one encoding per chunk. Only the sentences longer
+    /// than `max_input_length` on their own are cut, into consecutive windows.
     #[instrument(skip_all)]
-    pub async fn decode(
+    pub async fn encode_sentences(
         &self,
-        ids: Vec<u32>,
-        skip_special_tokens: bool,
-    ) -> Result<String, TextEmbeddingsError> {
-        // Check if inputs is empty
-        if ids.is_empty() {
-            return Err(TextEmbeddingsError::Validation(
-                "`input_ids` cannot be empty".to_string(),
-            ));
+        input: &str,
+        max_input_length: usize,
+        prompt_name: Option<String>,
+    ) -> Result<Vec<ValidEncoding>, TextEmbeddingsError> {
+        // Check if input is empty
+        if input.trim().is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
         }
 
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
         // Send request to the background validation task
-        // Unwrap is safe here
         self.sender
-            .send(TokenizerRequest::Decode(
-                ids,
-                skip_special_tokens,
+            .send(TokenizerRequest::EncodeSentences(
+                input.to_string(),
+                max_input_length,
+                prompt_name,
                 response_sender,
                 Span::current(),
             ))
             .await
-            .expect("Tokenization background task dropped the receiver. This is a bug.");
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
 
         // Await on response channel
-        // Unwrap is safe here
-        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
     }
-}
 
-/// Start tokenization workers
-fn tokenizer_worker(
-    mut tokenizer: Tokenizer,
-    max_input_length: usize,
-    position_offset: usize,
-    default_prompt: Option<String>,
-    prompts: Option<HashMap<String, String>>,
-    receiver: async_channel::Receiver<TokenizerRequest>,
-) {
-    // Loop over requests
-    while let Ok(request) = receiver.recv_blocking() {
-        match request {
-            TokenizerRequest::Encode(
+    /// Number of tokens of `inputs`, special tokens included
+    #[instrument(skip_all)]
+    pub async fn count_tokens(
+        &self,
+        inputs: EncodingInput,
+        prompt_name: Option<String>,
+    ) -> Result<usize, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send(TokenizerRequest::Count(
+                inputs,
+                prompt_name,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Number of tokens of each of `inputs`, special tokens included. All the inputs are counted in
+    /// a single worker turn.
+    #[instrument(skip_all)]
+    pub async fn count_tokens_batch(
+        &self,
+        inputs: Vec<EncodingInput>,
+        prompt_name: Option<String>,
+    ) -> Result<Vec<usize>, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() || inputs.iter().any(|input| input.is_empty()) {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send(TokenizerRequest::CountBatch(
+                inputs,
+                prompt_name,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Number of occurrences of each token id of `inputs`, special tokens excluded. Useful for
+    /// sparse term frequency representations built with the same tokenizer.
+    #[instrument(skip_all)]
+    pub async fn token_counts(
+        &self,
+        inputs: EncodingInput,
+        prompt_name: Option<String>,
+    ) -> Result<HashMap<u32, u32>, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send(TokenizerRequest::TokenCounts(
+                inputs,
+                prompt_name,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Run the same checks as `encode` and return the number of tokens of `inputs`, without
+    /// building the encoding. `te_request_input_length` is not recorded, the metrics of the
+    /// workers and of the char limit are.
+    #[instrument(skip_all)]
+    pub async fn validate(
+        &self,
+        inputs: EncodingInput,
+        truncate: bool,
+        truncation_direction: TruncationDirection,
+        prompt_name: Option<String>,
+    ) -> Result<usize, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send(TokenizerRequest::Validate(
+                inputs,
+                truncate,
+                truncation_direction,
+                prompt_name,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Encode `inputs` and also return the encoding it was built from, with the offsets, words
+    /// and special tokens mask of every token. The encoding cache is not used.
+    #[instrument(skip_all)]
+    pub async fn encode_raw(
+        &self,
+        inputs: EncodingInput,
+        add_special_tokens: bool,
+        overflow_policy: OverflowPolicy,
+        truncation_direction: TruncationDirection,
+        prompt_name: Option<String>,
+        prompt_text: Option<String>,
+    ) -> Result<(ValidEncoding, RawEncoding), TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        self.record_queue_depth();
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send(TokenizerRequest::EncodeRaw(
+                inputs,
+                add_special_tokens,
+                overflow_policy,
+                truncation_direction,
+                prompt_name,
+                prompt_text,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// `template_override`, `request_id` and `timeout` behave as in `encode`
+    #[instrument(skip_all)]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn tokenize(
+        &self,
+        inputs: EncodingInput,
+        add_special_tokens: bool,
+        prompt_name: Option<String>,
+        prompt_text: Option<String>,
+        template_override: Option<String>,
+        request_id: Option<RequestId>,
+        timeout: Option<Duration>,
+    ) -> Result<Tokenized, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        self.record_queue_depth();
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        with_timeout(timeout, async {
+            // Send request to the background validation task
+            self.sender
+                .send(TokenizerRequest::Tokenize(
+                    inputs,
+                    add_special_tokens,
+                    prompt_name,
+                    prompt_text,
+                    template_override,
+                    request_id,
+                    response_sender,
+                    Span::current(),
+                ))
+                .await
+                .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+            // Await on response channel
+            response_receiver
+                .await
+                .map_err(|_| TextEmbeddingsError::WorkersGone)?
+        })
+        .await
+    }
+
+    /// Indices of the tokens of `input` overlapping the bytes `byte_start..byte_end` of `input`,
+    /// special tokens excluded. The span refers to `input` alone, before the prompt selected by
+    /// `prompt_name` is applied.
+    #[instrument(skip_all)]
+    pub async fn tokens_for_span(
+        &self,
+        input: &str,
+        byte_start: usize,
+        byte_end: usize,
+        prompt_name: Option<String>,
+    ) -> Result<Range<usize>, TextEmbeddingsError> {
+        if byte_start >= byte_end || byte_end > input.len() {
+            let message = format!(
+                "`byte_start..byte_end` must be a non-empty span of the {} bytes of `input`. Given: {byte_start}..{byte_end}",
+                input.len()
+            );
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                message,
+            )));
+        }
+
+        let tokenized = self
+            .tokenize(input.into(), true, prompt_name, None, None, None, None)
+            .await?;
+        // The offsets refer to the input with the prompt prepended
+        let shift = tokenized
+            .prompt_text
+            .as_deref()
+            .map(|prompt| split_prompt(prompt).0.len())
+            .unwrap_or_default();
+        let (span_start, span_end) = (byte_start + shift, byte_end + shift);
+
+        let encoding = &tokenized.encoding;
+        let mut covering = encoding
+            .get_offsets()
+            .iter()
+            .zip(encoding.get_special_tokens_mask())
+            .enumerate()
+            // Special tokens have `(0, 0)` offsets, which would overlap a span at the start
+            .filter(|(_, (&(start, end), &special))| {
+                special == 0 && start < span_end && end > span_start
+            })
+            .map(|(index, _)| index);
+        let first = covering.next().ok_or_else(|| {
+            TextEmbeddingsError::Validation(ValidationError::Other(format!(
+                "No token covers the bytes {byte_start}..{byte_end} of `input`"
+            )))
+        })?;
+        let last = covering.last().unwrap_or(first);
+        Ok(first..last + 1)
+    }
+
+    /// Tokenize all `inputs` in a single worker turn.
+    /// Each input has its own result so that one invalid input does not fail the whole batch.
+    #[instrument(skip_all)]
+    pub async fn tokenize_batch(
+        &self,
+        inputs: Vec<EncodingInput>,
+        add_special_tokens: bool,
+        prompt_name: Option<String>,
+        prompt_text: Option<String>,
+    ) -> Result<Vec<Result<Tokenized, TextEmbeddingsError>>, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Empty));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.sender
+            .send(TokenizerRequest::TokenizeBatch(
+                inputs,
+                add_special_tokens,
+                prompt_name,
+                prompt_text,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        Ok(response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?)
+    }
+
+    /// `clean_up_tokenization_spaces` overrides the decoder cleanup behavior when set
+    #[instrument(skip_all)]
+    pub async fn decode(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+        clean_up_tokenization_spaces: Option<bool>,
+    ) -> Result<String, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if ids.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                "`input_ids` cannot be empty".to_string(),
+            )));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.decode_channel()
+            .send(TokenizerRequest::Decode(
+                ids,
+                skip_special_tokens,
+                clean_up_tokenization_spaces,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Decode `ids` with and without the special tokens, in this order, on the same worker
+    #[instrument(skip_all)]
+    pub async fn decode_both(
+        &self,
+        ids: Vec<u32>,
+    ) -> Result<(String, String), TextEmbeddingsError> {
+        // Check if inputs is empty
+        if ids.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                "`input_ids` cannot be empty".to_string(),
+            )));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.decode_channel()
+            .send(TokenizerRequest::DecodeBoth(
+                ids,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Decode every id on its own, returning one string per id.
+    ///
+    /// Decoders see a single token at a time, so context dependent behavior differs from
+    /// `decode` (e.g. the leading space of a word is dropped by Metaspace decoders). Byte-level
+    /// and byte fallback tokens holding part of a multi-byte character are not valid UTF-8 on
+    /// their own and decode to the U+FFFD replacement character. Skipped special tokens decode to
+    /// an empty string so that the output stays aligned with `ids`.
+    #[instrument(skip_all)]
+    pub async fn decode_tokens(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<Vec<String>, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if ids.is_empty() {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                "`input_ids` cannot be empty".to_string(),
+            )));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        self.decode_channel()
+            .send(TokenizerRequest::DecodeTokens(
+                ids,
+                skip_special_tokens,
+                response_sender,
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?;
+
+        // Await on response channel
+        response_receiver
+            .await
+            .map_err(|_| TextEmbeddingsError::WorkersGone)?
+    }
+
+    /// Decode `ids` incrementally, yielding each piece of text as soon as it is complete
+    #[instrument(skip_all)]
+    pub fn decode_stream(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> impl Stream<Item = Result<String, TextEmbeddingsError>> {
+        let sender = self.decode_channel().clone();
+        let span = Span::current();
+
+        // Create response channel
+        let (response_sender, response_receiver) = mpsc::unbounded_channel();
+        let request = async move {
+            // Check if inputs is empty
+            if ids.is_empty() {
+                return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                    "`input_ids` cannot be empty".to_string(),
+                )));
+            }
+
+            // Send request to the background validation task
+            sender
+                .send(TokenizerRequest::DecodeStream(
+                    ids,
+                    skip_special_tokens,
+                    response_sender,
+                    span,
+                ))
+                .await
+                .map_err(|_| TextEmbeddingsError::WorkersGone)
+        };
+
+        // On error, the response sender is dropped and the stream ends after the error
+        let pieces = futures::stream::unfold(response_receiver, |mut receiver| async move {
+            receiver.recv().await.map(|piece| (piece, receiver))
+        });
+        futures::stream::once(request)
+            .filter_map(|sent| async move { sent.err().map(Err) })
+            .chain(pieces)
+    }
+}
+
+/// Run `future`, failing with `Timeout` if it does not complete within `timeout`
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, TextEmbeddingsError>>,
+) -> Result<T, TextEmbeddingsError> {
+    match timeout {
+        None => future.await,
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| TextEmbeddingsError::Timeout)?,
+    }
+}
+
+/// Report a worker that panicked while processing the request of `span`
+fn worker_panicked(
+    panic: &(dyn std::any::Any + Send),
+    span: &Span,
+    live_workers: &AtomicUsize,
+    metrics: bool,
+) {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    let live = live_workers.fetch_sub(1, Ordering::AcqRel) - 1;
+    span.in_scope(|| {
+        tracing::error!("Tokenization worker panicked: {message}. {live} workers left");
+    });
+    if live == 0 {
+        tracing::error!("All tokenization workers panicked, tokenization is unhealthy");
+    }
+    if metrics {
+        let counter = metrics::counter!("te_tokenization_worker_panics_total");
+        counter.increment(1);
+    }
+}
+
+/// Encode and decode `WARMUP_INPUT` so that the lazily initialized state of the tokenizer, such as
+/// its normalizer regexes, is ready before the first request
+fn warm_up(
+    tokenizer: &SharedTokenizer,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    default_prompt: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+) -> Result<(), TextEmbeddingsError> {
+    // Never rejected for its length
+    let input = WARMUP_INPUT
+        .chars()
+        .take(max_input_length * max_char_multiplier)
+        .collect();
+    let (_, encoding, _) = tokenize_input(
+        EncodingInput::Single(input),
+        &EncodeOptions::default(),
+        max_input_length,
+        max_char_multiplier,
+        None,
+        default_prompt,
+        prompts,
+        false,
+        tokenizer,
+    )?;
+    if tokenizer.tokenizer.get_decoder().is_some() {
+        tokenizer.decode(encoding.get_ids(), true)?;
+    }
+    Ok(())
+}
+
+/// Start tokenization workers
+#[allow(clippy::too_many_arguments)]
+fn tokenizer_worker(
+    tokenizer: Arc<SharedTokenizer>,
+    batch_pool: Option<Arc<ThreadPool>>,
+    current_max_input_length: Arc<AtomicUsize>,
+    max_char_multiplier: usize,
+    position_offset: usize,
+    default_prompt: Option<String>,
+    prompts: Option<HashMap<String, String>>,
+    receiver: async_channel::Receiver<TokenizerRequest>,
+    worker_id: String,
+    in_flight: &RefCell<Span>,
+) {
+    // Shows how evenly the channel spreads the requests over the workers
+    let requests_counter = tokenizer
+        .metrics
+        .then(|| metrics::counter!("te_tokenizer_worker_requests_total", "worker_id" => worker_id));
+
+    // Loop over requests
+    while let Ok(request) = receiver.recv_blocking() {
+        if let Some(requests_counter) = &requests_counter {
+            requests_counter.increment(1);
+        }
+        let start_time = Instant::now();
+        let kind = request.kind();
+        let max_input_length = current_max_input_length.load(Ordering::Acquire);
+        *in_flight.borrow_mut() = request.span().cloned().unwrap_or_else(Span::none);
+
+        match request {
+            TokenizerRequest::Encode(inputs, mut options, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    let _span = options.request_id.map(RequestId::enter_span);
+                    if !response_tx.is_closed() {
+                        let _template = match TemplateOverride::set(
+                            options.template_override.take(),
+                            &tokenizer,
+                        ) {
+                            Ok(template) => template,
+                            Err(err) => {
+                                let _ = response_tx.send(Err(err));
+                                return;
+                            }
+                        };
+                        let default_prompt_clone = match options.prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+                        let wrap_tokens = options.wrap_tokens.take();
+                        let leading_cls = options.leading_cls;
+
+                        let encode = |inputs, wrap_tokens| {
+                            encode_input(
+                                inputs,
+                                &EncodeOptions {
+                                    wrap_tokens,
+                                    ..options.clone()
+                                },
+                                max_input_length,
+                                max_char_multiplier,
+                                position_offset,
+                                default_prompt_clone.clone(),
+                                prompts.as_ref(),
+                                &tokenizer,
+                            )
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_with_leading_cls(
+                            inputs,
+                            wrap_tokens,
+                            leading_cls,
+                            &tokenizer,
+                            encode,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::EncodeRaw(
+                inputs,
+                add_special_tokens,
+                overflow_policy,
+                truncation_direction,
+                prompt_name,
+                prompt_text,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_input_raw(
+                            inputs,
+                            &EncodeOptions {
+                                add_special_tokens,
+                                overflow_policy,
+                                truncation_direction,
+                                prompt_name,
+                                prompt_text,
+                                ..Default::default()
+                            },
+                            max_input_length,
+                            max_char_multiplier,
+                            position_offset,
+                            default_prompt_clone,
+                            prompts.as_ref(),
+                            &tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::EncodeBatch(
+                inputs,
+                truncate,
+                truncation_direction,
+                truncation_strategy,
+                prompt_name,
+                prompt_text,
+                with_offsets,
+                pad_to_longest,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        let encode = |input| {
+                            encode_input(
+                                input,
+                                &EncodeOptions {
+                                    overflow_policy: truncate.into(),
+                                    truncation_direction,
+                                    truncation_strategy,
+                                    prompt_name: prompt_name.clone(),
+                                    prompt_text: prompt_text.clone(),
+                                    with_offsets,
+                                    ..Default::default()
+                                },
+                                max_input_length,
+                                max_char_multiplier,
+                                position_offset,
+                                default_prompt_clone.clone(),
+                                prompts.as_ref(),
+                                &tokenizer,
+                            )
+                        };
+
+                        // Duplicate inputs are encoded once
+                        let n_inputs = inputs.len();
+                        let (inputs, positions) = dedup_inputs(inputs);
+                        if tokenizer.metrics {
+                            let histogram = metrics::histogram!("te_batch_dedup_ratio");
+                            histogram.record(1.0 - inputs.len() as f64 / n_inputs as f64);
+                        }
+
+                        // Encode all inputs in a single worker turn, stopping at the first error
+                        let encodings: Result<Vec<_>, _> = match &batch_pool {
+                            Some(batch_pool) => {
+                                batch_pool.install(|| inputs.into_par_iter().map(encode).collect())
+                            }
+                            None => inputs.into_iter().map(encode).collect(),
+                        };
+                        let encodings = encodings
+                            .map(|encodings| {
+                                if encodings.len() == n_inputs {
+                                    return encodings;
+                                }
+                                positions.iter().map(|&i| encodings[i].clone()).collect()
+                            })
+                            .and_then(|encodings| pad_batch(encodings, pad_to_longest, &tokenizer));
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encodings);
+                    }
+                })
+            }
+            TokenizerRequest::EncodeChunks(
+                inputs,
+                chunk_length,
+                stride,
+                position_mode,
+                prompt_name,
+                with_offsets,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_chunks(
+                            inputs,
+                            chunk_length,
+                            stride,
+                            position_mode,
+                            max_input_length,
+                            max_char_multiplier,
+                            position_offset,
+                            default_prompt_clone,
+                            prompt_name,
+                            None,
+                            with_offsets,
+                            prompts.as_ref(),
+                            &tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::EncodeSentences(
+                input,
+                chunk_length,
+                prompt_name,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_sentences(
+                            &input,
+                            chunk_length,
+                            max_input_length,
+                            max_char_multiplier,
+                            position_offset,
+                            default_prompt_clone,
+                            prompt_name,
+                            prompts.as_ref(),
+                            &tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::Count(inputs, prompt_name, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(
+                            tokenize_input(
+                                inputs,
+                                &EncodeOptions {
+                                    prompt_name,
+                                    ..Default::default()
+                                },
+                                max_input_length,
+                                max_char_multiplier,
+                                None,
+                                default_prompt_clone,
+                                prompts.as_ref(),
+                                false,
+                                &tokenizer,
+                            )
+                            .map(|(_, encoding, _)| encoding.len()),
+                        );
+                    }
+                })
+            }
+            TokenizerRequest::CountBatch(inputs, prompt_name, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        let count = |input| {
+                            tokenize_input(
+                                input,
+                                &EncodeOptions {
+                                    prompt_name: prompt_name.clone(),
+                                    ..Default::default()
+                                },
+                                max_input_length,
+                                max_char_multiplier,
+                                None,
+                                default_prompt_clone.clone(),
+                                prompts.as_ref(),
+                                false,
+                                &tokenizer,
+                            )
+                            .map(|(_, encoding, _)| encoding.len())
+                        };
+
+                        // Count all inputs in a single worker turn, stopping at the first error
+                        let counts = match &batch_pool {
+                            Some(batch_pool) => {
+                                batch_pool.install(|| inputs.into_par_iter().map(count).collect())
+                            }
+                            None => inputs.into_iter().map(count).collect(),
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(counts);
+                    }
+                })
+            }
+            TokenizerRequest::TokenCounts(inputs, prompt_name, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(
+                            tokenize_input(
+                                inputs,
+                                &EncodeOptions {
+                                    add_special_tokens: false,
+                                    prompt_name,
+                                    ..Default::default()
+                                },
+                                max_input_length,
+                                max_char_multiplier,
+                                None,
+                                default_prompt_clone,
+                                prompts.as_ref(),
+                                false,
+                                &tokenizer,
+                            )
+                            .map(|(_, encoding, _)| count_token_ids(&encoding)),
+                        );
+                    }
+                })
+            }
+            TokenizerRequest::Validate(
+                inputs,
+                truncate,
+                truncation_direction,
+                prompt_name,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        let truncate_params = truncate.then_some(TruncationParams {
+                            direction: truncation_direction,
+                            max_length: max_input_length,
+                            strategy: TruncationStrategy::LongestFirst,
+                            stride: 0,
+                        });
+                        let is_pair = matches!(inputs, EncodingInput::Dual(_, _));
+                        let pre_tokenized = matches!(inputs, EncodingInput::PreTokenized { .. });
+                        let (prompt_default, prompt_selected) =
+                            (default_prompt_clone.clone(), prompt_name.clone());
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(
+                            tokenize_input(
+                                inputs,
+                                &EncodeOptions {
+                                    prompt_name,
+                                    ..Default::default()
+                                },
+                                max_input_length,
+                                max_char_multiplier,
+                                truncate_params,
+                                default_prompt_clone,
+                                prompts.as_ref(),
+                                false,
+                                &tokenizer,
+                            )
+                            .and_then(|(_, encoding, _)| {
+                                let seq_len = check_input_length(encoding.len(), max_input_length);
+                                if truncate {
+                                    return seq_len;
+                                }
+                                with_truncation_hint(seq_len, || {
+                                    let prompt = prepare_pre_prompt(
+                                        prompt_default,
+                                        prompt_selected,
+                                        None,
+                                        prompts.as_ref(),
+                                        false,
+                                        false,
+                                    )?;
+                                    untruncatable_tokens(
+                                        is_pair,
+                                        !pre_tokenized,
+                                        prompt.as_deref().filter(|_| !pre_tokenized),
+                                        &tokenizer,
+                                    )
+                                })
+                            }),
+                        );
+                    }
+                })
+            }
+            TokenizerRequest::Tokenize(
+                inputs,
+                add_special_tokens,
+                prompt_name,
+                prompt_text,
+                template_override,
+                request_id,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    let _span = request_id.map(RequestId::enter_span);
+                    if !response_tx.is_closed() {
+                        let _template = match TemplateOverride::set(template_override, &tokenizer) {
+                            Ok(template) => template,
+                            Err(err) => {
+                                let _ = response_tx.send(Err(err));
+                                return;
+                            }
+                        };
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(tokenize_with_prompt(
+                            inputs,
+                            add_special_tokens,
+                            max_input_length,
+                            max_char_multiplier,
+                            default_prompt_clone,
+                            prompt_name,
+                            prompt_text,
+                            prompts.as_ref(),
+                            &tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::TokenizeBatch(
+                inputs,
+                add_special_tokens,
+                prompt_name,
+                prompt_text,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let default_prompt_clone = match prompt_name {
+                            None => default_prompt.clone(),
+                            Some(_) => None,
+                        };
+
+                        let results = inputs
+                            .into_iter()
+                            .map(|input| {
+                                if input.is_empty() {
+                                    return Err(TextEmbeddingsError::Validation(
+                                        ValidationError::Empty,
+                                    ));
+                                }
+                                tokenize_with_prompt(
+                                    input,
+                                    add_special_tokens,
+                                    max_input_length,
+                                    max_char_multiplier,
+                                    default_prompt_clone.clone(),
+                                    prompt_name.clone(),
+                                    prompt_text.clone(),
+                                    prompts.as_ref(),
+                                    &tokenizer,
+                                )
+                            })
+                            .collect();
+
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(results);
+                    }
+                })
+            }
+            TokenizerRequest::Decode(
+                ids,
+                skip_special_tokens,
+                clean_up_tokenization_spaces,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(decode_ids(
+                            ids,
+                            skip_special_tokens,
+                            clean_up_tokenization_spaces,
+                            &tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::DecodeBoth(ids, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let decoded = tokenizer
+                            .decoder()
+                            .and_then(|_| check_ids(&ids, &tokenizer))
+                            .and_then(|_| tokenizer.decode(&ids, false))
+                            .and_then(|with_special| {
+                                Ok((with_special, tokenizer.decode(&ids, true)?))
+                            });
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(decoded);
+                    }
+                })
+            }
+            TokenizerRequest::DecodeTokens(ids, skip_special_tokens, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ =
+                            response_tx.send(decode_tokens(&ids, skip_special_tokens, &tokenizer));
+                    }
+                })
+            }
+            TokenizerRequest::DecodeStream(ids, skip_special_tokens, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        decode_stream_ids(&ids, skip_special_tokens, &tokenizer, &response_tx);
+                    }
+                })
+            }
+        }
+
+        if let Some(kind) = kind.filter(|_| tokenizer.metrics) {
+            let histogram = metrics::histogram!("te_tokenization_duration_seconds", "kind" => kind);
+            histogram.record(start_time.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Decode `ids` one token at a time and send each new piece of text.
+///
+/// A single character can span several tokens (e.g. CJK with byte-level BPE), so tokens are
+/// buffered until they decode to complete UTF-8. Decoding restarts from the previous piece to
+/// keep the context used by decoders for spacing.
+fn decode_stream_ids(
+    ids: &[u32],
+    skip_special_tokens: bool,
+    tokenizer: &SharedTokenizer,
+    response_tx: &mpsc::UnboundedSender<Result<String, TextEmbeddingsError>>,
+) {
+    if let Err(err) = tokenizer.decoder().and_then(|_| check_ids(ids, tokenizer)) {
+        let _ = response_tx.send(Err(err));
+        return;
+    }
+
+    let mut prefix_offset = 0;
+    let mut read_offset = 0;
+
+    for end in 1..=ids.len() {
+        let piece = tokenizer
+            .decode(&ids[prefix_offset..read_offset], skip_special_tokens)
+            .and_then(|prefix_text| {
+                let text = tokenizer.decode(&ids[prefix_offset..end], skip_special_tokens)?;
+                // The last character is incomplete when it decodes to the replacement character
+                let complete = end == ids.len() || !text.ends_with('\u{FFFD}');
+                Ok(complete
+                    .then(|| text.get(prefix_text.len()..))
+                    .flatten()
+                    .filter(|piece| !piece.is_empty())
+                    .map(str::to_string))
+            });
+
+        match piece {
+            Ok(Some(piece)) => {
+                prefix_offset = read_offset;
+                read_offset = end;
+                // It's possible that the user dropped its request resulting in a send error.
+                // We just stop decoding
+                if response_tx.send(Ok(piece)).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                let _ = response_tx.send(Err(err));
+                return;
+            }
+        }
+    }
+}
+
+/// Decode every id on its own, see `Tokenization::decode_tokens`
+fn decode_tokens(
+    ids: &[u32],
+    skip_special_tokens: bool,
+    tokenizer: &SharedTokenizer,
+) -> Result<Vec<String>, TextEmbeddingsError> {
+    tokenizer.decoder()?;
+    check_ids(ids, tokenizer)?;
+    ids.iter()
+        .map(|&id| tokenizer.decode(&[id], skip_special_tokens))
+        .collect()
+}
+
+fn decode_ids(
+    ids: Vec<u32>,
+    skip_special_tokens: bool,
+    clean_up_tokenization_spaces: Option<bool>,
+    tokenizer: &SharedTokenizer,
+) -> Result<String, TextEmbeddingsError> {
+    let decoder = tokenizer.decoder()?;
+    check_ids(&ids, tokenizer)?;
+    let Some(clean_up_tokenization_spaces) = clean_up_tokenization_spaces else {
+        // Use the tokenizer default
+        return tokenizer.decode(&ids, skip_special_tokens);
+    };
+
+    let tokenizer = &tokenizer.tokenizer;
+    let tokens: Vec<String> = ids
+        .iter()
+        .filter_map(|&id| tokenizer.id_to_token(id))
+        .filter(|token| {
+            !skip_special_tokens || !tokenizer.get_added_vocabulary().is_special_token(token)
+        })
+        .collect();
+
+    // Decode without the decoder's own cleanup step so that the override applies uniformly
+    let mut decoder = decoder.clone();
+    match &mut decoder {
+        DecoderWrapper::WordPiece(wordpiece) => wordpiece.cleanup = false,
+        DecoderWrapper::CTC(ctc) => ctc.cleanup = false,
+        _ => {}
+    }
+    let text = decoder.decode(tokens)?;
+
+    Ok(match clean_up_tokenization_spaces {
+        true => clean_up_tokenization(&text),
+        false => text,
+    })
+}
+
+/// Remove the spaces added before punctuation and English contractions by the decoder
+fn clean_up_tokenization(text: &str) -> String {
+    text.replace(" .", ".")
+        .replace(" ?", "?")
+        .replace(" !", "!")
+        .replace(" ,", ",")
+        .replace(" ' ", "'")
+        .replace(" n't", "n't")
+        .replace(" 'm", "'m")
+        .replace(" 's", "'s")
+        .replace(" 've", "'ve")
+        .replace(" 're", "'re")
+}
+
+/// Resolve the prompt to prepend to the input.
+///
+/// Precedence: an explicit `prompt_text`, then the prompt registered under `prompt_name`,
+/// then `default_prompt`. `prompt_name` can hold several comma separated names whose prompts
+/// are concatenated in order, e.g. a language tag followed by a task instruction. A name that
+/// is itself a configured prompt is never split, and `\,` escapes a comma inside a name.
+///
+/// `report` warns about empty prompts, and counts them in `te_empty_prompt_total` when `metrics`
+/// is set. It is `false` when the prompt is resolved again for a request already reported.
+fn prepare_pre_prompt(
+    default_prompt: Option<String>,
+    prompt_name: Option<String>,
+    prompt_text: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+    report: bool,
+    metrics: bool,
+) -> Result<Option<String>, TextEmbeddingsError> {
+    let pre_prompt = if let Some(prompt_text) = prompt_text {
+        validate_prompt(&prompt_text)?;
+        Some(prompt_text)
+    } else if let Some(prompt_names) = prompt_name.as_ref() {
+        let prompt_names = match prompts {
+            Some(prompts) if prompts.contains_key(prompt_names) => vec![prompt_names.clone()],
+            _ => split_prompt_names(prompt_names),
+        };
+        let combined = prompt_names.len() > 1;
+        let mut pre_prompt = String::new();
+        for prompt_name in prompt_names.iter().map(String::as_str) {
+            match prompts {
+                None => {
+                    return Err(TextEmbeddingsError::Validation(
+                        ValidationError::UnknownPrompt {
+                            name: prompt_name.to_string(),
+                            available: None,
+                            suggestion: None,
+                        },
+                    ));
+                }
+                Some(prompts) if !prompts.contains_key(prompt_name) => {
+                    return Err(TextEmbeddingsError::Validation(
+                        ValidationError::UnknownPrompt {
+                            name: prompt_name.to_string(),
+                            available: Some(prompts.keys().cloned().collect()),
+                            suggestion: closest_prompt_name(prompt_name, prompts.keys())
+                                .map(str::to_string),
+                        },
+                    ));
+                }
+                Some(prompts) => {
+                    let prompt = &prompts[prompt_name];
+                    // Some models intentionally ship an empty prompt, e.g. for the passages
+                    if report && prompt.is_empty() {
+                        if combined {
+                            tracing::warn!("Prompt `{prompt_name}` is empty, it adds nothing");
+                        } else {
+                            tracing::warn!("Prompt `{prompt_name}` is empty, no prompt is applied");
+                        }
+                        if metrics {
+                            let counter = metrics::counter!(
+                                "te_empty_prompt_total",
+                                "name" => prompt_name.to_string()
+                            );
+                            counter.increment(1);
+                        }
+                    }
+                    pre_prompt.push_str(prompt);
+                }
+            }
+        }
+        // Each prompt is valid on its own but only one of them can hold a placeholder
+        validate_prompt(&pre_prompt)?;
+        Some(pre_prompt)
+    } else {
+        default_prompt
+    };
+    Ok(pre_prompt)
+}
+
+/// Split comma separated prompt names, trimming each of them. `\,` is a literal comma and `\\`
+/// a literal backslash.
+fn split_prompt_names(prompt_names: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut name = String::new();
+    let mut chars = prompt_names.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next()),
+            ',' => names.push(std::mem::take(&mut name).trim().to_string()),
+            c => name.push(c),
+        }
+    }
+    names.push(name.trim().to_string());
+    names
+}
+
+/// Name among `names` with the smallest edit distance to `name`, if it is at most a third of the
+/// length of `name` (at least 1). Ties go to the first name in alphabetical order.
+fn closest_prompt_name<'a>(
+    name: &str,
+    names: impl IntoIterator<Item = &'a String>,
+) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    names
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate.as_str()))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between `a` and `b`, in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the prefix of `a` processed so far to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Placeholder replaced by the text in a prompt template. A bare `{}` is not a placeholder, as
+/// existing prompts may contain it literally.
+const PROMPT_PLACEHOLDER: &str = "{text}";
+
+/// Check that `prompt` contains at most one placeholder
+pub fn validate_prompt(prompt: &str) -> Result<(), TextEmbeddingsError> {
+    let placeholders = prompt.matches(PROMPT_PLACEHOLDER).count();
+    if placeholders > 1 {
+        let message = format!(
+            "`{prompt}` must contain at most one `{{text}}` placeholder. Found: {placeholders}"
+        );
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            message,
+        )));
+    }
+    Ok(())
+}
+
+/// Split `prompt` around its placeholder. A prompt without placeholder is a prefix.
+fn split_prompt(prompt: &str) -> (&str, &str) {
+    prompt
+        .split_once(PROMPT_PLACEHOLDER)
+        .unwrap_or((prompt, ""))
+}
+
+/// Insert `text` in place of the `{text}` placeholder of `prompt`, or prepend `prompt` to `text` if
+/// it has no placeholder
+fn apply_prompt(prompt: Option<String>, text: String) -> String {
+    match prompt {
+        None => text,
+        Some(prompt) => {
+            // Allocated once with the final length
+            let (prefix, suffix) = split_prompt(&prompt);
+            let mut prompted = String::with_capacity(prefix.len() + text.len() + suffix.len());
+            prompted.push_str(prefix);
+            prompted.push_str(&text);
+            prompted.push_str(suffix);
+            prompted
+        }
+    }
+}
+
+thread_local! {
+    /// Post-processor replacing the tokenizer one for the request processed by this worker
+    static TEMPLATE_OVERRIDE: RefCell<Option<PostProcessorWrapper>> = const { RefCell::new(None) };
+}
+
+/// Sets the post-processor of the request processed by this worker until dropped, so that it
+/// never applies to the following requests
+struct TemplateOverride;
+
+impl TemplateOverride {
+    fn set(
+        template: Option<String>,
+        tokenizer: &SharedTokenizer,
+    ) -> Result<Option<Self>, TextEmbeddingsError> {
+        let Some(template) = template else {
+            return Ok(None);
+        };
+        TEMPLATE_OVERRIDE.set(Some(tokenizer.template(&template)?));
+        Ok(Some(Self))
+    }
+}
+
+impl Drop for TemplateOverride {
+    fn drop(&mut self) {
+        TEMPLATE_OVERRIDE.take();
+    }
+}
+
+/// Only `add_special_tokens`, `prompt_name` and `prompt_text` of `options` are used, the
+/// truncation is given by `truncate_params`.
+/// `with_text` returns the text decoded from `Ids` inputs. It is only built when requested.
+#[allow(clippy::too_many_arguments)]
+fn tokenize_input(
+    inputs: EncodingInput,
+    options: &EncodeOptions,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    truncate_params: Option<TruncationParams>,
+    default_prompt: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+    with_text: bool,
+    tokenizer: &SharedTokenizer,
+) -> Result<(Option<String>, RawEncoding, bool), TextEmbeddingsError> {
+    let inputs = inputs.resolve_half_empty(tokenizer.half_empty_dual)?;
+    let pre_prompt = prepare_pre_prompt(
+        default_prompt,
+        options.prompt_name.clone(),
+        options.prompt_text.clone(),
+        prompts,
+        true,
+        tokenizer.metrics,
+    )?;
+    tokenize_resolved(
+        inputs,
+        pre_prompt,
+        options.add_special_tokens,
+        max_input_length,
+        max_char_multiplier,
+        truncate_params,
+        with_text,
+        tokenizer,
+    )
+}
+
+/// `tokenize_input` for inputs whose half empty pairs and prompt are already resolved
+#[allow(clippy::too_many_arguments)]
+fn tokenize_resolved(
+    mut inputs: EncodingInput,
+    pre_prompt: Option<String>,
+    add_special_tokens: bool,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    truncate_params: Option<TruncationParams>,
+    with_text: bool,
+    tokenizer: &SharedTokenizer,
+) -> Result<(Option<String>, RawEncoding, bool), TextEmbeddingsError> {
+    // The prompt is left as is
+    if let Some(normalization) = tokenizer.normalization {
+        inputs.normalize(normalization);
+    }
+
+    let limit = max_input_length * max_char_multiplier;
+    let mut limited = false;
+    // The byte length is an upper bound of the number of chars: short inputs skip the scan
+    let short_input = match &inputs {
+        EncodingInput::Single(s) => s.len() <= limit,
+        EncodingInput::Dual(s1, s2) => s1.len() + s2.len() <= limit,
+        EncodingInput::Ids(_) | EncodingInput::PreTokenized { .. } => false,
+    };
+    // Short inputs are only scanned for the metrics on a sample of the requests, that every
+    // request has the same chance to be part of
+    let sampled = tokenizer.metrics
+        && INPUT_CHARS_SAMPLES.fetch_add(1, Ordering::Relaxed) % INPUT_CHARS_SAMPLING == 0;
+    let input_chars = (!short_input || sampled).then(|| inputs.count_chars());
+    if let Some(input_chars) = input_chars.filter(|_| sampled) {
+        let histogram = metrics::histogram!("te_request_input_chars");
+        histogram.record(input_chars as f64);
+    }
+    let over_limit = input_chars.filter(|&input_chars| input_chars > limit);
+    if let Some(input_chars) = over_limit {
+        if truncate_params.is_none() {
+            return Err(TextEmbeddingsError::Validation(
+                ValidationError::TooManyChars {
+                    limit,
+                    given: input_chars,
+                },
+            ));
+        }
+        limited = inputs.apply_limit(limit, tokenizer.dual_split.unwrap_or_default());
+        if limited && tokenizer.metrics {
+            let counter =
+                metrics::counter!("te_request_char_limit_hit_total", "input" => inputs.kind());
+            counter.increment(1);
+        }
+    }
+
+    let (text, encoding) = match inputs {
+        // encode input
+        EncodingInput::Single(s) => match (pre_prompt, truncate_params.as_ref()) {
+            // Only the text is truncated, never the prompt
+            (Some(pre_prompt), Some(params)) => {
+                let encoding =
+                    tokenizer.encode_with_prompt(&pre_prompt, &s, add_special_tokens, params)?;
+
+                (Some(apply_prompt(Some(pre_prompt), s)), encoding)
+            }
+            (pre_prompt, _) => {
+                let s = apply_prompt(pre_prompt, s);
+
+                let encoding =
+                    tokenizer.encode::<&str>(&s, add_special_tokens, truncate_params.as_ref())?;
+
+                (Some(s), encoding)
+            }
+        },
+        EncodingInput::Dual(s1, s2) => {
+            // The prompt only applies to the first (query) segment
+            let s1 = apply_prompt(pre_prompt, s1);
+
+            (
+                None,
+                tokenizer.encode::<(String, String)>(
+                    (s1, s2),
+                    add_special_tokens,
+                    truncate_params.as_ref(),
+                )?,
+            )
+        }
+        // input is encoded -> convert to tokenizers Encoding
+        EncodingInput::Ids(ids) => {
+            check_ids(&ids, tokenizer)?;
+
+            let prompt_ids = pre_prompt
+                .as_deref()
+                .and_then(|prompt| tokenizer.prompt_ids(prompt, &ids));
+            if let Some((prefix, suffix)) = prompt_ids {
+                // Put the prompt ids around the input instead of decoding and re-encoding it. Only
+                // the input is truncated, never the prompt.
+                let encoding = match truncate_params.as_ref() {
+                    Some(params) => tokenizer.truncate_between_prompt(
+                        tokenizer.encoding_from_ids(prefix, 0),
+                        tokenizer.encoding_from_ids(&ids, 0),
+                        tokenizer.encoding_from_ids(suffix, 0),
+                        add_special_tokens,
+                        params,
+                    )?,
+                    None => {
+                        let ids: Vec<u32> =
+                            prefix.iter().chain(&ids).chain(suffix).copied().collect();
+                        tokenizer.post_process(
+                            tokenizer.encoding_from_ids(&ids, 0),
+                            None,
+                            add_special_tokens,
+                        )?
+                    }
+                };
+
+                (None, encoding)
+            } else if let Some(pre_prompt) = pre_prompt {
+                let text = tokenizer.decode(&ids, true)?;
+
+                // Same as the `Single` inputs: only the text is truncated, never the prompt
+                match truncate_params.as_ref() {
+                    Some(params) => {
+                        let encoding = tokenizer.encode_with_prompt(
+                            &pre_prompt,
+                            &text,
+                            add_special_tokens,
+                            params,
+                        )?;
+                        (
+                            with_text.then(|| apply_prompt(Some(pre_prompt), text)),
+                            encoding,
+                        )
+                    }
+                    None => {
+                        // The prompted text is built once for the encoding and the caller
+                        let text = apply_prompt(Some(pre_prompt), text);
+                        let encoding = tokenizer.encode::<&str>(&text, add_special_tokens, None)?;
+                        (with_text.then_some(text), encoding)
+                    }
+                }
+            } else {
+                // The special tokens of the ids are kept. With `add_special_tokens`, the ones the
+                // post-processor adds again are stripped from the ends first, so that content ids
+                // and complete ids both end up with a single set.
+                let ids = if add_special_tokens {
+                    tokenizer.strip_added_special_ids(&ids)?
+                } else {
+                    &ids[..]
+                };
+                let text = tokenizer.decode(ids, false)?;
+
+                let encoding = tokenizer.encode::<&str>(
+                    &text,
+                    add_special_tokens,
+                    truncate_params.as_ref(),
+                )?;
+
+                (with_text.then_some(text), encoding)
+            }
+        }
+        // input is already tokenized -> the ids are used as is
+        EncodingInput::PreTokenized { ids, type_ids } => {
+            if ids.len() != type_ids.len() {
+                return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                    format!(
+                        "`type_ids` must have the same length as `ids`. Given: {} and {}",
+                        type_ids.len(),
+                        ids.len()
+                    ),
+                )));
+            }
+            check_ids(&ids, tokenizer)?;
+
+            let mut encoding = tokenizer.encoding_from_ids(&ids, 0);
+            encoding.set_type_ids(type_ids);
+            if let Some(params) = truncate_params.as_ref() {
+                encoding = tokenizer.truncate(encoding, params.max_length, params);
+            }
+
+            (None, encoding)
+        }
+    };
+    Ok((text, encoding, limited))
+}
+
+/// Tokenize the input and locate the tokens coming from the prompt
+#[allow(clippy::too_many_arguments)]
+fn tokenize_with_prompt(
+    inputs: EncodingInput,
+    add_special_tokens: bool,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    default_prompt: Option<String>,
+    prompt_name: Option<String>,
+    prompt_text: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+    tokenizer: &SharedTokenizer,
+) -> Result<Tokenized, TextEmbeddingsError> {
+    // Resolved before looking at the segments, see `tokenize_input`
+    let inputs = inputs.resolve_half_empty(tokenizer.half_empty_dual)?;
+    let pre_prompt = prepare_pre_prompt(
+        default_prompt,
+        prompt_name,
+        prompt_text,
+        prompts,
+        true,
+        tokenizer.metrics,
+    )?;
+    // Pre-tokenized inputs are used as is, the prompt is never applied
+    let prompt = match inputs {
+        EncodingInput::PreTokenized { .. } => None,
+        _ => pre_prompt.clone(),
+    };
+    // The normalization can change the length of the text, see `map_to_original`
+    let normalized = match (&inputs, tokenizer.normalization) {
+        (EncodingInput::Single(s) | EncodingInput::Dual(s, _), Some(normalization)) => {
+            Some(normalization.normalize(s))
+        }
+        _ => None,
+    };
+    // `Ids` inputs may get the prompt ids prepended without decoding
+    let prompt_ids_len = match (&inputs, prompt.as_deref()) {
+        (EncodingInput::Ids(ids), Some(prompt)) => tokenizer
+            .prompt_ids(prompt, ids)
+            .map(|(prefix, suffix)| prefix.len() + suffix.len()),
+        _ => None,
+    };
+
+    let (mut full_text, mut encoding, _) = tokenize_resolved(
+        inputs,
+        pre_prompt,
+        add_special_tokens,
+        max_input_length,
+        max_char_multiplier,
+        None,
+        true,
+        tokenizer,
+    )?;
+
+    let prompt_token_count = match (prompt.as_deref(), prompt_ids_len) {
+        (_, Some(prompt_ids_len)) => prompt_ids_len,
+        (None, None) => 0,
+        // Counted from the prompt ids rather than from the offsets, as a token can straddle the
+        // boundary between the prompt and the text
+        (Some(prompt), None) => prompt_token_count(prompt, tokenizer)?,
+    };
+
+    // Only single inputs have a `full_text` the offsets refer to
+    let mut offsets_approximate = false;
+    let changed = normalized.filter(|normalized| normalized.get() != normalized.get_original());
+    if let (Some(normalized), Some(text)) = (changed, &mut full_text) {
+        let (prefix, suffix) = prompt.as_deref().map(split_prompt).unwrap_or_default();
+        offsets_approximate = !map_to_original(&mut encoding, &normalized, prefix.len());
+        *text = format!("{prefix}{}{suffix}", normalized.get_original());
+    }
+
+    Ok(Tokenized {
+        full_text,
+        prompt_text: prompt,
+        prompt_token_count,
+        offsets_approximate,
+        encoding,
+    })
+}
+
+/// Make the offsets of `encoding` refer to the original text instead of the `normalized` one,
+/// which starts at `text_start` in the tokenized text. The offsets after the text are shifted by
+/// the difference of length. Returns `false` if some offsets could not be mapped and were left
+/// as is.
+fn map_to_original(
+    encoding: &mut RawEncoding,
+    normalized: &NormalizedString,
+    text_start: usize,
+) -> bool {
+    let text_end = text_start + normalized.get().len();
+    let original_end = text_start + normalized.get_original().len();
+    let mut exact = true;
+    encoding.process_tokens_with_offsets_mut(|(_, (_, offsets))| {
+        let (start, end) = *offsets;
+        // Part of the token inside the text, in normalized coordinates
+        let (text_token_start, text_token_end) = (
+            start.clamp(text_start, text_end) - text_start,
+            end.clamp(text_start, text_end) - text_start,
+        );
+        let Some(original) = normalized.convert_offsets(NormalizedRange::Normalized(
+            text_token_start..text_token_end,
+        )) else {
+            exact = false;
+            return;
+        };
+        let map = |position: usize, original_position: usize| {
+            if position <= text_start {
+                position
+            } else if position >= text_end {
+                position - text_end + original_end
+            } else {
+                text_start + original_position
+            }
+        };
+        *offsets = (map(start, original.start), map(end, original.end));
+    });
+    exact
+}
+
+/// Get input length and optionally truncate it. `position_offset` is the default offset of the
+/// `position_ids`, replaced by the one of `options` when set. `options.template_override`,
+/// `options.leading_cls`, `options.request_id` and `options.timeout` are handled by the caller.
+#[allow(clippy::too_many_arguments)]
+fn encode_input(
+    inputs: EncodingInput,
+    options: &EncodeOptions,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    position_offset: usize,
+    default_prompt: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+    tokenizer: &SharedTokenizer,
+) -> Result<ValidEncoding, TextEmbeddingsError> {
+    let (encoding, _) = encode_input_raw(
+        inputs,
+        options,
+        max_input_length,
+        max_char_multiplier,
+        position_offset,
+        default_prompt,
+        prompts,
+        tokenizer,
+    )?;
+    Ok(encoding)
+}
+
+/// Apply `leading_cls` to the result of `encode`. The input is encoded again with the CLS token
+/// prepended to the `wrap_tokens` when it has to be inserted, so that it counts in the length.
+fn encode_with_leading_cls(
+    inputs: EncodingInput,
+    wrap_tokens: Option<(Vec<u32>, Vec<u32>)>,
+    leading_cls: LeadingCls,
+    tokenizer: &SharedTokenizer,
+    encode: impl Fn(
+        EncodingInput,
+        Option<(Vec<u32>, Vec<u32>)>,
+    ) -> Result<ValidEncoding, TextEmbeddingsError>,
+) -> Result<ValidEncoding, TextEmbeddingsError> {
+    if leading_cls == LeadingCls::Unchecked {
+        return encode(inputs, wrap_tokens);
+    }
+    let Some(cls_id) = tokenizer.cls_id else {
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            "`leading_cls` is set but the tokenizer has no CLS token".to_string(),
+        )));
+    };
+    // Left padding comes before the CLS token
+    let starts_with_cls = |encoding: &ValidEncoding| {
+        encoding
+            .input_ids
+            .iter()
+            .zip(&encoding.attention_mask)
+            .find(|(_, &attention)| attention == 1)
+            .is_some_and(|(&id, _)| id == cls_id)
+    };
+
+    let encoding = encode(inputs.clone(), wrap_tokens.clone())?;
+    if starts_with_cls(&encoding) && encoding.chunks.iter().all(starts_with_cls) {
+        return Ok(encoding);
+    }
+    match leading_cls {
+        LeadingCls::Unchecked => Ok(encoding),
+        LeadingCls::Require => Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            format!("`input_ids` must start with the CLS token {cls_id}"),
+        ))),
+        LeadingCls::Insert => {
+            let (prefix, suffix) = wrap_tokens.unwrap_or_default();
+            let prefix = std::iter::once(cls_id).chain(prefix).collect();
+            encode(inputs, Some((prefix, suffix)))
+        }
+    }
+}
+
+/// `encode_input` also returning the encoding the `ValidEncoding` was built from
+#[allow(clippy::too_many_arguments)]
+fn encode_input_raw(
+    inputs: EncodingInput,
+    options: &EncodeOptions,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    position_offset: usize,
+    default_prompt: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+    tokenizer: &SharedTokenizer,
+) -> Result<(ValidEncoding, RawEncoding), TextEmbeddingsError> {
+    let EncodeOptions {
+        add_special_tokens,
+        overflow_policy,
+        truncation_direction,
+        truncation_strategy,
+        with_offsets,
+        with_special_mask,
+        pad_to_multiple_of,
+        ..
+    } = *options;
+    // The request offset overrides the default one
+    let position_offset = options.position_offset.unwrap_or(position_offset);
+    // `OnlyFirst` and `OnlySecond` select a segment of a pair
+    if !matches!(truncation_strategy, TruncationStrategy::LongestFirst)
+        && !matches!(inputs, EncodingInput::Dual(_, _))
+    {
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            format!(
+                "`{truncation_strategy:?}` truncation strategy can only be used with dual inputs"
+            ),
+        )));
+    }
+    // Resolved before looking at the segments, see `tokenize_input`. A pair collapsed to its
+    // non-empty segment has a single segment to truncate.
+    let inputs = inputs.resolve_half_empty(tokenizer.half_empty_dual)?;
+    let truncation_strategy = match inputs {
+        EncodingInput::Dual(_, _) => truncation_strategy,
+        _ => TruncationStrategy::LongestFirst,
+    };
+
+    let pre_prompt = prepare_pre_prompt(
+        default_prompt,
+        options.prompt_name.clone(),
+        options.prompt_text.clone(),
+        prompts,
+        true,
+        tokenizer.metrics,
+    )?;
+    // Pre-tokenized inputs are used as is, the prompt is never applied
+    let applied_prompt = match inputs {
+        EncodingInput::PreTokenized { .. } => None,
+        _ => pre_prompt.clone(),
+    };
+    // The prompt tokens do not take room from the input when they are not counted, within the
+    // hard limit of the model
+    let n_uncounted_tokens = match applied_prompt.as_deref() {
+        Some(prompt) if !tokenizer.count_prompt_in_limit => {
+            let n_prompt_tokens = prompt_token_count(prompt, tokenizer)?;
+            match tokenizer.max_model_length {
+                Some(max_model_length) => {
+                    n_prompt_tokens.min(max_model_length.saturating_sub(max_input_length))
+                }
+                None => n_prompt_tokens,
+            }
+        }
+        _ => 0,
+    };
+
+    // Keep room for the wrapping tokens
+    let n_wrap_tokens = options
+        .wrap_tokens
+        .as_ref()
+        .map(|(prefix, suffix)| prefix.len() + suffix.len())
+        .unwrap_or(0);
+    let max_length = (max_input_length + n_uncounted_tokens).saturating_sub(n_wrap_tokens);
+    let truncate_params = match overflow_policy {
+        OverflowPolicy::Error => None,
+        OverflowPolicy::Truncate => Some(TruncationParams {
+            direction: truncation_direction,
+            max_length,
+            strategy: truncation_strategy,
+            stride: 0,
+        }),
+        OverflowPolicy::Chunk { stride } => Some(TruncationParams {
+            direction: truncation_direction,
+            max_length,
+            strategy: truncation_strategy,
+            stride,
+        }),
+    };
+
+    let kind = inputs.kind();
+    let is_pair = matches!(inputs, EncodingInput::Dual(_, _));
+    // Pre-tokenized inputs are only cut
+    let add_kept_tokens =
+        add_special_tokens && !matches!(inputs, EncodingInput::PreTokenized { .. });
+    let (_, mut encoding, limited) = tokenize_resolved(
+        inputs,
+        pre_prompt,
+        add_special_tokens,
+        max_input_length,
+        max_char_multiplier,
+        truncate_params,
+        false,
+        tokenizer,
+    )?;
+    if tokenizer.min_content_tokens > 0 {
+        check_content_tokens(&encoding, applied_prompt.as_deref(), tokenizer)?;
+    }
+    if let Some((prefix, suffix)) = options.wrap_tokens.clone() {
+        encoding = wrap_encoding(encoding, prefix, suffix, tokenizer);
+    }
+    // The tokens that do not fit are kept as the following windows instead of being dropped
+    let chunks = match overflow_policy {
+        OverflowPolicy::Chunk { .. } => encoding.take_overflowing(),
+        OverflowPolicy::Error | OverflowPolicy::Truncate => Vec::new(),
+    };
+    let mut seq_len = check_input_length(
+        encoding.len().saturating_sub(n_uncounted_tokens),
+        max_input_length,
+    );
+    if overflow_policy == OverflowPolicy::Error {
+        seq_len = with_truncation_hint(seq_len, || {
+            let n_special_and_prompt_tokens = untruncatable_tokens(
+                is_pair,
+                add_kept_tokens,
+                applied_prompt
+                    .as_deref()
+                    .filter(|_| tokenizer.count_prompt_in_limit),
+                tokenizer,
+            )?;
+            Ok(n_wrap_tokens + n_special_and_prompt_tokens)
+        });
+    }
+    let seq_len = seq_len? + n_uncounted_tokens;
+
+    let histogram = tokenizer
+        .metrics
+        .then(|| metrics::histogram!("te_request_input_length"));
+    if let Some(histogram) = &histogram {
+        histogram.record(seq_len as f64);
+    }
+
+    // Truncated tokens are moved to the overflowing encodings
+    let overflowed = !encoding.get_overflowing().is_empty();
+    if overflowed && tokenizer.metrics {
+        let counter = metrics::counter!("te_request_truncated_total", "input" => kind);
+        counter.increment(1);
+    }
+
+    let chunks = chunks
+        .into_iter()
+        .map(|mut chunk| {
+            if let Some(histogram) = &histogram {
+                histogram.record(chunk.len() as f64);
+            }
+            into_valid_encoding(
+                &mut chunk,
+                position_offset,
+                limited,
+                with_offsets,
+                with_special_mask,
+                pad_to_multiple_of,
+                max_input_length,
+                tokenizer,
+            )
+        })
+        .collect::<Result<_, _>>()?;
+
+    let mut valid = into_valid_encoding(
+        &mut encoding,
+        position_offset,
+        limited || overflowed,
+        with_offsets,
+        with_special_mask,
+        pad_to_multiple_of,
+        max_input_length,
+        tokenizer,
+    )?;
+    valid.applied_prompt = applied_prompt;
+    valid.chunks = chunks;
+    Ok((valid, encoding))
+}
+
+/// Check that `encoding` has at least `min_content_tokens` tokens that are not special, not
+/// whitespace and not coming from `prompt`
+fn check_content_tokens(
+    encoding: &RawEncoding,
+    prompt: Option<&str>,
+    tokenizer: &SharedTokenizer,
+) -> Result<(), TextEmbeddingsError> {
+    let is_content = |id: u32| -> Result<bool, TextEmbeddingsError> {
+        Ok(!tokenizer.decode(&[id], true)?.trim().is_empty())
+    };
+
+    let n_prompt_tokens = match prompt {
+        None => 0,
+        Some(prompt) => {
+            let mut n_prompt_tokens = 0;
+            for id in prompt_token_ids(prompt, tokenizer)? {
+                n_prompt_tokens += is_content(id)? as usize;
+            }
+            n_prompt_tokens
+        }
+    };
+
+    // Stop decoding once enough tokens were found
+    let required = tokenizer.min_content_tokens + n_prompt_tokens;
+    let mut n_tokens = 0;
+    for (&id, &special) in encoding
+        .get_ids()
+        .iter()
+        .zip(encoding.get_special_tokens_mask())
+    {
+        if n_tokens == required {
+            return Ok(());
+        }
+        if special == 0 && is_content(id)? {
+            n_tokens += 1;
+        }
+    }
+    if n_tokens >= required {
+        return Ok(());
+    }
+    Err(TextEmbeddingsError::Validation(
+        ValidationError::TooFewTokens {
+            minimum: tokenizer.min_content_tokens,
+            given: n_tokens.saturating_sub(n_prompt_tokens),
+        },
+    ))
+}
+
+/// Add the `prefix` and `suffix` token ids around `encoding` and its overflowing encodings
+fn wrap_encoding(
+    encoding: RawEncoding,
+    prefix: Vec<u32>,
+    suffix: Vec<u32>,
+    tokenizer: &SharedTokenizer,
+) -> RawEncoding {
+    // The suffix belongs to the same segment as the end of the input
+    let suffix_type_id = encoding.get_type_ids().last().copied().unwrap_or(0);
+    let mut wrapped = tokenizer.encoding_from_ids(&prefix, 0);
+    wrapped.merge_with(encoding, false);
+    wrapped.merge_with(tokenizer.encoding_from_ids(&suffix, suffix_type_id), false);
+    wrapped
+}
+
+/// Build a `ValidEncoding`, padding `encoding` first if `pad_to_multiple_of` is set. The padding
+/// stops at `max_input_length` so that the `position_ids` stay within the model positions.
+#[allow(clippy::too_many_arguments)]
+fn into_valid_encoding(
+    encoding: &mut RawEncoding,
+    position_offset: usize,
+    truncated: bool,
+    with_offsets: bool,
+    with_special_mask: bool,
+    pad_to_multiple_of: Option<usize>,
+    max_input_length: usize,
+    tokenizer: &SharedTokenizer,
+) -> Result<ValidEncoding, TextEmbeddingsError> {
+    let seq_len = encoding.len();
+    let padded_len = match pad_to_multiple_of {
+        None => seq_len,
+        Some(0) => {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                "`pad_to_multiple_of` must be greater than 0".to_string(),
+            )))
+        }
+        Some(multiple) => {
+            let Some(padding) = &tokenizer.padding else {
+                return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                    "`pad_to_multiple_of` is set but the tokenizer has no padding token configured"
+                        .to_string(),
+                )));
+            };
+            let padded_len = (seq_len.div_ceil(multiple) * multiple)
+                .min(max_input_length)
+                .max(seq_len);
+            encoding.pad(
+                padded_len,
+                padding.pad_id,
+                padding.pad_type_id,
+                &padding.pad_token,
+                padding.direction,
+            );
+            padded_len
+        }
+    };
+
+    Ok(ValidEncoding {
+        input_ids: encoding.get_ids().to_vec(),
+        token_type_ids: encoding.get_type_ids().to_vec(),
+        position_ids: (position_offset as u32..(padded_len + position_offset) as u32)
+            .collect::<Vec<_>>(),
+        attention_mask: encoding.get_attention_mask().to_vec(),
+        truncated,
+        offsets: with_offsets.then(|| encoding.get_offsets().to_vec()),
+        special_tokens_mask: with_special_mask.then(|| encoding.get_special_tokens_mask().to_vec()),
+        applied_prompt: None,
+        chunks: Vec::new(),
+    })
+}
+
+/// Length of the longest of `encodings`, padding the others to it if `pad_to_longest` is set
+fn pad_batch(
+    mut encodings: Vec<ValidEncoding>,
+    pad_to_longest: bool,
+    tokenizer: &SharedTokenizer,
+) -> Result<(Vec<ValidEncoding>, usize), TextEmbeddingsError> {
+    let max_length = encodings
+        .iter()
+        .map(|encoding| encoding.input_ids.len())
+        .max()
+        .unwrap_or(0);
+    if !pad_to_longest {
+        return Ok((encodings, max_length));
+    }
+    let Some(padding) = &tokenizer.padding else {
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            "`pad_to_longest` is set but the tokenizer has no padding token configured".to_string(),
+        )));
+    };
+
+    for encoding in &mut encodings {
+        let n_pad = max_length - encoding.input_ids.len();
+        if n_pad == 0 {
+            continue;
+        }
+        let direction = padding.direction;
+        pad_values(&mut encoding.input_ids, padding.pad_id, n_pad, direction);
+        pad_values(
+            &mut encoding.token_type_ids,
+            padding.pad_type_id,
+            n_pad,
+            direction,
+        );
+        pad_values(&mut encoding.attention_mask, 0, n_pad, direction);
+        if let Some(special_tokens_mask) = &mut encoding.special_tokens_mask {
+            pad_values(special_tokens_mask, 1, n_pad, direction);
+        }
+        if let Some(offsets) = &mut encoding.offsets {
+            pad_values(offsets, (0, 0), n_pad, direction);
+        }
+        // Same numbering as `into_valid_encoding`: the positions run over the padding
+        let position_offset = encoding.position_ids.first().copied().unwrap_or(0);
+        encoding.position_ids = (position_offset..position_offset + max_length as u32).collect();
+    }
+    Ok((encodings, max_length))
+}
+
+/// Add `n_pad` times `value` on the `direction` side of `values`
+fn pad_values<T: Clone>(values: &mut Vec<T>, value: T, n_pad: usize, direction: PaddingDirection) {
+    let padding = std::iter::repeat_n(value, n_pad);
+    match direction {
+        PaddingDirection::Left => {
+            values.splice(0..0, padding);
+        }
+        PaddingDirection::Right => values.extend(padding),
+    }
+}
+
+/// Keep the encodings that fit in `max_total_tokens`, skipping the ones that would exceed it.
+/// Returns the kept encodings and the indices of the skipped ones.
+fn pack_encodings(
+    encodings: Vec<ValidEncoding>,
+    max_total_tokens: usize,
+) -> (Vec<ValidEncoding>, Vec<usize>) {
+    let mut total_tokens = 0;
+    let mut packed = Vec::with_capacity(encodings.len());
+    let mut deferred = Vec::new();
+    for (i, encoding) in encodings.into_iter().enumerate() {
+        let seq_len = encoding.input_ids.len();
+        if total_tokens + seq_len > max_total_tokens {
+            deferred.push(i);
+        } else {
+            total_tokens += seq_len;
+            packed.push(encoding);
+        }
+    }
+    (packed, deferred)
+}
+
+/// Estimate the number of tokens of an input of `chars` characters without tokenizing it, for
+/// example to reject oversized payloads before queueing them. Special tokens are not counted.
+///
+/// This is only an estimate: the minimum assumes that a token covers at most `multiplier` chars,
+/// as the `MAX_CHAR_MULTIPLIER` guard does, and the maximum that each byte of a UTF-8 char becomes
+/// its own token. The actual length is still checked by `encode`.
+pub fn estimate_tokens(chars: usize, multiplier: usize) -> (usize, usize) {
+    (chars.div_ceil(multiplier.max(1)), chars.saturating_mul(4))
+}
+
+/// Number of occurrences of each token id of `encoding`, special tokens excluded
+fn count_token_ids(encoding: &RawEncoding) -> HashMap<u32, u32> {
+    let mut counts = HashMap::new();
+    for (&id, &special) in encoding
+        .get_ids()
+        .iter()
+        .zip(encoding.get_special_tokens_mask())
+    {
+        if special == 0 {
+            *counts.entry(id).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// Tell in a `TooManyTokens` error whether the input would fit once truncated, given the number
+/// of tokens that truncation cannot remove
+fn with_truncation_hint(
+    result: Result<usize, TextEmbeddingsError>,
+    n_kept_tokens: impl FnOnce() -> Result<usize, TextEmbeddingsError>,
+) -> Result<usize, TextEmbeddingsError> {
+    match result {
+        Err(TextEmbeddingsError::Validation(ValidationError::TooManyTokens {
+            limit,
+            given,
+            ..
+        })) => Err(TextEmbeddingsError::Validation(
+            ValidationError::TooManyTokens {
+                limit,
+                given,
+                truncation_would_fit: n_kept_tokens()? < limit,
+            },
+        )),
+        result => result,
+    }
+}
+
+/// Number of tokens kept by the truncation: the prompt and the special tokens
+fn untruncatable_tokens(
+    is_pair: bool,
+    add_special_tokens: bool,
+    prompt: Option<&str>,
+    tokenizer: &SharedTokenizer,
+) -> Result<usize, TextEmbeddingsError> {
+    let n_special_tokens = match (add_special_tokens, tokenizer.post_processor().as_deref()) {
+        (true, Some(post_processor)) => post_processor.added_tokens(is_pair),
+        _ => 0,
+    };
+    let n_prompt_tokens = match prompt {
+        Some(prompt) => prompt_token_ids(prompt, tokenizer)?.len(),
+        None => 0,
+    };
+    Ok(n_special_tokens + n_prompt_tokens)
+}
+
+/// Number of tokens of the text before and after the placeholder of `prompt`. The configured
+/// prompts are not tokenized again.
+fn prompt_token_count(
+    prompt: &str,
+    tokenizer: &SharedTokenizer,
+) -> Result<usize, TextEmbeddingsError> {
+    match tokenizer.prompt_ids.get(prompt) {
+        Some((prefix, suffix)) => Ok(prefix.len() + suffix.len()),
+        None => Ok(prompt_token_ids(prompt, tokenizer)?.len()),
+    }
+}
+
+/// Token ids of the text before and after the placeholder of `prompt`
+fn prompt_token_ids(
+    prompt: &str,
+    tokenizer: &SharedTokenizer,
+) -> Result<Vec<u32>, TextEmbeddingsError> {
+    if let Some((prefix, suffix)) = tokenizer.prompt_ids.get(prompt) {
+        return Ok(prefix.iter().chain(suffix).copied().collect());
+    }
+    let (prefix, suffix) = split_prompt(prompt);
+    let mut ids = tokenizer
+        .tokenizer
+        .encode(prefix, false)?
+        .get_ids()
+        .to_vec();
+    ids.extend(tokenizer.tokenizer.encode(suffix, false)?.get_ids());
+    Ok(ids)
+}
+
+/// Returns `seq_len` if it fits in `max_input_length`
+fn check_input_length(
+    seq_len: usize,
+    max_input_length: usize,
+) -> Result<usize, TextEmbeddingsError> {
+    if seq_len > max_input_length {
+        return Err(TextEmbeddingsError::Validation(
+            ValidationError::TooManyTokens {
+                limit: max_input_length,
+                given: seq_len,
+                truncation_would_fit: false,
+            },
+        ));
+    }
+    Ok(seq_len)
+}
+
+/// Returns an error for the first id that is not in the vocabulary
+fn check_ids(ids: &[u32], tokenizer: &SharedTokenizer) -> Result<(), TextEmbeddingsError> {
+    let vocab_size = tokenizer.tokenizer.get_vocab_size(true);
+    match ids.iter().find(|&&id| id as usize >= vocab_size) {
+        Some(id) => Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            format!("token id {id} out of range for a vocabulary of {vocab_size} tokens"),
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Custom truncation of the single sequence inputs, replacing the `TruncationParams` direction
+/// and stride. Pairs are always truncated with `TruncationStrategy`.
+pub trait Truncator: fmt::Debug + Send + Sync {
+    /// Return `encoding` shortened to at most `max_len` tokens, keeping the tokens in order.
+    /// Special tokens are added afterwards. Tokens that are not from `encoding` must be marked in
+    /// the special tokens mask.
+    fn truncate(&self, encoding: RawEncoding, max_len: usize) -> RawEncoding;
+}
+
+/// Keep both ends of the input: the first `head` tokens and as many of the last tokens as fit,
+/// dropping the middle. `separator` is inserted in place of the dropped tokens when set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MiddleTruncation {
+    pub head: usize,
+    pub separator: Option<u32>,
+}
+
+impl Truncator for MiddleTruncation {
+    fn truncate(&self, encoding: RawEncoding, max_len: usize) -> RawEncoding {
+        let len = encoding.len();
+        if len <= max_len {
+            return encoding;
+        }
+        // No room for the separator in an empty budget
+        let separator = self.separator.filter(|_| max_len > 0);
+        let budget = max_len - separator.is_some() as usize;
+        let head = self.head.min(budget);
+        let tail_start = len - (budget - head);
+
+        // The separator takes an empty span where the dropped tokens started
+        let separator_offset = encoding.get_offsets()[..head]
+            .last()
+            .map(|&(_, end)| (end, end))
+            .unwrap_or_default();
+        let type_id = encoding.get_type_ids().first().copied().unwrap_or(0);
+        let ids = drop_middle(encoding.get_ids(), head, tail_start, separator);
+        let type_ids = drop_middle(
+            encoding.get_type_ids(),
+            head,
+            tail_start,
+            separator.map(|_| type_id),
+        );
+        let tokens = drop_middle(
+            encoding.get_tokens(),
+            head,
+            tail_start,
+            separator.map(|_| String::new()),
+        );
+        let word_ids = drop_middle(
+            encoding.get_word_ids(),
+            head,
+            tail_start,
+            separator.map(|_| None),
+        );
+        let offsets = drop_middle(
+            encoding.get_offsets(),
+            head,
+            tail_start,
+            separator.map(|_| separator_offset),
+        );
+        let special_tokens_mask = drop_middle(
+            encoding.get_special_tokens_mask(),
+            head,
+            tail_start,
+            separator.map(|_| 1),
+        );
+        let attention_mask = drop_middle(
+            encoding.get_attention_mask(),
+            head,
+            tail_start,
+            separator.map(|_| 1),
+        );
+
+        RawEncoding::new(
+            ids,
+            type_ids,
+            tokens,
+            word_ids,
+            offsets,
+            special_tokens_mask,
+            attention_mask,
+            Vec::new(),
+            HashMap::new(),
+        )
+    }
+}
+
+/// `values` without the ones between `head` and `tail_start`, replaced by `separator` if set
+fn drop_middle<T: Clone>(
+    values: &[T],
+    head: usize,
+    tail_start: usize,
+    separator: Option<T>,
+) -> Vec<T> {
+    values[..head]
+        .iter()
+        .cloned()
+        .chain(separator)
+        .chain(values[tail_start..].iter().cloned())
+        .collect()
+}
+
+/// Tokenizer shared read-only by all the workers
+///
+/// `tokenizers` stores the truncation parameters in the tokenizer itself, which would require a
+/// mutable copy of the whole tokenizer per worker. Instead, the raw encodings are truncated per
+/// request and the post-processor is applied on top of them, mirroring `Tokenizer::encode`.
+#[derive(Debug)]
+struct SharedTokenizer {
+    /// Tokenizer without truncation, padding nor post-processor
+    tokenizer: Tokenizer,
+    /// Padding configuration of the tokenizer, only applied when a request asks for padding
+    padding: Option<PaddingParams>,
+    /// Unicode normalization applied to the text inputs before tokenization
+    normalization: Option<UnicodeNormalization>,
+    /// Whether to emit metrics
+    metrics: bool,
+    /// Token ids before and after the placeholder of the configured prompts
+    prompt_ids: HashMap<String, (Vec<u32>, Vec<u32>)>,
+    post_processor: Option<PostProcessorWrapper>,
+    /// Replaces the longest first truncation of the single sequences when set
+    truncator: Option<Box<dyn Truncator>>,
+    /// Minimum number of tokens of the input besides the special and prompt tokens
+    min_content_tokens: usize,
+    /// Replaces the longest first truncation of the pairs when set
+    dual_split: Option<DualSplit>,
+    /// What to do with the `Dual` inputs with exactly one empty segment
+    half_empty_dual: HalfEmptyDual,
+    /// Whether the prompt tokens count against `max_input_length`
+    count_prompt_in_limit: bool,
+    /// Hard limit of the number of tokens of an encoding, the uncounted prompt included
+    max_model_length: Option<usize>,
+    /// Id of the CLS token, for `LeadingCls`
+    cls_id: Option<u32>,
+}
+
+impl SharedTokenizer {
+    fn new(mut tokenizer: Tokenizer) -> Self {
+        let post_processor = tokenizer.get_post_processor().cloned();
+        tokenizer.with_post_processor(None::<PostProcessorWrapper>);
+        tokenizer
+            .with_truncation(None)
+            .expect("Disabling truncation cannot fail");
+        // The segments must not be padded before they are truncated and post-processed
+        let padding = tokenizer.get_padding().cloned();
+        tokenizer.with_padding(None);
+
+        Self {
+            tokenizer,
+            padding,
+            normalization: None,
+            metrics: true,
+            prompt_ids: HashMap::new(),
+            post_processor,
+            truncator: None,
+            min_content_tokens: 0,
+            dual_split: None,
+            half_empty_dual: HalfEmptyDual::default(),
+            count_prompt_in_limit: true,
+            max_model_length: None,
+            cls_id: None,
+        }
+    }
+
+    /// Token ids of `prompt` if it can be prepended to `ids` as is. The input is decoded instead
+    /// when it contains special tokens, as they are added again around the prompt.
+    fn prompt_ids(&self, prompt: &str, ids: &[u32]) -> Option<&(Vec<u32>, Vec<u32>)> {
+        let added_vocabulary = self.tokenizer.get_added_vocabulary();
+        let plain_ids = ids.iter().all(|&id| {
+            self.tokenizer
+                .id_to_token(id)
+                .is_some_and(|token| !added_vocabulary.is_special_token(&token))
+        });
+        plain_ids.then(|| self.prompt_ids.get(prompt)).flatten()
+    }
+
+    /// Post-processor of the request: the template override of the worker if any
+    fn post_processor(&self) -> Option<Cow<'_, PostProcessorWrapper>> {
+        match TEMPLATE_OVERRIDE.with_borrow(Option::clone) {
+            Some(post_processor) => Some(Cow::Owned(post_processor)),
+            None => self.post_processor.as_ref().map(Cow::Borrowed),
+        }
+    }
+
+    /// Post-processor applying the single sequence `template`. Every piece of the template
+    /// besides `$A` is a special token, e.g. `[CLS]:0 $A:0 [SEP]:0`. The pair template repeats
+    /// the special tokens following `$A` after `$B`, e.g. `[CLS]:0 $A:0 [SEP]:0 $B:1 [SEP]:1`.
+    fn template(&self, template: &str) -> Result<PostProcessorWrapper, TextEmbeddingsError> {
+        fn strip_type_id(piece: &str) -> &str {
+            match piece.rsplit_once(':') {
+                Some((token, type_id)) if !token.is_empty() && type_id.parse::<u32>().is_ok() => {
+                    token
+                }
+                _ => piece,
+            }
+        }
+        let invalid = |message: String| {
+            TextEmbeddingsError::Validation(ValidationError::Other(format!(
+                "Invalid `template_override` `{template}`: {message}"
+            )))
+        };
+
+        let pieces: Vec<&str> = template.split_whitespace().collect();
+        let Some(sequence) = pieces.iter().position(|piece| piece.starts_with('$')) else {
+            return Err(invalid("`$A` is missing".to_string()));
+        };
+        let mut pair = format!("{template} $B:1");
+        for piece in &pieces[sequence + 1..] {
+            pair.push_str(&format!(" {}:1", strip_type_id(piece)));
+        }
+
+        let special_tokens = pieces
+            .iter()
+            .filter(|piece| !piece.starts_with('$'))
+            .map(|piece| {
+                let token = strip_type_id(piece);
+                match self.tokenizer.token_to_id(token) {
+                    Some(id) => Ok((token.to_string(), id)),
+                    None => Err(invalid(format!("`{token}` is not in the vocabulary"))),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let template = TemplateProcessing::builder()
+            .try_single(template)
+            .map_err(|err| invalid(err.to_string()))?
+            .try_pair(pair.as_str())
+            .map_err(|err| invalid(err.to_string()))?
+            .special_tokens(special_tokens)
+            .build()
+            .map_err(|err| invalid(err.to_string()))?;
+        Ok(PostProcessorWrapper::Template(template))
+    }
+
+    /// `ids` without the special tokens the post-processor adds before and after a single sequence,
+    /// when they start or end with them
+    fn strip_added_special_ids<'i>(
+        &self,
+        ids: &'i [u32],
+    ) -> Result<&'i [u32], TextEmbeddingsError> {
+        // The post-processor marks the tokens it adds as special, unlike the placeholder
+        let encoding =
+            self.truncate_and_post_process(self.encoding_from_ids(&[0], 0), None, true, None)?;
+        let special_tokens_mask = encoding.get_special_tokens_mask();
+        let placeholder = special_tokens_mask
+            .iter()
+            .position(|&special| special == 0)
+            .unwrap_or(special_tokens_mask.len());
+        let (prefix, suffix) = encoding.get_ids().split_at(placeholder);
+        let suffix = suffix.get(1..).unwrap_or_default();
+
+        let ids = ids.strip_prefix(prefix).unwrap_or(ids);
+        Ok(ids.strip_suffix(suffix).unwrap_or(ids))
+    }
+
+    /// Build an encoding from token ids, without offsets
+    fn encoding_from_ids(&self, ids: &[u32], type_id: u32) -> RawEncoding {
+        let tokens = ids
+            .iter()
+            .map(|&id| {
+                let value = self.tokenizer.id_to_token(id).unwrap_or_default();
+                Token::new(id, value, (0, 0))
+            })
+            .collect();
+        RawEncoding::from_tokens(tokens, type_id)
+    }
+
+    fn encode<'s, E>(
+        &self,
+        input: E,
+        add_special_tokens: bool,
+        truncate_params: Option<&TruncationParams>,
+    ) -> Result<RawEncoding, TextEmbeddingsError>
+    where
+        E: Into<EncodeInput<'s>>,
+    {
+        let (sequence, pair) = match input.into() {
+            EncodeInput::Single(sequence) => (sequence, None),
+            EncodeInput::Dual(sequence, pair) => (sequence, Some(pair)),
+        };
+
+        let encoding = self.tokenizer.encode(sequence, false)?;
+        let pair_encoding = match pair {
+            None => None,
+            Some(pair) => {
+                let mut pair_encoding = self.tokenizer.encode(pair, false)?;
+                // The second sequence of a pair has type id 1
+                pair_encoding.set_type_ids(vec![1; pair_encoding.len()]);
+                Some(pair_encoding)
+            }
+        };
+
+        self.truncate_and_post_process(encoding, pair_encoding, add_special_tokens, truncate_params)
+    }
+
+    /// Truncate the raw encodings, keeping room for the special tokens, then post-process them
+    fn truncate_and_post_process(
+        &self,
+        encoding: RawEncoding,
+        pair_encoding: Option<RawEncoding>,
+        add_special_tokens: bool,
+        truncate_params: Option<&TruncationParams>,
+    ) -> Result<RawEncoding, TextEmbeddingsError> {
+        let (encoding, pair_encoding) = match truncate_params {
+            None => (encoding, pair_encoding),
+            Some(params) => {
+                // Keep room for the special tokens added by the post-processor
+                let n_added_tokens = match (add_special_tokens, self.post_processor().as_deref()) {
+                    (true, Some(post_processor)) => {
+                        post_processor.added_tokens(pair_encoding.is_some())
+                    }
+                    _ => 0,
+                };
+                let max_length = params.max_length.saturating_sub(n_added_tokens);
+                if max_length > 0 && params.stride >= max_length {
+                    return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                        format!(
+                            "`stride` must be less than {max_length}. Given: {}",
+                            params.stride
+                        ),
+                    )));
+                }
+
+                match (&self.truncator, pair_encoding, self.dual_split) {
+                    (Some(_), None, _) => (self.truncate(encoding, max_length, params), None),
+                    (_, Some(mut pair_encoding), Some(dual_split))
+                        if matches!(params.strategy, TruncationStrategy::LongestFirst) =>
+                    {
+                        let (max_length_1, max_length_2) =
+                            dual_split.budgets(encoding.len(), pair_encoding.len(), max_length);
+                        // The stride is validated against the whole budget, keep it under the
+                        // budget of each segment
+                        let mut encoding = encoding;
+                        encoding.truncate(
+                            max_length_1,
+                            params.stride.min(max_length_1.saturating_sub(1)),
+                            params.direction,
+                        );
+                        pair_encoding.truncate(
+                            max_length_2,
+                            params.stride.min(max_length_2.saturating_sub(1)),
+                            params.direction,
+                        );
+                        (encoding, Some(pair_encoding))
+                    }
+                    (_, pair_encoding, _) => truncate_encodings(
+                        encoding,
+                        pair_encoding,
+                        &TruncationParams {
+                            max_length,
+                            ..*params
+                        },
+                    )?,
+                }
+            }
+        };
+
+        self.post_process(encoding, pair_encoding, add_special_tokens)
+    }
+
+    /// Encode `text` with `prompt` applied, truncating the text tokens only so that the prompt
+    /// is always kept whole
+    fn encode_with_prompt(
+        &self,
+        prompt: &str,
+        text: &str,
+        add_special_tokens: bool,
+        params: &TruncationParams,
+    ) -> Result<RawEncoding, TextEmbeddingsError> {
+        // Keep the tokenization of the whole string when it does not need to be truncated
+        let full_text = apply_prompt(Some(prompt.to_string()), text.to_string());
+        let encoding = self.encode::<&str>(&full_text, add_special_tokens, None)?;
+        if encoding.len() <= params.max_length {
+            return Ok(encoding);
+        }
+
+        let (prefix, suffix) = split_prompt(prompt);
+        let prefix_encoding = self.tokenizer.encode(prefix, false)?;
+        let text_encoding = self.tokenizer.encode(text, false)?;
+        let suffix_encoding = self.tokenizer.encode(suffix, false)?;
+        self.truncate_between_prompt(
+            prefix_encoding,
+            text_encoding,
+            suffix_encoding,
+            add_special_tokens,
+            params,
+        )
+    }
+
+    /// Truncate `text_encoding` only, to fit between the prompt encodings, then post-process them
+    fn truncate_between_prompt(
+        &self,
+        mut encoding: RawEncoding,
+        text_encoding: RawEncoding,
+        suffix_encoding: RawEncoding,
+        add_special_tokens: bool,
+        params: &TruncationParams,
+    ) -> Result<RawEncoding, TextEmbeddingsError> {
+        // Keep room for the prompt and the special tokens added by the post-processor
+        let n_added_tokens = match (add_special_tokens, self.post_processor().as_deref()) {
+            (true, Some(post_processor)) => post_processor.added_tokens(false),
+            _ => 0,
+        };
+        let max_length = params
+            .max_length
+            .saturating_sub(n_added_tokens + encoding.len() + suffix_encoding.len());
+        if max_length > 0 && params.stride >= max_length {
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                format!(
+                    "`stride` must be less than {max_length}. Given: {}",
+                    params.stride
+                ),
+            )));
+        }
+        let text_encoding = self.truncate(text_encoding, max_length, params);
+
+        encoding.merge_with(text_encoding, true);
+        encoding.merge_with(suffix_encoding, true);
+        self.post_process(encoding, None, add_special_tokens)
+    }
+
+    /// Truncate a single sequence to `max_length` tokens with the custom truncator if any
+    fn truncate(
+        &self,
+        mut encoding: RawEncoding,
+        max_length: usize,
+        params: &TruncationParams,
+    ) -> RawEncoding {
+        let Some(truncator) = &self.truncator else {
+            encoding.truncate(max_length, params.stride, params.direction);
+            return encoding;
+        };
+        if encoding.len() <= max_length {
+            return encoding;
+        }
+
+        let original_ids = encoding.get_ids().to_vec();
+        let type_id = encoding.get_type_ids().first().copied().unwrap_or(0);
+        let kept = truncator.truncate(encoding, max_length);
+
+        // Like `RawEncoding::truncate`, the tokens that were cut become the overflowing window.
+        // The input has no special tokens before post-processing: the special ones were inserted.
+        let mut kept_ids = kept
+            .get_ids()
+            .iter()
+            .zip(kept.get_special_tokens_mask())
+            .filter(|(_, &special)| special == 0)
+            .map(|(id, _)| id)
+            .peekable();
+        let cut_ids: Vec<u32> = original_ids
+            .into_iter()
+            .filter(|id| kept_ids.next_if_eq(&id).is_none())
+            .collect();
+        RawEncoding::new(
+            kept.get_ids().to_vec(),
+            kept.get_type_ids().to_vec(),
+            kept.get_tokens().to_vec(),
+            kept.get_word_ids().to_vec(),
+            kept.get_offsets().to_vec(),
+            kept.get_special_tokens_mask().to_vec(),
+            kept.get_attention_mask().to_vec(),
+            vec![self.encoding_from_ids(&cut_ids, type_id)],
+            HashMap::new(),
+        )
+    }
+
+    /// Add the special tokens and merge the pair, mirroring `Tokenizer::post_process`
+    fn post_process(
+        &self,
+        encoding: RawEncoding,
+        pair_encoding: Option<RawEncoding>,
+        add_special_tokens: bool,
+    ) -> Result<RawEncoding, TextEmbeddingsError> {
+        let encoding = match self.post_processor().as_deref() {
+            Some(post_processor) => {
+                post_processor.process(encoding, pair_encoding, add_special_tokens)?
+            }
+            None => match pair_encoding {
+                None => encoding,
+                Some(mut pair_encoding) => {
+                    let mut encoding = encoding;
+                    encoding.set_sequence_id(0);
+                    pair_encoding.set_sequence_id(1);
+                    encoding.merge_with(pair_encoding, false);
+                    encoding
+                }
+            },
+        };
+        Ok(encoding)
+    }
+
+    fn decode(
+        &self,
+        ids: &[u32],
+        skip_special_tokens: bool,
+    ) -> Result<String, TextEmbeddingsError> {
+        Ok(self.tokenizer.decode(ids, skip_special_tokens)?)
+    }
+
+    /// Decoder of the tokenizer. Without one, `tokenizers` joins the raw tokens with spaces,
+    /// which is good enough to re-encode the inputs but not to return text to the user.
+    fn decoder(&self) -> Result<&DecoderWrapper, TextEmbeddingsError> {
+        self.tokenizer.get_decoder().ok_or_else(|| {
+            TextEmbeddingsError::Validation(ValidationError::Other(
+                "This model does not support decoding: `tokenizer.json` has no decoder".to_string(),
+            ))
+        })
+    }
+}
+
+/// Tokenize the input and split it into overlapping windows
+#[allow(clippy::too_many_arguments)]
+fn encode_chunks(
+    inputs: EncodingInput,
+    chunk_length: usize,
+    stride: usize,
+    position_mode: PositionMode,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    position_offset: usize,
+    default_prompt: Option<String>,
+    prompt_name: Option<String>,
+    prompt_text: Option<String>,
+    with_offsets: bool,
+    prompts: Option<&HashMap<String, String>>,
+    tokenizer: &SharedTokenizer,
+) -> Result<Vec<ChunkEncoding>, TextEmbeddingsError> {
+    if chunk_length == 0 || chunk_length > max_input_length {
+        let message = format!(
+            "`max_input_length` must be between 1 and {max_input_length}. Given: {chunk_length}"
+        );
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            message,
+        )));
+    }
+    if stride >= chunk_length {
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            format!("`stride` must be less than {chunk_length}. Given: {stride}"),
+        )));
+    }
+
+    // Overflowing tokens are kept by `tokenizers` as additional encodings that overlap by `stride`
+    let truncate_params = Some(TruncationParams {
+        direction: TruncationDirection::Right,
+        max_length: chunk_length,
+        strategy: TruncationStrategy::LongestFirst,
+        stride,
+    });
+
+    // Resolved before looking at the segments, see `tokenize_input`
+    let inputs = inputs.resolve_half_empty(tokenizer.half_empty_dual)?;
+    let pre_prompt = prepare_pre_prompt(
+        default_prompt,
+        prompt_name,
+        prompt_text,
+        prompts,
+        true,
+        tokenizer.metrics,
+    )?;
+    // The prompt of a single sequence is repeated whole in every window, its tokens are not part
+    // of the input
+    let n_prompt_tokens = match (&inputs, pre_prompt.as_deref()) {
+        (EncodingInput::Single(_) | EncodingInput::Ids(_), Some(prompt)) => {
+            prompt_token_count(prompt, tokenizer)?
+        }
+        _ => 0,
+    };
+
+    let (_, mut encoding, limited) = tokenize_resolved(
+        inputs,
+        pre_prompt,
+        true,
+        max_input_length,
+        max_char_multiplier,
+        truncate_params,
+        false,
+        tokenizer,
+    )?;
+    let overflowing = encoding.take_overflowing();
+    let n_chunks = overflowing.len() + 1;
+
+    let histogram = tokenizer
+        .metrics
+        .then(|| metrics::histogram!("te_request_input_length"));
+    // Each window starts `stride` tokens before the end of the previous one
+    let mut start_token = 0;
+    let chunks = std::iter::once(encoding)
+        .chain(overflowing)
+        .enumerate()
+        .map(|(chunk_index, chunk)| {
+            let seq_len = chunk.len();
+            if let Some(histogram) = &histogram {
+                histogram.record(seq_len as f64);
+            }
+            let n_input_tokens = chunk
+                .get_special_tokens_mask()
+                .iter()
+                .filter(|&&special| special == 0)
+                .count()
+                .saturating_sub(n_prompt_tokens);
+            let first_position = match position_mode {
+                PositionMode::Restart => position_offset,
+                PositionMode::Continuous => position_offset + start_token,
+            };
+            let valid = ValidEncoding {
+                input_ids: chunk.get_ids().to_vec(),
+                token_type_ids: chunk.get_type_ids().to_vec(),
+                position_ids: (first_position as u32..(seq_len + first_position) as u32)
+                    .collect::<Vec<_>>(),
+                attention_mask: chunk.get_attention_mask().to_vec(),
+                truncated: limited,
+                offsets: with_offsets.then(|| chunk.get_offsets().to_vec()),
+                special_tokens_mask: None,
+                applied_prompt: None,
+                chunks: Vec::new(),
+            };
+            let chunk_encoding = ChunkEncoding {
+                valid,
+                chunk_index,
+                start_token,
+                is_last: chunk_index + 1 == n_chunks,
+            };
+            start_token += n_input_tokens.saturating_sub(stride);
+            chunk_encoding
+        })
+        .collect();
+    Ok(chunks)
+}
+
+/// Pack the sentences of `input` into chunks of at most `chunk_length` tokens
+#[allow(clippy::too_many_arguments)]
+fn encode_sentences(
+    input: &str,
+    chunk_length: usize,
+    max_input_length: usize,
+    max_char_multiplier: usize,
+    position_offset: usize,
+    default_prompt: Option<String>,
+    prompt_name: Option<String>,
+    prompts: Option<&HashMap<String, String>>,
+    tokenizer: &SharedTokenizer,
+) -> Result<Vec<ValidEncoding>, TextEmbeddingsError> {
+    if chunk_length == 0 || chunk_length > max_input_length {
+        let message = format!(
+            "`max_input_length` must be between 1 and {max_input_length}. Given: {chunk_length}"
+        );
+        return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+            message,
+        )));
+    }
+
+    let count = |text: &str| {
+        tokenize_input(
+            EncodingInput::Single(text.to_string()),
+            &EncodeOptions {
+                prompt_name: prompt_name.clone(),
+                ..Default::default()
+            },
+            max_input_length,
+            max_char_multiplier,
+            None,
+            default_prompt.clone(),
+            prompts,
+            false,
+            tokenizer,
+        )
+        .map(|(_, encoding, _)| encoding.len())
+    };
+
+    // Tokens of the prompt and of the special tokens, and of a sentence on its own
+    let n_added_tokens = count("")?;
+    let count_sentence = |sentence: Range<usize>| {
+        tokenizer
+            .encode::<&str>(input[sentence].trim(), false, None)
+            .map(|encoding| encoding.len())
+    };
+    // Longer chunks would be rejected by `count`, the byte length bounds the number of chars
+    let max_chars = max_input_length * max_char_multiplier;
+
+    // Tokens do not always add up across sentences: chunks grow on an estimate of their number of
+    // tokens, and are only counted as a whole when the estimate gets close to `chunk_length`.
+    // `margin` grows by one token for each sentence added since the last count.
+    let mut chunks = Vec::new();
+    let mut current: Option<(Range<usize>, usize, usize)> = None;
+    for sentence in sentence_ranges(input) {
+        let sentence_tokens = count_sentence(sentence.clone())?;
+        current = match current {
+            None => Some((sentence, n_added_tokens + sentence_tokens, 0)),
+            Some((chunk, n_tokens, margin)) => {
+                let candidate = chunk.start..sentence.end;
+                let estimate = n_tokens + sentence_tokens;
+                let fits = if input[candidate.clone()].trim().len() > max_chars {
+                    None
+                } else if estimate + margin < chunk_length {
+                    Some((estimate, margin + 1))
+                } else {
+                    let n_tokens = count(input[candidate.clone()].trim())?;
+                    (n_tokens <= chunk_length).then_some((n_tokens, 0))
+                };
+                match fits {
+                    Some((n_tokens, margin)) => Some((candidate, n_tokens, margin)),
+                    None => {
+                        chunks.push(chunk);
+                        Some((sentence, n_added_tokens + sentence_tokens, 0))
+                    }
+                }
+            }
+        };
+    }
+    chunks.extend(current.map(|(chunk, _, _)| chunk));
+
+    let mut encodings = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let text = input[chunk].trim();
+        if text.is_empty() {
+            continue;
+        }
+        // A single window unless the chunk is a sentence longer than `chunk_length`
+        let windows = encode_chunks(
+            EncodingInput::Single(text.to_string()),
+            chunk_length,
+            0,
+            PositionMode::Restart,
+            max_input_length,
+            max_char_multiplier,
+            position_offset,
+            default_prompt.clone(),
+            prompt_name.clone(),
+            None,
+            false,
+            prompts,
+            tokenizer,
+        )?;
+        encodings.extend(windows.into_iter().map(|window| window.valid));
+    }
+    Ok(encodings)
+}
+
+/// Byte ranges of the sentences of `text`, covering all of it. A sentence ends after `.`, `!` or
+/// `?` followed by whitespace, or after their full width forms, and keeps the trailing whitespace.
+fn sentence_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let is_boundary = match c {
+            '。' | '！' | '？' => true,
+            '.' | '!' | '?' => chars.peek().is_none_or(|(_, next)| next.is_whitespace()),
+            _ => false,
+        };
+        if is_boundary {
+            while chars.next_if(|(_, next)| next.is_whitespace()).is_some() {}
+            let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+            sentences.push(start..end);
+            start = end;
+        }
+    }
+    if start < text.len() {
+        sentences.push(start..text.len());
+    }
+    sentences
+}
+
+/// Bounded cache of encodings that evicts the least recently used entry when full
+#[derive(Debug)]
+struct EncodingCache {
+    capacity: usize,
+    /// Incremented on every access to order the entries by recency
+    tick: u64,
+    /// Incremented every time the cache is cleared
+    generation: u64,
+    entries: HashMap<Arc<EncodingCacheKey>, (u64, ValidEncoding)>,
+    /// Keys of `entries` by their last access
+    recency: BTreeMap<u64, Arc<EncodingCacheKey>>,
+}
+
+impl EncodingCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            generation: 0,
+            entries: HashMap::with_capacity(capacity),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &EncodingCacheKey) -> Option<ValidEncoding> {
+        self.tick += 1;
+        let (last_used, encoding) = self.entries.get_mut(key)?;
+        let key = self
+            .recency
+            .remove(&*last_used)
+            .expect("Cached encodings always have a recency. This is a bug.");
+        *last_used = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(encoding.clone())
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn insert(&mut self, key: EncodingCacheKey, encoding: ValidEncoding) {
+        match self.entries.get(&key) {
+            Some((last_used, _)) => {
+                self.recency.remove(last_used);
+            }
+            None if self.entries.len() >= self.capacity => {
+                if let Some((_, lru_key)) = self.recency.pop_first() {
+                    self.entries.remove(&lru_key);
+                }
+            }
+            None => {}
+        }
+        self.tick += 1;
+        let key = Arc::new(key);
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, encoding));
+    }
+}
+
+/// Everything that changes the result of `encode`. The whole key is compared on lookup, so that
+/// distinct requests never share an entry.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct EncodingCacheKey {
+    inputs: EncodingInput,
+    add_special_tokens: bool,
+    overflow_policy: OverflowPolicy,
+    truncation_direction: u8,
+    truncation_strategy: u8,
+    prompt_name: Option<String>,
+    prompt_text: Option<String>,
+    with_offsets: bool,
+    with_special_mask: bool,
+    position_offset: Option<usize>,
+    pad_to_multiple_of: Option<usize>,
+    wrap_tokens: Option<(Vec<u32>, Vec<u32>)>,
+    template_override: Option<String>,
+    leading_cls: LeadingCls,
+}
+
+/// Key of the cached result of `encode`. `request_id` and `timeout` do not change the result.
+fn encoding_cache_key(inputs: &EncodingInput, options: &EncodeOptions) -> EncodingCacheKey {
+    EncodingCacheKey {
+        inputs: inputs.clone(),
+        add_special_tokens: options.add_special_tokens,
+        overflow_policy: options.overflow_policy,
+        truncation_direction: match options.truncation_direction {
+            TruncationDirection::Left => 0,
+            TruncationDirection::Right => 1,
+        },
+        truncation_strategy: match options.truncation_strategy {
+            TruncationStrategy::LongestFirst => 0,
+            TruncationStrategy::OnlyFirst => 1,
+            TruncationStrategy::OnlySecond => 2,
+        },
+        prompt_name: options.prompt_name.clone(),
+        prompt_text: options.prompt_text.clone(),
+        with_offsets: options.with_offsets,
+        with_special_mask: options.with_special_mask,
+        position_offset: options.position_offset,
+        pad_to_multiple_of: options.pad_to_multiple_of,
+        wrap_tokens: options.wrap_tokens.clone(),
+        template_override: options.template_override.clone(),
+        leading_cls: options.leading_cls,
+    }
+}
+
+/// Unique `inputs` in order of first occurrence, and the index among them of every input
+fn dedup_inputs(inputs: Vec<EncodingInput>) -> (Vec<EncodingInput>, Vec<usize>) {
+    let positions: Vec<usize> = {
+        let mut seen: HashMap<&EncodingInput, usize> = HashMap::with_capacity(inputs.len());
+        inputs
+            .iter()
+            .map(|input| {
+                let next = seen.len();
+                *seen.entry(input).or_insert(next)
+            })
+            .collect()
+    };
+    // The first occurrence of an input is the one that gets the next index
+    let mut n_unique = 0;
+    let unique = inputs
+        .into_iter()
+        .zip(&positions)
+        .filter_map(|(input, &position)| {
+            if position < n_unique {
+                return None;
+            }
+            n_unique += 1;
+            Some(input)
+        })
+        .collect();
+    (unique, positions)
+}
+
+/// Identifier of a request, recorded on the tokenization spans
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(pub u64);
+
+impl RequestId {
+    /// New id, unique in the process, for the servers to tag the requests they receive
+    pub fn next() -> Self {
+        static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn enter_span(self) -> EnteredSpan {
+        tracing::info_span!("tokenization", request_id = self.0).entered()
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Output of `Tokenization::tokenize`
+#[derive(Debug)]
+pub struct Tokenized {
+    /// Text that was tokenized, prompt included. `None` for dual and pre-tokenized inputs
+    pub full_text: Option<String>,
+    /// Prompt applied to the input
+    pub prompt_text: Option<String>,
+    /// Number of tokens of `encoding` coming from the prompt
+    pub prompt_token_count: usize,
+    /// The offsets refer to `full_text`, with the original input when the normalization changed
+    /// it. `true` if some of them could not be mapped back and still refer to the normalized text.
+    pub offsets_approximate: bool,
+    pub encoding: RawEncoding,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ValidEncoding {
+    pub input_ids: Vec<u32>,
+    pub token_type_ids: Vec<u32>,
+    pub position_ids: Vec<u32>,
+    /// `1` for the tokens to attend to, `0` for padding
+    pub attention_mask: Vec<u32>,
+    /// Whether the input was shortened to fit `max_input_length`
+    pub truncated: bool,
+    /// Byte offsets of each token in the input, when requested
+    pub offsets: Option<Vec<(usize, usize)>>,
+    /// `1` for the special and padding tokens, `0` for the tokens of the input, when requested
+    pub special_tokens_mask: Option<Vec<u32>>,
+    /// Prompt prepended to the input by `encode`, including the default prompt when no prompt was
+    /// selected. Not set on the windows in `chunks`.
+    pub applied_prompt: Option<String>,
+    /// Windows following this one when the input was split with `OverflowPolicy::Chunk`
+    pub chunks: Vec<ValidEncoding>,
+}
+
+impl ValidEncoding {
+    /// Compact binary form for a co-located backend. Every field is written in order, little
+    /// endian: the lists as a `u32` length followed by the values (`u32`, or two `u64` for the
+    /// offsets), the optional fields behind a `u8` flag, and the chunks recursively after their
+    /// `u32` count.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 * (self.input_ids.len() + 4));
+        self.write_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Read an encoding written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TextEmbeddingsError> {
+        let mut reader = ByteReader(bytes);
+        let encoding = Self::read_bytes(&mut reader, false)?;
+        if !reader.0.is_empty() {
+            return Err(invalid_bytes("trailing bytes"));
+        }
+        Ok(encoding)
+    }
+
+    fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        let write_u32s = |bytes: &mut Vec<u8>, values: &[u32]| {
+            bytes.extend((values.len() as u32).to_le_bytes());
+            bytes.extend(values.iter().flat_map(|value| value.to_le_bytes()));
+        };
+        write_u32s(bytes, &self.input_ids);
+        write_u32s(bytes, &self.token_type_ids);
+        write_u32s(bytes, &self.position_ids);
+        write_u32s(bytes, &self.attention_mask);
+        bytes.push(self.truncated as u8);
+        bytes.push(self.offsets.is_some() as u8);
+        if let Some(offsets) = &self.offsets {
+            bytes.extend((offsets.len() as u32).to_le_bytes());
+            for &(start, end) in offsets {
+                bytes.extend((start as u64).to_le_bytes());
+                bytes.extend((end as u64).to_le_bytes());
+            }
+        }
+        bytes.push(self.special_tokens_mask.is_some() as u8);
+        if let Some(special_tokens_mask) = &self.special_tokens_mask {
+            write_u32s(bytes, special_tokens_mask);
+        }
+        bytes.push(self.applied_prompt.is_some() as u8);
+        if let Some(applied_prompt) = &self.applied_prompt {
+            bytes.extend((applied_prompt.len() as u32).to_le_bytes());
+            bytes.extend(applied_prompt.as_bytes());
+        }
+        bytes.extend((self.chunks.len() as u32).to_le_bytes());
+        for chunk in &self.chunks {
+            chunk.write_bytes(bytes);
+        }
+    }
+
+    /// Chunks are one level deep: `is_chunk` rejects chunks of their own
+    fn read_bytes(
+        reader: &mut ByteReader<'_>,
+        is_chunk: bool,
+    ) -> Result<Self, TextEmbeddingsError> {
+        let input_ids = reader.u32s()?;
+        let token_type_ids = reader.u32s()?;
+        let position_ids = reader.u32s()?;
+        let attention_mask = reader.u32s()?;
+        let truncated = reader.flag()?;
+        let offsets = match reader.flag()? {
+            false => None,
+            true => Some(
+                (0..reader.u32()?)
+                    .map(|_| Ok((reader.u64()? as usize, reader.u64()? as usize)))
+                    .collect::<Result<_, TextEmbeddingsError>>()?,
+            ),
+        };
+        let special_tokens_mask = match reader.flag()? {
+            false => None,
+            true => Some(reader.u32s()?),
+        };
+        let applied_prompt = match reader.flag()? {
+            false => None,
+            true => {
+                let len = reader.u32()? as usize;
+                let prompt = std::str::from_utf8(reader.take(len)?)
+                    .map_err(|_| invalid_bytes("prompt is not valid UTF-8"))?;
+                Some(prompt.to_string())
+            }
+        };
+        let n_chunks = reader.u32()?;
+        if is_chunk && n_chunks > 0 {
+            return Err(invalid_bytes("chunks cannot have chunks"));
+        }
+        let chunks = (0..n_chunks)
+            .map(|_| Self::read_bytes(reader, true))
+            .collect::<Result<_, _>>()?;
+
+        let len = input_ids.len();
+        let same_len = token_type_ids.len() == len
+            && position_ids.len() == len
+            && attention_mask.len() == len
+            && offsets
+                .as_ref()
+                .is_none_or(|offsets: &Vec<_>| offsets.len() == len)
+            && special_tokens_mask
+                .as_ref()
+                .is_none_or(|special_tokens_mask| special_tokens_mask.len() == len);
+        if !same_len {
+            return Err(invalid_bytes("the lists do not have the same length"));
+        }
+        Ok(Self {
+            input_ids,
+            token_type_ids,
+            position_ids,
+            attention_mask,
+            truncated,
+            offsets,
+            special_tokens_mask,
+            applied_prompt,
+            chunks,
+        })
+    }
+}
+
+/// Cursor over the bytes of `ValidEncoding::from_bytes`
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], TextEmbeddingsError> {
+        if self.0.len() < len {
+            return Err(invalid_bytes("unexpected end of input"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn flag(&mut self) -> Result<bool, TextEmbeddingsError> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            flag => Err(invalid_bytes(&format!("invalid flag {flag}"))),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, TextEmbeddingsError> {
+        let bytes = self.take(4)?.try_into().expect("4 bytes were taken");
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, TextEmbeddingsError> {
+        let bytes = self.take(8)?.try_into().expect("8 bytes were taken");
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn u32s(&mut self) -> Result<Vec<u32>, TextEmbeddingsError> {
+        let len = self.u32()? as usize;
+        // The length is checked before allocating
+        let bytes = self.take(len.saturating_mul(4))?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|value| u32::from_le_bytes(value.try_into().expect("chunks of 4 bytes")))
+            .collect())
+    }
+}
+
+fn invalid_bytes(reason: &str) -> TextEmbeddingsError {
+    TextEmbeddingsError::Validation(ValidationError::Other(format!(
+        "Invalid `ValidEncoding` bytes: {reason}"
+    )))
+}
+
+/// Window of an input split by `Tokenization::encode_chunks`
+#[derive(Debug, Clone)]
+pub struct ChunkEncoding {
+    pub valid: ValidEncoding,
+    /// Position of the window among the windows of the input
+    pub chunk_index: usize,
+    /// Index of the first token of the window among the tokens of the whole input, special
+    /// tokens excluded
+    pub start_token: usize,
+    /// Whether this is the last window of the input
+    pub is_last: bool,
+}
+
+/// Whether `encode` guarantees that `input_ids` start with the CLS token, which the backends read
+/// for CLS pooling. The CLS token is the one of `Tokenization::special_token_ids`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LeadingCls {
+    /// Return the encoding as is
+    #[default]
+    Unchecked,
+    /// Reject the encodings not starting with the CLS token
+    Require,
+    /// Prepend the CLS token to the encodings not starting with it, within `max_input_length`
+    Insert,
+}
+
+/// What to do with inputs longer than `max_input_length`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Reject the input
+    #[default]
+    Error,
+    /// Drop the tokens that do not fit
+    Truncate,
+    /// Split the input into windows sharing `stride` tokens with the previous one, returned in
+    /// `ValidEncoding::chunks`
+    Chunk { stride: usize },
+}
+
+/// Per-request options of `Tokenization::encode`
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub add_special_tokens: bool,
+    pub overflow_policy: OverflowPolicy,
+    pub truncation_direction: TruncationDirection,
+    pub truncation_strategy: TruncationStrategy,
+    pub prompt_name: Option<String>,
+    pub prompt_text: Option<String>,
+    pub with_offsets: bool,
+    /// Fill `special_tokens_mask`
+    pub with_special_mask: bool,
+    /// Replaces the offset of the `position_ids` given to the builder when set
+    pub position_offset: Option<usize>,
+    /// Pad the encoding with the tokenizer pad token up to the next multiple, without going over
+    /// `max_input_length`
+    pub pad_to_multiple_of: Option<usize>,
+    /// Token ids added before and after the encoding, after tokenization
+    pub wrap_tokens: Option<(Vec<u32>, Vec<u32>)>,
+    /// Replaces the post-processor of the tokenizer with this template for this request only,
+    /// e.g. `[CLS] $A [SEP]`. Its special tokens must be in the vocabulary. Pair inputs get `$B`
+    /// after it, followed by the special tokens that follow `$A`.
+    pub template_override: Option<String>,
+    /// Check that `input_ids` start with the CLS token, or insert it
+    pub leading_cls: LeadingCls,
+    /// Recorded on the worker span to correlate the tokenization with the request
+    pub request_id: Option<RequestId>,
+    /// Bounds the wait for a worker. On expiry the worker still finishes the request but its
+    /// result is discarded. Not used by `encode_blocking`.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            add_special_tokens: true,
+            overflow_policy: OverflowPolicy::default(),
+            truncation_direction: TruncationDirection::Right,
+            truncation_strategy: TruncationStrategy::LongestFirst,
+            prompt_name: None,
+            prompt_text: None,
+            with_offsets: false,
+            with_special_mask: false,
+            position_offset: None,
+            pad_to_multiple_of: None,
+            wrap_tokens: None,
+            template_override: None,
+            leading_cls: LeadingCls::default(),
+            request_id: None,
+            timeout: None,
+        }
+    }
+}
+
+/// How the `position_ids` of the windows returned by `Tokenization::encode_chunks` are numbered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PositionMode {
+    /// Every window starts again from `position_offset`, as for a single sequence. Models with
+    /// learned absolute position embeddings (BERT, RoBERTa, XLM-RoBERTa, ...) need this since
+    /// they have no embedding past `max_position_embeddings`.
+    #[default]
+    Restart,
+    /// Every window starts from `position_offset` plus its `start_token`, so that overlapping
+    /// tokens keep the same position in every window. Only meaningful for models with relative
+    /// positions that extrapolate past their training length (rotary or ALiBi models such as
+    /// Nomic BERT, GTE or Jina BERT).
+    Continuous,
+}
+
+/// Text of the tokens whose offsets split a multi-byte character, which happens with byte-level
+/// BPE merges on CJK or emoji
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidUtf8Policy {
+    /// Widen the offsets to the enclosing char boundaries, the text holds whole characters
+    #[default]
+    Widen,
+    /// Keep the offsets, the partial characters are replaced by U+FFFD
+    Lossy,
+    /// Keep the offsets, the bytes of the partial characters are escaped as `\xNN`
+    Escape,
+    /// Reject the input
+    Error,
+}
+
+/// How the length budget of a `Dual` input is shared between its two segments when they do not
+/// both fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DualSplit {
+    /// Each segment gets half of the budget, the share left unused by the shorter segment goes
+    /// to the longer one
+    #[default]
+    Even,
+    /// Each segment gets a share of the budget proportional to its length
+    Proportional,
+    /// The first segment gets at most `first_max`, the second one the rest
+    Fixed { first_max: usize },
+}
+
+/// What to do with a `Dual` input with exactly one empty segment, which some models handle badly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HalfEmptyDual {
+    /// Encode the non-empty segment as a `Single` input
+    #[default]
+    Collapse,
+    /// Reject the input with `ValidationError::EmptySegment`
+    Reject,
+}
+
+impl DualSplit {
+    /// Budgets of segments of `len_1` and `len_2` sharing `limit`. A non-empty segment keeps at
+    /// least one unit whenever `limit` leaves room for both segments
+    fn budgets(self, len_1: usize, len_2: usize, limit: usize) -> (usize, usize) {
+        if len_1 + len_2 <= limit {
+            return (len_1, len_2);
+        }
+        let (budget_1, budget_2) = match self {
+            DualSplit::Even => {
+                if len_1 <= limit / 2 {
+                    (len_1, limit - len_1)
+                } else if len_2 <= limit / 2 {
+                    (limit - len_2, len_2)
+                } else {
+                    (limit / 2, limit - limit / 2)
+                }
+            }
+            DualSplit::Proportional => {
+                let budget_1 = limit * len_1 / (len_1 + len_2);
+                (budget_1, limit - budget_1)
+            }
+            DualSplit::Fixed { first_max } => {
+                let budget_1 = len_1.min(first_max).min(limit);
+                (budget_1, limit - budget_1)
+            }
+        };
+        if budget_1 == 0 && len_1 > 0 && budget_2 > 1 {
+            (1, budget_2 - 1)
+        } else if budget_2 == 0 && len_2 > 0 && budget_1 > 1 {
+            (budget_1 - 1, 1)
+        } else {
+            (budget_1, budget_2)
+        }
+    }
+}
+
+/// Unicode normalization form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum UnicodeNormalization {
+    /// Canonical decomposition followed by canonical composition
+    Nfc,
+    /// Canonical decomposition
+    Nfd,
+    /// Compatibility decomposition followed by canonical composition
+    Nfkc,
+    /// Compatibility decomposition
+    Nfkd,
+}
+
+impl UnicodeNormalization {
+    fn apply(self, text: &str) -> String {
+        self.normalize(text).get().to_string()
+    }
+
+    /// Normalize `text`, keeping the alignments with the original
+    fn normalize(self, text: &str) -> NormalizedString {
+        let mut normalized = NormalizedString::from(text);
+        match self {
+            UnicodeNormalization::Nfc => normalized.nfc(),
+            UnicodeNormalization::Nfd => normalized.nfd(),
+            UnicodeNormalization::Nfkc => normalized.nfkc(),
+            UnicodeNormalization::Nfkd => normalized.nfkd(),
+        };
+        normalized
+    }
+}
+
+impl From<bool> for OverflowPolicy {
+    fn from(truncate: bool) -> Self {
+        match truncate {
+            true => OverflowPolicy::Truncate,
+            false => OverflowPolicy::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum EncodingInput {
+    Single(String),
+    Dual(String, String),
+    Ids(Vec<u32>),
+    /// Token ids passed straight through without decoding nor re-encoding.
+    /// No prompt nor special tokens are added.
+    PreTokenized {
+        ids: Vec<u32>,
+        type_ids: Vec<u32>,
+    },
+}
+
+impl EncodingInput {
+    fn is_empty(&self) -> bool {
+        match self {
+            EncodingInput::Single(s) => s.is_empty(),
+            EncodingInput::Dual(s1, s2) => s1.is_empty() && s2.is_empty(),
+            EncodingInput::Ids(v) => v.is_empty(),
+            EncodingInput::PreTokenized { ids, .. } => ids.is_empty(),
+        }
+    }
+
+    /// Safe preview of the input for logging: the first `max` chars of each segment, or the
+    /// number of ids
+    pub fn summary(&self, max: usize) -> String {
+        let preview = |s: &str| {
+            let mut preview: String = s.chars().take(max).collect();
+            if preview.len() < s.len() {
+                preview.push_str("...");
+            }
+            preview
+        };
+
+        match self {
+            EncodingInput::Single(s) => format!("{:?}", preview(s)),
+            EncodingInput::Dual(s1, s2) => format!("({:?}, {:?})", preview(s1), preview(s2)),
+            EncodingInput::Ids(ids) => format!("{} ids", ids.len()),
+            EncodingInput::PreTokenized { ids, .. } => format!("{} pre-tokenized ids", ids.len()),
+        }
+    }
+
+    /// Apply `half_empty_dual` to a `Dual` input with exactly one empty segment
+    fn resolve_half_empty(
+        self,
+        half_empty_dual: HalfEmptyDual,
+    ) -> Result<Self, TextEmbeddingsError> {
+        let EncodingInput::Dual(s1, s2) = self else {
+            return Ok(self);
+        };
+        let empty_segment = match (s1.is_empty(), s2.is_empty()) {
+            (true, false) => 0,
+            (false, true) => 1,
+            _ => return Ok(EncodingInput::Dual(s1, s2)),
+        };
+        match half_empty_dual {
+            HalfEmptyDual::Collapse if empty_segment == 0 => Ok(EncodingInput::Single(s2)),
+            HalfEmptyDual::Collapse => Ok(EncodingInput::Single(s1)),
+            HalfEmptyDual::Reject => Err(TextEmbeddingsError::Validation(
+                ValidationError::EmptySegment {
+                    segment: empty_segment,
+                },
+            )),
+        }
+    }
+
+    /// Name of the variant, used as a metric label
+    fn kind(&self) -> &'static str {
+        match self {
+            EncodingInput::Single(_) => "single",
+            EncodingInput::Dual(_, _) => "dual",
+            EncodingInput::Ids(_) => "ids",
+            EncodingInput::PreTokenized { .. } => "pre_tokenized",
+        }
+    }
+
+    fn count_chars(&self) -> usize {
+        match self {
+            EncodingInput::Single(s) => s.chars().count(),
+            EncodingInput::Dual(s1, s2) => s1.chars().count() + s2.chars().count(),
+            EncodingInput::Ids(v) => v.len(),
+            EncodingInput::PreTokenized { ids, .. } => ids.len(),
+        }
+    }
+
+    /// Apply `normalization` to the text inputs, token ids are left untouched
+    fn normalize(&mut self, normalization: UnicodeNormalization) {
+        match self {
+            EncodingInput::Single(s) => *s = normalization.apply(s),
+            EncodingInput::Dual(s1, s2) => {
+                *s1 = normalization.apply(s1);
+                *s2 = normalization.apply(s2);
+            }
+            EncodingInput::Ids(_) | EncodingInput::PreTokenized { .. } => {}
+        }
+    }
+
+    /// Returns `true` if at least one of the inputs was shortened
+    fn apply_limit(&mut self, limit: usize, dual_split: DualSplit) -> bool {
+        // The limits are in chars, not in bytes
+        let truncate_string = |s: &mut String, limit: usize| match s.char_indices().nth(limit) {
+            Some((end, _)) => {
+                s.truncate(end);
+                true
+            }
+            None => false,
+        };
+
+        match self {
+            EncodingInput::Single(s) => truncate_string(s, limit),
+            EncodingInput::Dual(s1, s2) => {
+                let (limit_s1, limit_s2) =
+                    dual_split.budgets(s1.chars().count(), s2.chars().count(), limit);
+
+                let truncated_s1 = truncate_string(s1, limit_s1);
+                let truncated_s2 = truncate_string(s2, limit_s2);
+                truncated_s1 || truncated_s2
+            }
+            EncodingInput::Ids(_) | EncodingInput::PreTokenized { .. } => false,
+        }
+    }
+}
+
+impl From<String> for EncodingInput {
+    fn from(value: String) -> Self {
+        Self::Single(value)
+    }
+}
+
+impl From<&str> for EncodingInput {
+    fn from(value: &str) -> Self {
+        Self::Single(value.to_string())
+    }
+}
+
+impl From<(String, String)> for EncodingInput {
+    fn from(value: (String, String)) -> Self {
+        Self::Dual(value.0, value.1)
+    }
+}
+
+impl From<(&str, &str)> for EncodingInput {
+    fn from(value: (&str, &str)) -> Self {
+        Self::Dual(value.0.to_string(), value.1.to_string())
+    }
+}
+
+impl From<Vec<u32>> for EncodingInput {
+    fn from(value: Vec<u32>) -> Self {
+        Self::Ids(value)
+    }
+}
+
+enum TokenizerRequest {
+    Encode(
+        EncodingInput,
+        EncodeOptions,
+        oneshot::Sender<Result<ValidEncoding, TextEmbeddingsError>>,
+        Span,
+    ),
+    EncodeRaw(
+        EncodingInput,
+        bool,
+        OverflowPolicy,
+        TruncationDirection,
+        Option<String>,
+        Option<String>,
+        oneshot::Sender<Result<(ValidEncoding, RawEncoding), TextEmbeddingsError>>,
+        Span,
+    ),
+    EncodeBatch(
+        Vec<EncodingInput>,
+        bool,
+        TruncationDirection,
+        TruncationStrategy,
+        Option<String>,
+        Option<String>,
+        bool,
+        bool,
+        oneshot::Sender<Result<(Vec<ValidEncoding>, usize), TextEmbeddingsError>>,
+        Span,
+    ),
+    EncodeChunks(
+        EncodingInput,
+        usize,
+        usize,
+        PositionMode,
+        Option<String>,
+        bool,
+        oneshot::Sender<Result<Vec<ChunkEncoding>, TextEmbeddingsError>>,
+        Span,
+    ),
+    EncodeSentences(
+        String,
+        usize,
+        Option<String>,
+        oneshot::Sender<Result<Vec<ValidEncoding>, TextEmbeddingsError>>,
+        Span,
+    ),
+    Count(
+        EncodingInput,
+        Option<String>,
+        oneshot::Sender<Result<usize, TextEmbeddingsError>>,
+        Span,
+    ),
+    CountBatch(
+        Vec<EncodingInput>,
+        Option<String>,
+        oneshot::Sender<Result<Vec<usize>, TextEmbeddingsError>>,
+        Span,
+    ),
+    TokenCounts(
+        EncodingInput,
+        Option<String>,
+        oneshot::Sender<Result<HashMap<u32, u32>, TextEmbeddingsError>>,
+        Span,
+    ),
+    Validate(
+        EncodingInput,
+        bool,
+        TruncationDirection,
+        Option<String>,
+        oneshot::Sender<Result<usize, TextEmbeddingsError>>,
+        Span,
+    ),
+    Tokenize(
+        EncodingInput,
+        bool,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<RequestId>,
+        oneshot::Sender<Result<Tokenized, TextEmbeddingsError>>,
+        Span,
+    ),
+    TokenizeBatch(
+        Vec<EncodingInput>,
+        bool,
+        Option<String>,
+        Option<String>,
+        oneshot::Sender<Vec<Result<Tokenized, TextEmbeddingsError>>>,
+        Span,
+    ),
+    Decode(
+        Vec<u32>,
+        bool,
+        Option<bool>,
+        oneshot::Sender<Result<String, TextEmbeddingsError>>,
+        Span,
+    ),
+    DecodeBoth(
+        Vec<u32>,
+        oneshot::Sender<Result<(String, String), TextEmbeddingsError>>,
+        Span,
+    ),
+    DecodeTokens(
+        Vec<u32>,
+        bool,
+        oneshot::Sender<Result<Vec<String>, TextEmbeddingsError>>,
+        Span,
+    ),
+    DecodeStream(
+        Vec<u32>,
+        bool,
+        mpsc::UnboundedSender<Result<String, TextEmbeddingsError>>,
+        Span,
+    ),
+}
+
+impl TokenizerRequest {
+    /// Label of the request in the tokenization metrics. `None` for control requests.
+    fn kind(&self) -> Option<&'static str> {
+        match self {
+            TokenizerRequest::Encode(..)
+            | TokenizerRequest::EncodeRaw(..)
+            | TokenizerRequest::EncodeBatch(..)
+            | TokenizerRequest::EncodeChunks(..)
+            | TokenizerRequest::EncodeSentences(..)
+            | TokenizerRequest::Count(..)
+            | TokenizerRequest::CountBatch(..)
+            | TokenizerRequest::TokenCounts(..)
+            | TokenizerRequest::Validate(..) => Some("encode"),
+            TokenizerRequest::Tokenize(..) | TokenizerRequest::TokenizeBatch(..) => {
+                Some("tokenize")
+            }
+            TokenizerRequest::Decode(..)
+            | TokenizerRequest::DecodeBoth(..)
+            | TokenizerRequest::DecodeTokens(..)
+            | TokenizerRequest::DecodeStream(..) => Some("decode"),
+        }
+    }
+
+    /// Span of the caller. `None` for control requests.
+    fn span(&self) -> Option<&Span> {
+        match self {
+            TokenizerRequest::Encode(.., span)
+            | TokenizerRequest::EncodeRaw(.., span)
+            | TokenizerRequest::EncodeBatch(.., span)
+            | TokenizerRequest::EncodeChunks(.., span)
+            | TokenizerRequest::EncodeSentences(.., span)
+            | TokenizerRequest::Count(.., span)
+            | TokenizerRequest::CountBatch(.., span)
+            | TokenizerRequest::TokenCounts(.., span)
+            | TokenizerRequest::Validate(.., span)
+            | TokenizerRequest::Tokenize(.., span)
+            | TokenizerRequest::TokenizeBatch(.., span)
+            | TokenizerRequest::Decode(.., span)
+            | TokenizerRequest::DecodeBoth(.., span)
+            | TokenizerRequest::DecodeTokens(.., span)
+            | TokenizerRequest::DecodeStream(.., span) => Some(span),
+        }
+    }
+}
+
+/// `start` and `stop` are byte offsets in `input`, or char offsets if `char_offsets` is set.
+/// Char offsets require an extra pass over `input` and a map as large as the input in bytes.
+/// `invalid_utf8` sets the text of the tokens whose offsets split a multi-byte character.
+pub fn into_tokens(
+    encoding: tokenizers::Encoding,
+    input: &str,
+    char_offsets: bool,
+    invalid_utf8: InvalidUtf8Policy,
+) -> Result<Vec<SimpleToken>, TextEmbeddingsError> {
+    collect_tokens(encoding, input, char_offsets, invalid_utf8, true)
+}
+
+/// Same as `into_tokens` without the special tokens, which are skipped before being built
+pub fn into_content_tokens(
+    encoding: tokenizers::Encoding,
+    input: &str,
+    char_offsets: bool,
+    invalid_utf8: InvalidUtf8Policy,
+) -> Result<Vec<SimpleToken>, TextEmbeddingsError> {
+    collect_tokens(encoding, input, char_offsets, invalid_utf8, false)
+}
+
+fn collect_tokens(
+    encoding: tokenizers::Encoding,
+    input: &str,
+    char_offsets: bool,
+    invalid_utf8: InvalidUtf8Policy,
+    with_special: bool,
+) -> Result<Vec<SimpleToken>, TextEmbeddingsError> {
+    // Char index of every byte offset, only computed when requested
+    let char_indices = char_offsets.then(|| byte_to_char_indices(input));
+
+    encoding
+        .get_ids()
+        .iter()
+        .zip(encoding.get_offsets())
+        .zip(encoding.get_special_tokens_mask())
+        .zip(encoding.get_tokens())
+        .zip(encoding.get_word_ids())
+        .zip(encoding.get_type_ids())
+        .filter(|(((((_, _), special), _), _), _)| with_special || **special == 0)
+        .map(
+            |(((((&id, &(start, stop)), special), token), &word_id), &type_id)| {
+                let special = *special == 1;
+                let token = match special {
+                    true => SimpleToken {
+                        id,
+                        text: token.clone(),
+                        special,
+                        start: None,
+                        stop: None,
+                        word_id: None,
+                        type_id,
+                    },
+                    // Some custom byte-level tokenizers emit inconsistent offsets: keep the token
+                    // string and the raw offsets
+                    false if stop < start => SimpleToken {
+                        id,
+                        text: token.clone(),
+                        special,
+                        start: Some(start),
+                        stop: Some(stop),
+                        word_id,
+                        type_id,
+                    },
+                    false => {
+                        let (start, stop, text) = span_text(input, start, stop, invalid_utf8)?;
+                        let (start, stop) = match &char_indices {
+                            Some(char_indices) => (char_indices[start], char_indices[stop]),
+                            None => (start, stop),
+                        };
+                        SimpleToken {
+                            id,
+                            text,
+                            special,
+                            start: Some(start),
+                            stop: Some(stop),
+                            word_id,
+                            type_id,
+                        }
+                    }
+                };
+                Ok(token)
+            },
+        )
+        .collect()
+}
+
+/// Offsets and text of the `start..stop` bytes of `input`
+fn span_text(
+    input: &str,
+    start: usize,
+    stop: usize,
+    invalid_utf8: InvalidUtf8Policy,
+) -> Result<(usize, usize, String), TextEmbeddingsError> {
+    let stop = stop.min(input.len());
+    let start = start.min(stop);
+    if input.is_char_boundary(start) && input.is_char_boundary(stop) {
+        return Ok((start, stop, input[start..stop].to_string()));
+    }
+
+    let bytes = &input.as_bytes()[start..stop];
+    let text = match invalid_utf8 {
+        InvalidUtf8Policy::Widen => {
+            let (start, stop) = widen_to_char_boundaries(input, start, stop);
+            return Ok((start, stop, input[start..stop].to_string()));
+        }
+        InvalidUtf8Policy::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        InvalidUtf8Policy::Escape => {
+            let mut text = String::with_capacity(bytes.len());
+            for chunk in bytes.utf8_chunks() {
+                text.push_str(chunk.valid());
+                text.extend(chunk.invalid().iter().map(|byte| format!("\\x{byte:02x}")));
+            }
+            text
+        }
+        InvalidUtf8Policy::Error => {
+            let message =
+                format!("The token spanning bytes {start}..{stop} splits a character of `inputs`");
+            return Err(TextEmbeddingsError::Validation(ValidationError::Other(
+                message,
+            )));
+        }
+    };
+    Ok((start, stop, text))
+}
+
+/// Map every byte offset of `input` to the index of the char it belongs to
+fn byte_to_char_indices(input: &str) -> Vec<usize> {
+    let mut char_indices = Vec::with_capacity(input.len() + 1);
+    for (char_index, c) in input.chars().enumerate() {
+        char_indices.extend(std::iter::repeat(char_index).take(c.len_utf8()));
+    }
+    char_indices.push(input.chars().count());
+    char_indices
+}
+
+/// Offsets can land inside a multi-byte character (e.g. BPE merges on CJK or emoji).
+/// Widen them to the nearest char boundaries so that they always delimit a valid substring.
+fn widen_to_char_boundaries(input: &str, start: usize, stop: usize) -> (usize, usize) {
+    let mut stop = stop.min(input.len());
+    let mut start = start.min(stop);
+    while !input.is_char_boundary(start) {
+        start -= 1;
+    }
+    while !input.is_char_boundary(stop) {
+        stop += 1;
+    }
+    (start, stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hf_hub::api::sync::ApiBuilder;
+    use std::sync::Barrier;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::{AddedToken, PaddingStrategy};
+
+    fn get_tokenizer() -> Tokenizer {
+        let api = ApiBuilder::from_env().build().unwrap();
+        let filename = api
+            .model("BAAI/bge-m3".to_string())
+            .get("tokenizer.json")
+            .unwrap();
+        Tokenizer::from_file(filename).unwrap()
+    }
+
+    #[test]
+    fn tokenizer() {
+        let api = ApiBuilder::from_env().build().unwrap();
+        let filename = api
+            .model("BAAI/bge-m3".to_string())
+            .get("tokenizer.json")
+            .unwrap();
+        let string = "这是一个文本向量化的测试句子";
+        let tokenizer = Tokenizer::from_file(filename).unwrap();
+
+        let encoded = tokenizer.encode(string, true).unwrap();
+        assert_eq!(
+            encoded.get_offsets(),
+            vec![
+                (0, 0),
+                (0, 3),
+                (0, 12),
+                (12, 18),
+                (18, 21),
+                (21, 24),
+                (24, 30),
+                (30, 36),
+                (36, 39),
+                (39, 42),
+                (0, 0)
+            ]
+        );
+
+        let tokens = into_tokens(encoded, &string, false, InvalidUtf8Policy::Widen).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                SimpleToken {
+                    id: 0,
+                    text: "<s>".to_string(),
+                    special: true,
+                    start: None,
+                    stop: None,
+                    word_id: None,
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 6,
+                    text: "这".to_string(),
+                    special: false,
+                    start: Some(0),
+                    stop: Some(3),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 100013,
+                    text: "这是一个".to_string(),
+                    special: false,
+                    start: Some(0),
+                    stop: Some(12),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 189061,
+                    text: "文本".to_string(),
+                    special: false,
+                    start: Some(12),
+                    stop: Some(18),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 2110,
+                    text: "向".to_string(),
+                    special: false,
+                    start: Some(18),
+                    stop: Some(21),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 3272,
+                    text: "量".to_string(),
+                    special: false,
+                    start: Some(21),
+                    stop: Some(24),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 41904,
+                    text: "化的".to_string(),
+                    special: false,
+                    start: Some(24),
+                    stop: Some(30),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 49125,
+                    text: "测试".to_string(),
+                    special: false,
+                    start: Some(30),
+                    stop: Some(36),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 27683,
+                    text: "句".to_string(),
+                    special: false,
+                    start: Some(36),
+                    stop: Some(39),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 1344,
+                    text: "子".to_string(),
+                    special: false,
+                    start: Some(39),
+                    stop: Some(42),
+                    word_id: Some(0),
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 2,
+                    text: "</s>".to_string(),
+                    special: true,
+                    start: None,
+                    stop: None,
+                    word_id: None,
+                    type_id: 0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn content_tokens() {
+        let tokenizer = get_tokenizer();
+        let string = "What is Deep Learning?";
+        let encoding = tokenizer.encode(string, true).unwrap();
+
+        let tokens =
+            into_tokens(encoding.clone(), string, false, InvalidUtf8Policy::Widen).unwrap();
+        let content_tokens =
+            into_content_tokens(encoding, string, false, InvalidUtf8Policy::Widen).unwrap();
+        assert!(tokens.iter().any(|token| !token.is_content()));
+        assert!(content_tokens.iter().all(SimpleToken::is_content));
+        assert_eq!(
+            content_tokens,
+            tokens
+                .into_iter()
+                .filter(SimpleToken::is_content)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn into_tokens_degenerate_offsets() {
+        let encoding = RawEncoding::from_tokens(
+            vec![
+                Token::new(1, "Deep".to_string(), (0, 4)),
+                Token::new(2, "Ġlearning".to_string(), (13, 4)),
+            ],
+            0,
+        );
+
+        let tokens =
+            into_tokens(encoding, "Deep learning", true, InvalidUtf8Policy::Widen).unwrap();
+        assert_eq!(tokens[0].text, "Deep");
+        assert_eq!(
+            tokens[1],
+            SimpleToken {
+                id: 2,
+                text: "Ġlearning".to_string(),
+                special: false,
+                start: Some(13),
+                stop: Some(4),
+                word_id: None,
+                type_id: 0
+            }
+        );
+    }
+
+    #[test]
+    fn into_tokens_multi_byte_offsets() {
+        // Thumbs up + skin tone modifier: two 4-byte code points
+        let string = "👍🏽";
+        let encoding = RawEncoding::from_tokens(
+            vec![
+                Token::new(1, "a".to_string(), (0, 2)),
+                Token::new(2, "b".to_string(), (2, 8)),
+            ],
+            0,
+        );
+
+        let tokens =
+            into_tokens(encoding.clone(), string, false, InvalidUtf8Policy::Widen).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                SimpleToken {
+                    id: 1,
+                    text: "👍".to_string(),
+                    special: false,
+                    start: Some(0),
+                    stop: Some(4),
+                    word_id: None,
+                    type_id: 0
+                },
+                SimpleToken {
+                    id: 2,
+                    text: "👍🏽".to_string(),
+                    special: false,
+                    start: Some(0),
+                    stop: Some(8),
+                    word_id: None,
+                    type_id: 0
+                },
+            ]
+        );
+        assert!(tokens.iter().all(|t| !t.text.contains('\u{FFFD}')));
+
+        // Each code point counts as a single char
+        let tokens = into_tokens(encoding.clone(), string, true, InvalidUtf8Policy::Widen).unwrap();
+        assert_eq!(tokens[0].start, Some(0));
+        assert_eq!(tokens[0].stop, Some(1));
+        assert_eq!(tokens[1].start, Some(0));
+        assert_eq!(tokens[1].stop, Some(2));
+
+        // The other policies keep the offsets
+        let texts = |invalid_utf8| {
+            into_tokens(encoding.clone(), string, false, invalid_utf8).map(|tokens| {
+                tokens
+                    .into_iter()
+                    .map(|token| (token.start, token.stop, token.text))
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            texts(InvalidUtf8Policy::Lossy).unwrap(),
+            vec![
+                (Some(0), Some(2), "\u{FFFD}".to_string()),
+                (Some(2), Some(8), "\u{FFFD}\u{FFFD}🏽".to_string()),
+            ]
+        );
+        assert_eq!(
+            texts(InvalidUtf8Policy::Escape).unwrap(),
+            vec![
+                (Some(0), Some(2), "\\xf0\\x9f".to_string()),
+                (Some(2), Some(8), "\\x91\\x8d🏽".to_string()),
+            ]
+        );
+        assert!(texts(InvalidUtf8Policy::Error).is_err());
+    }
+
+    #[test]
+    fn dual_input_prompt() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let query = "What is Deep Learning?".to_string();
+        let passage = "Deep Learning is a subset of machine learning. ".repeat(20);
+
+        let truncate_params = TruncationParams {
+            direction: TruncationDirection::Right,
+            max_length: 32,
+            strategy: TruncationStrategy::LongestFirst,
+            stride: 0,
+        };
+        let (_, encoding, _) = tokenize_input(
+            EncodingInput::Dual(query, passage),
+            &EncodeOptions {
+                prompt_text: Some("query: ".to_string()),
+                ..Default::default()
+            },
+            32,
+            MAX_CHAR_MULTIPLIER,
+            Some(truncate_params),
+            None,
+            None,
+            false,
+            &tokenizer,
+        )
+        .unwrap();
+        assert_eq!(encoding.len(), 32);
+
+        // The prompt is prepended to the query and the query is untouched by the truncation
+        let expected = tokenizer
+            .encode::<&str>("query: What is Deep Learning?", false, None)
+            .unwrap();
+        let query_ids: Vec<u32> = encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_sequence_ids())
+            .filter_map(|(&id, sequence_id)| (sequence_id == Some(0)).then_some(id))
+            .collect();
+        assert_eq!(query_ids, expected.get_ids());
+    }
+
+    #[test]
+    fn ids_input_prompt_ids() {
+        let mut tokenizer = SharedTokenizer::new(get_tokenizer());
+        let prompt_ids = tokenizer
+            .tokenizer
+            .encode("query: ", false)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        tokenizer
+            .prompt_ids
+            .insert("query: ".to_string(), (prompt_ids.clone(), Vec::new()));
+
+        let text_ids = tokenizer
+            .tokenizer
+            .encode("What is Deep Learning?", false)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        let tokenize = |ids: Vec<u32>, add_special_tokens, max_length: Option<usize>| {
+            tokenize_input(
+                EncodingInput::Ids(ids),
+                &EncodeOptions {
+                    add_special_tokens,
+                    prompt_text: Some("query: ".to_string()),
+                    ..Default::default()
+                },
+                512,
+                MAX_CHAR_MULTIPLIER,
+                max_length.map(|max_length| TruncationParams {
+                    direction: TruncationDirection::Right,
+                    max_length,
+                    strategy: TruncationStrategy::LongestFirst,
+                    stride: 0,
+                }),
+                None,
+                None,
+                true,
+                &tokenizer,
+            )
+            .unwrap()
+        };
+
+        // The prompt ids are prepended as is, the input is not decoded
+        let (text, encoding, _) = tokenize(text_ids.clone(), true, None);
+        assert!(text.is_none());
+        let expected: Vec<u32> = [0]
+            .iter()
+            .chain(&prompt_ids)
+            .chain(&text_ids)
+            .chain(&[2])
+            .copied()
+            .collect();
+        assert_eq!(encoding.get_ids(), expected);
+
+        // Inputs with special tokens go through the text
+        let with_special_tokens: Vec<u32> =
+            [0].into_iter().chain(text_ids.clone()).chain([2]).collect();
+        let (text, encoding, _) = tokenize(with_special_tokens.clone(), true, None);
+        assert_eq!(text.as_deref(), Some("query: What is Deep Learning?"));
+        assert_eq!(encoding.get_ids().iter().filter(|&&id| id == 0).count(), 1);
+
+        // `add_special_tokens` is honored
+        let (_, encoding, _) = tokenize(text_ids.clone(), false, None);
+        assert_eq!(encoding.get_ids(), &expected[1..expected.len() - 1]);
+
+        // Only the input is truncated, the prompt is kept whole
+        let max_length = prompt_ids.len() + 4;
+        for ids in [text_ids.clone(), with_special_tokens] {
+            let (_, encoding, _) = tokenize(ids, true, Some(max_length));
+            let ids = encoding.get_ids();
+            assert_eq!(ids.len(), max_length);
+            assert_eq!(ids[1..=prompt_ids.len()], prompt_ids);
+            assert_eq!(ids[prompt_ids.len() + 1..max_length - 1], text_ids[..2]);
+        }
+    }
+
+    #[test]
+    fn ids_input_add_special_tokens() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let text_ids = tokenizer
+            .tokenizer
+            .encode("What is Deep Learning?", false)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        let with_special_tokens: Vec<u32> =
+            [0].into_iter().chain(text_ids.clone()).chain([2]).collect();
+        let tokenize = |ids: &[u32], add_special_tokens| {
+            let (_, encoding, _) = tokenize_input(
+                EncodingInput::Ids(ids.to_vec()),
+                &EncodeOptions {
+                    add_special_tokens,
+                    ..Default::default()
+                },
+                512,
+                MAX_CHAR_MULTIPLIER,
+                None,
+                None,
+                None,
+                false,
+                &tokenizer,
+            )
+            .unwrap();
+            encoding.get_ids().to_vec()
+        };
+
+        // The ids are kept as they are
+        assert_eq!(tokenize(&text_ids, false), text_ids);
+        assert_eq!(tokenize(&with_special_tokens, false), with_special_tokens);
+
+        // The special tokens are added once
+        assert_eq!(tokenize(&text_ids, true), with_special_tokens);
+        assert_eq!(tokenize(&with_special_tokens, true), with_special_tokens);
+
+        // Only the ends are stripped: the special tokens inside the ids are kept
+        let pair: Vec<u32> = with_special_tokens
+            .iter()
+            .chain(&[2])
+            .chain(&text_ids)
+            .chain(&[2])
+            .copied()
+            .collect();
+        let ids = tokenize(&pair, true);
+        assert_eq!(ids.iter().filter(|&&id| id == 0).count(), 1);
+        assert_eq!(ids.iter().filter(|&&id| id == 2).count(), 3);
+        assert_eq!((ids[0], ids[ids.len() - 1]), (0, 2));
+    }
+
+    #[test]
+    fn valid_encoding_bytes() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let text = "What is Deep Learning? ".repeat(10);
+        let mut encoding = encode_input(
+            EncodingInput::from(text.as_str()),
+            &EncodeOptions {
+                overflow_policy: OverflowPolicy::Chunk { stride: 2 },
+                with_offsets: true,
+                with_special_mask: true,
+                ..Default::default()
+            },
+            16,
+            MAX_CHAR_MULTIPLIER,
+            0,
+            Some("query: ".to_string()),
+            None,
+            &tokenizer,
+        )
+        .unwrap();
+        assert!(!encoding.chunks.is_empty());
+        encoding.chunks[0].applied_prompt = Some("é".to_string());
+
+        let bytes = encoding.to_bytes();
+        assert_eq!(ValidEncoding::from_bytes(&bytes).unwrap(), encoding);
+
+        // Chunks are one level deep and the lists have the same length
+        let mut nested = encoding.clone();
+        nested.chunks[0].chunks.push(encoding.chunks[0].clone());
+        assert!(ValidEncoding::from_bytes(&nested.to_bytes()).is_err());
+        let mut uneven = encoding.clone();
+        uneven.position_ids.pop();
+        assert!(ValidEncoding::from_bytes(&uneven.to_bytes()).is_err());
+        let mut uneven = encoding.clone();
+        uneven.chunks[0].offsets.as_mut().unwrap().pop();
+        assert!(ValidEncoding::from_bytes(&uneven.to_bytes()).is_err());
+
+        let plain = ValidEncoding {
+            offsets: None,
+            special_tokens_mask: None,
+            applied_prompt: None,
+            chunks: Vec::new(),
+            ..encoding
+        };
+        let plain_bytes = plain.to_bytes();
+        // Four lists of `u32`, the flags and the number of chunks
+        assert_eq!(
+            plain_bytes.len(),
+            4 * (4 + 4 * plain.input_ids.len()) + 4 + 4
+        );
+        assert_eq!(ValidEncoding::from_bytes(&plain_bytes).unwrap(), plain);
+
+        assert!(ValidEncoding::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ValidEncoding::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(ValidEncoding::from_bytes(&[0xff; 4]).is_err());
+    }
+
+    #[test]
+    fn special_token_ids() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        assert_eq!(
+            tokenization.special_token_ids(),
+            SpecialTokens {
+                pad: Some(1),
+                bos: Some(0),
+                eos: Some(2),
+                unk: Some(3),
+                cls: Some(0),
+                sep: Some(2),
+            }
+        );
+
+        // Tokens that are not marked as special are not reported
+        let mut tokenizer = Tokenizer::new(WordLevel::default());
+        tokenizer.add_tokens(&[AddedToken::from("[CLS]", false)]);
+        tokenizer.add_special_tokens(&[AddedToken::from("[SEP]", true)]);
+        let special_tokens = SpecialTokens::from_tokenizer(&tokenizer);
+        assert_eq!(special_tokens.cls, None);
+        assert_eq!(special_tokens.sep, tokenizer.token_to_id("[SEP]"));
+        assert_eq!(special_tokens.pad, None);
+    }
+
+    #[test]
+    fn leading_cls() {
+        fn encode(
+            add_special_tokens: bool,
+            tokenizer: &SharedTokenizer,
+        ) -> impl Fn(
+            EncodingInput,
+            Option<(Vec<u32>, Vec<u32>)>,
+        ) -> Result<ValidEncoding, TextEmbeddingsError>
+               + '_ {
+            move |inputs, wrap_tokens| {
+                encode_input(
+                    inputs,
+                    &EncodeOptions {
+                        add_special_tokens,
+                        overflow_policy: OverflowPolicy::Truncate,
+                        wrap_tokens,
+                        ..Default::default()
+                    },
+                    4,
+                    10,
+                    0,
+                    None,
+                    None,
+                    tokenizer,
+                )
+            }
+        }
+        let mut tokenizer = SharedTokenizer::new(get_tokenizer());
+        tokenizer.cls_id = Some(0);
+        let input = || EncodingInput::from("What is Deep Learning?");
+
+        let encoding = encode_with_leading_cls(
+            input(),
+            None,
+            LeadingCls::Require,
+            &tokenizer,
+            encode(true, &tokenizer),
+        )
+        .unwrap();
+        assert_eq!(encoding.input_ids[0], 0);
+
+        let err = encode_with_leading_cls(
+            input(),
+            None,
+            LeadingCls::Require,
+            &tokenizer,
+            encode(false, &tokenizer),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("must start with the CLS token 0"));
+
+        // The inserted CLS token counts against `max_input_length`
+        let unchecked = encode_with_leading_cls(
+            input(),
+            None,
+            LeadingCls::Unchecked,
+            &tokenizer,
+            encode(false, &tokenizer),
+        )
+        .unwrap();
+        let inserted = encode_with_leading_cls(
+            input(),
+            None,
+            LeadingCls::Insert,
+            &tokenizer,
+            encode(false, &tokenizer),
+        )
+        .unwrap();
+        assert_ne!(unchecked.input_ids[0], 0);
+        assert_eq!(inserted.input_ids.len(), 4);
+        assert_eq!(inserted.input_ids[0], 0);
+        assert_eq!(inserted.input_ids[1..], unchecked.input_ids[..3]);
+
+        tokenizer.cls_id = None;
+        let err = encode_with_leading_cls(
+            input(),
+            None,
+            LeadingCls::Insert,
+            &tokenizer,
+            encode(false, &tokenizer),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("tokenizer has no CLS token"));
+        assert!(encode_with_leading_cls(
+            input(),
+            None,
+            LeadingCls::Unchecked,
+            &tokenizer,
+            encode(false, &tokenizer),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn ids_out_of_range() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let vocab_size = tokenizer.tokenizer.get_vocab_size(true) as u32;
+
+        assert!(check_ids(&[0, 6, vocab_size - 1], &tokenizer).is_ok());
+        let err = check_ids(&[0, vocab_size, vocab_size + 1], &tokenizer).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Input validation error: token id {vocab_size} out of range for a vocabulary of {vocab_size} tokens"
+            )
+        );
+
+        // Decoding rejects them as well instead of skipping them
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let ids = vec![0, 6, vocab_size];
+        let out_of_range = |result: Result<_, TextEmbeddingsError>| {
+            matches!(
+                result,
+                Err(TextEmbeddingsError::Validation(ValidationError::Other(message)))
+                    if message.starts_with(&format!("token id {vocab_size} out of range"))
+            )
+        };
+        assert!(out_of_range(futures::executor::block_on(
+            tokenization.decode(ids.clone(), true, None)
+        )));
+        assert!(out_of_range(futures::executor::block_on(
+            tokenization.decode(ids.clone(), true, Some(false))
+        )));
+        assert!(out_of_range(futures::executor::block_on(
+            tokenization.decode_both(ids.clone())
+        )));
+        assert!(out_of_range(futures::executor::block_on(
+            tokenization.decode_tokens(ids.clone(), true)
+        )));
+        let pieces: Vec<_> =
+            futures::executor::block_on(tokenization.decode_stream(ids, true).collect());
+        assert_eq!(pieces.len(), 1);
+        assert!(out_of_range(pieces.into_iter().next().unwrap()));
+    }
+
+    #[test]
+    fn pre_tokenized_input() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let ids = vec![0, 6, 100013, 189061, 2];
+
+        let encoding = encode_input(
+            EncodingInput::PreTokenized {
+                ids: ids.clone(),
+                type_ids: vec![0; 5],
+            },
+            &EncodeOptions {
+                overflow_policy: OverflowPolicy::Truncate,
+                ..Default::default()
+            },
+            4,
+            MAX_CHAR_MULTIPLIER,
+            0,
+            None,
+            None,
+            &tokenizer,
+        )
+        .unwrap();
+        // The ids are not decoded and re-encoded, only truncated
+        assert_eq!(encoding.input_ids, ids[..4]);
+        assert_eq!(encoding.position_ids, vec![0, 1, 2, 3]);
+        assert_eq!(encoding.attention_mask, vec![1, 1, 1, 1]);
+        assert!(encoding.truncated);
+
+        let mismatch = encode_input(
+            EncodingInput::PreTokenized {
+                ids,
+                type_ids: vec![0; 2],
+            },
+            &EncodeOptions::default(),
+            8,
+            MAX_CHAR_MULTIPLIER,
+            0,
+            None,
+            None,
+            &tokenizer,
+        );
+        assert!(mismatch.is_err());
+    }
+
+    #[test]
+    fn special_tokens_mask() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let encode = |with_special_mask| {
+            encode_input(
+                EncodingInput::Single("What is Deep Learning?".to_string()),
+                &EncodeOptions {
+                    with_special_mask,
+                    ..Default::default()
+                },
+                512,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                &tokenizer,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(encode(false).special_tokens_mask, None);
+        let encoding = encode(true);
+        let mask = encoding.special_tokens_mask.unwrap();
+        assert_eq!(mask.len(), encoding.input_ids.len());
+        // Only the surrounding special tokens are masked
+        assert_eq!(mask[0], 1);
+        assert_eq!(mask[mask.len() - 1], 1);
+        assert!(mask[1..mask.len() - 1].iter().all(|&special| special == 0));
+    }
+
+    #[test]
+    fn applied_prompt() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let prompts = HashMap::from([("query".to_string(), "query: ".to_string())]);
+        let encode = |inputs, default_prompt: Option<&str>, prompt_name: Option<&str>| {
+            encode_input(
+                inputs,
+                &EncodeOptions {
+                    prompt_name: prompt_name.map(str::to_string),
+                    ..Default::default()
+                },
+                512,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                default_prompt.map(str::to_string),
+                Some(&prompts),
+                &tokenizer,
+            )
+            .unwrap()
+            .applied_prompt
+        };
+
+        let text = || EncodingInput::from("What is Deep Learning?");
+        assert_eq!(encode(text(), None, None), None);
+        // The default prompt is reported even though the request did not ask for it
+        assert_eq!(
+            encode(text(), Some("passage: "), None).as_deref(),
+            Some("passage: ")
+        );
+        assert_eq!(
+            encode(text(), None, Some("query")).as_deref(),
+            Some("query: ")
+        );
+        let pre_tokenized = EncodingInput::PreTokenized {
+            ids: vec![0, 6, 2],
+            type_ids: vec![0; 3],
+        };
+        assert_eq!(encode(pre_tokenized, Some("passage: "), None), None);
+    }
+
+    #[test]
+    fn custom_truncator() {
+        /// Keep the first and last halves of the budget
+        #[derive(Debug)]
+        struct HeadTail;
+
+        impl Truncator for HeadTail {
+            fn truncate(&self, encoding: RawEncoding, max_len: usize) -> RawEncoding {
+                let ids = encoding.get_ids();
+                let head = max_len / 2;
+                let tail = ids.len().saturating_sub(max_len - head);
+                let ids: Vec<u32> = ids[..head].iter().chain(&ids[tail..]).copied().collect();
+                RawEncoding::from_tokens(
+                    ids.into_iter()
+                        .map(|id| Token::new(id, String::new(), (0, 0)))
+                        .collect(),
+                    0,
+                )
+            }
+        }
+
+        let tokenizer = SharedTokenizer {
+            truncator: Some(Box::new(HeadTail)),
+            ..SharedTokenizer::new(get_tokenizer())
+        };
+        let ids: Vec<u32> = (10..30).collect();
+        let encoding = encode_input(
+            EncodingInput::PreTokenized {
+                ids,
+                type_ids: vec![0; 20],
+            },
+            &EncodeOptions {
+                overflow_policy: OverflowPolicy::Truncate,
+                ..Default::default()
+            },
+            6,
+            MAX_CHAR_MULTIPLIER,
+            0,
+            None,
+            None,
+            &tokenizer,
+        )
+        .unwrap();
+        // The middle of the input is dropped
+        assert_eq!(encoding.input_ids, vec![10, 11, 12, 27, 28, 29]);
+        assert!(encoding.truncated);
+    }
+
+    #[test]
+    fn middle_truncation() {
+        let encode = |separator: Option<u32>, text: &str| {
+            let tokenizer = SharedTokenizer {
+                truncator: Some(Box::new(MiddleTruncation { head: 2, separator })),
+                ..SharedTokenizer::new(get_tokenizer())
+            };
+            let (valid, raw) = encode_input_raw(
+                EncodingInput::from(text),
+                &EncodeOptions {
+                    overflow_policy: OverflowPolicy::Chunk { stride: 0 },
+                    with_offsets: true,
+                    with_special_mask: true,
+                    ..Default::default()
+                },
+                8,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                &tokenizer,
+            )
+            .unwrap();
+            (valid, raw, tokenizer)
+        };
+        let text = "one two three four five six seven eight nine ten";
+
+        let (encoding, raw, tokenizer) = encode(None, text);
+        let ids = tokenizer
+            .tokenizer
+            .encode(text, false)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        // `<s>`, the first 2 tokens, the last 4 tokens and `</s>`
+        assert_eq!(encoding.input_ids.len(), 8);
+        assert_eq!(encoding.input_ids[1..3], ids[..2]);
+        assert_eq!(encoding.input_ids[3..7], ids[ids.len() - 4..]);
+        assert_eq!(encoding.position_ids, (0..8).collect::<Vec<_>>());
+        assert!(encoding.truncated);
+        // The offsets of the tail still point to the end of the text
+        assert_eq!(encoding.offsets.as_ref().unwrap()[6].1, text.len());
+        // The dropped middle becomes the overflowing window
+        let middle = &ids[2..ids.len() - 4];
+        let chunk_ids: Vec<u32> = encoding.chunks[0]
+            .input_ids
+            .iter()
+            .zip(encoding.chunks[0].special_tokens_mask.as_ref().unwrap())
+            .filter(|(_, &special)| special == 0)
+            .map(|(&id, _)| id)
+            .collect();
+        assert_eq!(chunk_ids, middle);
+        assert_eq!(raw.len(), 8);
+
+        let (encoding, _, _) = encode(Some(5), text);
+        assert_eq!(encoding.input_ids.len(), 8);
+        assert_eq!(encoding.input_ids[3], 5);
+        assert_eq!(encoding.input_ids[4..7], ids[ids.len() - 3..]);
+        assert_eq!(encoding.special_tokens_mask.as_ref().unwrap()[3], 1);
+        assert_eq!(encoding.chunks[0].input_ids.len(), middle.len() + 3);
+
+        // Short inputs are kept as is
+        let (encoding, _, _) = encode(Some(5), "one two");
+        assert!(!encoding.truncated);
+        assert!(!encoding.input_ids.contains(&5));
+
+        // An empty budget leaves no room for the separator
+        let raw = tokenizer.tokenizer.encode(text, false).unwrap();
+        let truncator = MiddleTruncation {
+            head: 2,
+            separator: Some(5),
+        };
+        assert_eq!(truncator.truncate(raw.clone(), 0).len(), 0);
+        assert_eq!(truncator.truncate(raw, 1).get_ids(), [5]);
+    }
+
+    #[test]
+    fn encode_raw() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let (valid, raw) = futures::executor::block_on(tokenization.encode_raw(
+            EncodingInput::from("What is Deep Learning?"),
+            true,
+            OverflowPolicy::Error,
+            TruncationDirection::Right,
+            None,
+            None,
+        ))
+        .unwrap();
+
+        // Both come from the same tokenization
+        assert_eq!(valid.input_ids, raw.get_ids());
+        assert_eq!(raw.get_offsets().len(), valid.input_ids.len());
+        assert_eq!(raw.get_offsets()[1], (0, 4));
+    }
+
+    #[test]
+    fn min_content_tokens() {
+        let tokenizer = SharedTokenizer {
+            min_content_tokens: 1,
+            ..SharedTokenizer::new(get_tokenizer())
+        };
+        let encode = |text: &str, default_prompt: Option<&str>| {
+            encode_input(
+                EncodingInput::from(text),
+                &EncodeOptions::default(),
+                512,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                default_prompt.map(str::to_string),
+                None,
+                &tokenizer,
+            )
+        };
+
+        assert!(encode("What is Deep Learning?", None).is_ok());
+        assert!(encode("What is Deep Learning?", Some("query: ")).is_ok());
+        // Whitespace and prompt only inputs have no content
+        for (text, prompt) in [("   ", None), (" ", Some("query: "))] {
+            assert!(matches!(
+                encode(text, prompt),
+                Err(TextEmbeddingsError::Validation(
+                    ValidationError::TooFewTokens {
+                        minimum: 1,
+                        given: 0
+                    }
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn count_prompt_in_limit() {
+        let prompt = "Represent this sentence for searching relevant passages: ";
+        let encode = |tokenizer: &SharedTokenizer,
+                      text: &str,
+                      overflow_policy: OverflowPolicy,
+                      max_input_length: usize| {
+            encode_input(
+                EncodingInput::from(text),
+                &EncodeOptions {
+                    overflow_policy,
+                    ..Default::default()
+                },
+                max_input_length,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                Some(prompt.to_string()),
+                None,
+                tokenizer,
+            )
+        };
+        let counted = SharedTokenizer::new(get_tokenizer());
+        let uncounted = SharedTokenizer {
+            count_prompt_in_limit: false,
+            ..SharedTokenizer::new(get_tokenizer())
+        };
+        let n_prompt_tokens = prompt_token_ids(prompt, &counted).unwrap().len();
+        let n_tokens = encode(
+            &counted,
+            "What is Deep Learning?",
+            OverflowPolicy::Error,
+            512,
+        )
+        .unwrap()
+        .input_ids
+        .len();
+        let max_input_length = n_tokens - n_prompt_tokens;
+
+        // The input alone fits, but not with the prompt
+        assert!(matches!(
+            encode(
+                &counted,
+                "What is Deep Learning?",
+                OverflowPolicy::Error,
+                max_input_length
+            ),
+            Err(TextEmbeddingsError::Validation(
+                ValidationError::TooManyTokens { .. }
+            ))
+        ));
+        let encoding = encode(
+            &uncounted,
+            "What is Deep Learning?",
+            OverflowPolicy::Error,
+            max_input_length,
+        )
+        .unwrap();
+        assert_eq!(encoding.input_ids.len(), n_tokens);
+
+        // The input is truncated to `max_input_length` besides the prompt
+        let text = "What is Deep Learning? ".repeat(20);
+        let encoding = encode(
+            &uncounted,
+            &text,
+            OverflowPolicy::Truncate,
+            max_input_length,
+        )
+        .unwrap();
+        assert!(encoding.truncated);
+        assert_eq!(encoding.input_ids.len(), max_input_length + n_prompt_tokens);
+
+        // The prompt still counts beyond the hard limit of the model
+        let max_model_length = max_input_length + n_prompt_tokens - 1;
+        let bounded = SharedTokenizer {
+            max_model_length: Some(max_model_length),
+            ..uncounted
+        };
+        assert!(matches!(
+            encode(
+                &bounded,
+                "What is Deep Learning?",
+                OverflowPolicy::Error,
+                max_input_length
+            ),
+            Err(TextEmbeddingsError::Validation(
+                ValidationError::TooManyTokens { .. }
+            ))
+        ));
+        let encoding = encode(&bounded, &text, OverflowPolicy::Truncate, max_input_length).unwrap();
+        assert!(encoding.truncated);
+        assert_eq!(encoding.input_ids.len(), max_model_length);
+    }
+
+    #[test]
+    fn padding_after_post_processing() {
+        let mut tokenizer = get_tokenizer();
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::Fixed(32),
+            pad_id: 1,
+            pad_token: "<pad>".to_string(),
+            ..Default::default()
+        }));
+        let tokenizer = SharedTokenizer::new(tokenizer);
+        assert_eq!(
+            tokenizer.padding.as_ref().map(|padding| padding.pad_id),
+            Some(1)
+        );
+
+        // The segments are not padded before the special tokens are added
+        let encoding = tokenizer
+            .encode::<&str>("What is Deep Learning?", true, None)
+            .unwrap();
+        let mut plain = get_tokenizer();
+        plain.with_padding(None);
+        let expected = plain.encode("What is Deep Learning?", true).unwrap();
+        assert_eq!(encoding.get_ids(), expected.get_ids());
+        assert!(!encoding.get_ids().contains(&1));
+    }
+
+    #[test]
+    fn pad_to_multiple_of() {
+        let encode = |tokenizer: &SharedTokenizer, max_input_length| {
+            encode_input(
+                EncodingInput::PreTokenized {
+                    ids: vec![0, 6, 100013, 189061, 2],
+                    type_ids: vec![0; 5],
+                },
+                &EncodeOptions {
+                    pad_to_multiple_of: Some(8),
+                    ..Default::default()
+                },
+                max_input_length,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                tokenizer,
+            )
+        };
+
+        let mut tokenizer = get_tokenizer();
+        tokenizer.with_padding(None);
+        assert!(encode(&SharedTokenizer::new(tokenizer.clone()), 32).is_err());
+
+        tokenizer.with_padding(Some(PaddingParams {
+            pad_id: 1,
+            pad_token: "<pad>".to_string(),
+            ..Default::default()
+        }));
+        let tokenizer = SharedTokenizer::new(tokenizer);
+        let encoding = encode(&tokenizer, 32).unwrap();
+        assert_eq!(encoding.input_ids, vec![0, 6, 100013, 189061, 2, 1, 1, 1]);
+        assert_eq!(encoding.token_type_ids, vec![0; 8]);
+        assert_eq!(encoding.position_ids, (0..8).collect::<Vec<_>>());
+        assert_eq!(encoding.attention_mask, vec![1, 1, 1, 1, 1, 0, 0, 0]);
+
+        // The padding stops at `max_input_length`
+        let encoding = encode(&tokenizer, 6).unwrap();
+        assert_eq!(encoding.input_ids, vec![0, 6, 100013, 189061, 2, 1]);
+        assert_eq!(encoding.position_ids, (0..6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn encode_stream() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 2, 512)
+            .queue_capacity(2)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let texts = ["What is Deep Learning?", "Deep", "", "Learning"];
+        let inputs = futures::stream::iter(texts.map(EncodingInput::from));
+
+        let results: Vec<_> = futures::executor::block_on(
+            tokenization
+                .encode_stream(
+                    inputs,
+                    false,
+                    TruncationDirection::Right,
+                    TruncationStrategy::LongestFirst,
+                    None,
+                    None,
+                    false,
+                )
+                .collect(),
+        );
+        assert_eq!(results.len(), texts.len());
+        // The results are in the order of the inputs and an error does not end the stream
+        for (text, result) in texts.iter().zip(results) {
+            if text.is_empty() {
+                assert!(matches!(
+                    result,
+                    Err(TextEmbeddingsError::Validation(ValidationError::Empty))
+                ));
+                continue;
+            }
+            let expected = get_tokenizer().encode(*text, true).unwrap();
+            assert_eq!(result.unwrap().input_ids, expected.get_ids());
+        }
+    }
+
+    #[test]
+    fn count_tokens_matches_encode() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 8)
+            .prompts(Some(HashMap::from([(
+                "query".to_string(),
+                "query: ".to_string(),
+            )])))
+            .metrics(false)
+            .build()
+            .unwrap();
+        let count = |text: &str, prompt_name: Option<&str>| {
+            futures::executor::block_on(
+                tokenization
+                    .count_tokens(EncodingInput::from(text), prompt_name.map(str::to_string)),
+            )
+        };
+        let tokenizer = get_tokenizer();
+        let expected = |text: &str| tokenizer.encode(text, true).unwrap().len();
+
+        let text = "What is Deep Learning?";
+        assert_eq!(count(text, None).unwrap(), expected(text));
+        // Inputs longer than `max_input_length` are counted in full
+        let long = "Deep Learning is a subset of machine learning.";
+        assert!(expected(long) > 8);
+        assert_eq!(count(long, None).unwrap(), expected(long));
+        // The prompt tokens are part of the count
+        assert_eq!(
+            count(text, Some("query")).unwrap(),
+            expected("query: What is Deep Learning?")
+        );
+
+        assert!(count(text, Some("passage")).is_err());
+        assert!(matches!(
+            count("", None),
+            Err(TextEmbeddingsError::Validation(ValidationError::Empty))
+        ));
+    }
+
+    #[test]
+    fn encode_batch_in_order() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 16)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let encode_batch = |texts: &[&str], truncate: bool| {
+            futures::executor::block_on(
+                tokenization.encode_batch(
+                    texts
+                        .iter()
+                        .map(|&text| EncodingInput::from(text))
+                        .collect(),
+                    truncate,
+                    TruncationDirection::Right,
+                    TruncationStrategy::LongestFirst,
+                    None,
+                    None,
+                    false,
+                    false,
+                ),
+            )
+        };
+        let long =
+            "Deep Learning is a subset of machine learning, which is a subset of artificial \
+                    intelligence.";
+
+        // One round-trip returns the encodings in the order of the inputs
+        let texts = ["What is Deep Learning?", "Hi"];
+        let (encodings, max_length) = encode_batch(&texts, false).unwrap();
+        assert_eq!(encodings.len(), 2);
+        for (encoding, text) in encodings.iter().zip(texts) {
+            let expected = get_tokenizer().encode(text, true).unwrap();
+            assert_eq!(encoding.input_ids, expected.get_ids());
+        }
+        assert_eq!(max_length, encodings[0].input_ids.len());
+
+        // A single invalid input fails the whole batch
+        assert!(matches!(
+            encode_batch(&[texts[0], long], false),
+            Err(TextEmbeddingsError::Validation(
+                ValidationError::TooManyTokens { .. }
+            ))
+        ));
+        let (encodings, max_length) = encode_batch(&[texts[0], long], true).unwrap();
+        assert_eq!(encodings[1].input_ids.len(), 16);
+        assert_eq!(max_length, 16);
+
+        for texts in [&[][..], &["Hi", ""][..]] {
+            assert!(matches!(
+                encode_batch(texts, false),
+                Err(TextEmbeddingsError::Validation(ValidationError::Empty))
+            ));
+        }
+    }
+
+    #[test]
+    fn encode_batch_pad_to_longest() {
+        let inputs = || {
+            vec![
+                EncodingInput::from("Deep"),
+                EncodingInput::from("What is Deep Learning?"),
+            ]
+        };
+        let encode_batch = |tokenizer: Tokenizer, pad_to_longest: bool| {
+            let tokenization = Tokenization::builder(tokenizer, 1, 512)
+                .metrics(false)
+                .build()
+                .unwrap();
+            futures::executor::block_on(tokenization.encode_batch(
+                inputs(),
+                false,
+                TruncationDirection::Right,
+                TruncationStrategy::LongestFirst,
+                None,
+                None,
+                true,
+                pad_to_longest,
+            ))
+        };
+
+        let mut tokenizer = get_tokenizer();
+        tokenizer.with_padding(None);
+        let (encodings, max_length) = encode_batch(tokenizer.clone(), false).unwrap();
+        let lengths: Vec<_> = encodings.iter().map(|e| e.input_ids.len()).collect();
+        assert_eq!(max_length, lengths[1]);
+        assert!(lengths[0] < max_length);
+        assert!(encode_batch(tokenizer.clone(), true).is_err());
+
+        tokenizer.with_padding(Some(PaddingParams {
+            pad_id: 1,
+            pad_token: "<pad>".to_string(),
+            ..Default::default()
+        }));
+        let (padded, padded_length) = encode_batch(tokenizer, true).unwrap();
+        assert_eq!(padded_length, max_length);
+        for (encoding, padded) in encodings.iter().zip(&padded) {
+            let n_pad = max_length - encoding.input_ids.len();
+            assert_eq!(padded.input_ids.len(), max_length);
+            assert_eq!(
+                padded.input_ids[..encoding.input_ids.len()],
+                encoding.input_ids
+            );
+            assert!(padded.input_ids[encoding.input_ids.len()..]
+                .iter()
+                .all(|&id| id == 1));
+            assert_eq!(
+                padded.attention_mask.iter().filter(|&&m| m == 0).count(),
+                n_pad
+            );
+            assert_eq!(padded.offsets.as_ref().unwrap().len(), max_length);
+            assert_eq!(
+                padded.position_ids,
+                (0..max_length as u32).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn truncation_does_not_leak_across_requests() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let text = "Deep Learning is a subset of machine learning. ".repeat(4);
+
+        let encode = || {
+            encode_input(
+                EncodingInput::from(text.as_str()),
+                &EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    ..Default::default()
+                },
+                8,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                &tokenizer,
+            )
+            .unwrap()
+        };
+        let tokenize = || {
+            tokenize_input(
+                EncodingInput::from(text.as_str()),
+                &EncodeOptions::default(),
+                8,
+                MAX_CHAR_MULTIPLIER,
+                None,
+                None,
+                None,
+                false,
+                &tokenizer,
+            )
+            .unwrap()
+            .1
+        };
+
+        // Truncation parameters only apply to the request that sets them
+        let full_len = tokenize().len();
+        assert!(full_len > 8);
+        assert_eq!(encode().input_ids.len(), 8);
+        assert_eq!(tokenize().len(), full_len);
+        assert_eq!(encode().input_ids.len(), 8);
+    }
+
+    #[test]
+    fn prompt_survives_truncation() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let text = "Deep Learning is a subset of machine learning. ".repeat(20);
+
+        let encoding = encode_input(
+            EncodingInput::from(text),
+            &EncodeOptions {
+                overflow_policy: OverflowPolicy::Truncate,
+                truncation_direction: TruncationDirection::Left,
+                prompt_text: Some(
+                    "Represent this sentence for searching relevant passages: ".to_string(),
+                ),
+                ..Default::default()
+            },
+            16,
+            MAX_CHAR_MULTIPLIER,
+            0,
+            None,
+            None,
+            &tokenizer,
+        )
+        .unwrap();
+        assert_eq!(encoding.input_ids.len(), 16);
+        assert!(encoding.truncated);
+
+        // The prompt follows the first special token even though the start of the input is cut
+        let prompt = tokenizer
+            .tokenizer
+            .encode(
+                "Represent this sentence for searching relevant passages: ",
+                false,
+            )
+            .unwrap();
+        let prompt_ids = prompt.get_ids();
+        assert_eq!(&encoding.input_ids[1..prompt_ids.len() + 1], prompt_ids);
+    }
+
+    #[test]
+    fn tokenize_batch_per_item_errors() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let tokenize_batch = |inputs: Vec<EncodingInput>, prompt_name: Option<&str>| {
+            futures::executor::block_on(tokenization.tokenize_batch(
+                inputs,
+                true,
+                prompt_name.map(str::to_string),
+                None,
+            ))
+        };
+        let expected = |text: &str| get_tokenizer().encode(text, true).unwrap();
+
+        // Invalid inputs get their own error, the others are tokenized
+        let results = tokenize_batch(
+            vec![
+                EncodingInput::from("What is Deep Learning?"),
+                EncodingInput::from(""),
+                EncodingInput::from(vec![0, 1_000_000, 2]),
+                EncodingInput::from("Hi"),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(
+            results[0].as_ref().unwrap().encoding.get_ids(),
+            expected("What is Deep Learning?").get_ids()
+        );
+        assert!(matches!(
+            results[1],
+            Err(TextEmbeddingsError::Validation(ValidationError::Empty))
+        ));
+        assert!(matches!(
+            &results[2],
+            Err(TextEmbeddingsError::Validation(ValidationError::Other(message)))
+                if message.contains("out of range")
+        ));
+        assert_eq!(
+            results[3].as_ref().unwrap().encoding.get_ids(),
+            expected("Hi").get_ids()
+        );
+
+        // An unknown prompt fails every input, an empty batch fails as a whole
+        let results = tokenize_batch(vec![EncodingInput::from("Hi")], Some("query")).unwrap();
+        assert!(matches!(
+            results[0],
+            Err(TextEmbeddingsError::Validation(
+                ValidationError::UnknownPrompt { .. }
+            ))
+        ));
+        assert!(tokenize_batch(Vec::new(), None).is_err());
+    }
+
+    #[test]
+    fn tokenize_prompt_token_count() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let count_tokens = |text: &str| tokenizer.tokenizer.encode(text, false).unwrap().len();
+        let tokenize = |prompt: &str| {
+            tokenize_with_prompt(
+                EncodingInput::from("What is Deep Learning?"),
+                true,
+                512,
+                MAX_CHAR_MULTIPLIER,
+                None,
+                None,
+                Some(prompt.to_string()),
+                None,
+                &tokenizer,
+            )
+            .unwrap()
+        };
+
+        let tokenized = tokenize("query: ");
+        assert_eq!(
+            tokenized.full_text.as_deref(),
+            Some("query: What is Deep Learning?")
+        );
+        assert_eq!(tokenized.prompt_text.as_deref(), Some("query: "));
+        assert_eq!(tokenized.prompt_token_count, count_tokens("query: "));
+
+        // Tokens on both sides of the placeholder belong to the prompt
+        let tokenized = tokenize("query: {text} Represent this question.");
+        assert_eq!(
+            tokenized.prompt_token_count,
+            count_tokens("query: ") + count_tokens(" Represent this question.")
+        );
+
+        // Without a separator a token can straddle the prompt and the text, the count still
+        // comes from the prompt alone
+        let tokenized = tokenize("query:");
+        assert_eq!(tokenized.prompt_token_count, count_tokens("query:"));
+    }
+
+    #[test]
+    fn overflow_policy() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let text = "Deep Learning is a subset of machine learning. ".repeat(4);
+        let encode = |overflow_policy: OverflowPolicy| {
+            encode_input(
+                EncodingInput::from(text.as_str()),
+                &EncodeOptions {
+                    overflow_policy,
+                    ..Default::default()
+                },
+                16,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                &tokenizer,
+            )
+        };
+
+        assert!(encode(OverflowPolicy::Error).is_err());
+
+        let truncated = encode(OverflowPolicy::Truncate).unwrap();
+        assert_eq!(truncated.input_ids.len(), 16);
+        assert!(truncated.truncated);
+        assert!(truncated.chunks.is_empty());
+
+        // The following windows are kept instead of being dropped
+        let chunked = encode(OverflowPolicy::Chunk { stride: 0 }).unwrap();
+        assert_eq!(chunked.input_ids, truncated.input_ids);
+        assert!(!chunked.truncated);
+        assert!(!chunked.chunks.is_empty());
+        assert!(chunked
+            .chunks
+            .iter()
+            .all(|chunk| chunk.input_ids.len() <= 16));
+
+        // Overlapping windows produce more chunks
+        let strided = encode(OverflowPolicy::Chunk { stride: 4 }).unwrap();
+        assert_eq!(strided.input_ids, truncated.input_ids);
+        assert!(strided.chunks.len() > chunked.chunks.len());
+
+        assert!(encode(OverflowPolicy::Chunk { stride: 16 }).is_err());
+    }
+
+    #[test]
+    fn wrap_tokens() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+
+        let encoding = encode_input(
+            EncodingInput::PreTokenized {
+                ids: vec![0, 6, 100013, 189061, 2],
+                type_ids: vec![0; 5],
+            },
+            &EncodeOptions {
+                overflow_policy: OverflowPolicy::Truncate,
+                wrap_tokens: Some((vec![7], vec![8])),
+                ..Default::default()
+            },
+            6,
+            MAX_CHAR_MULTIPLIER,
+            0,
+            None,
+            None,
+            &tokenizer,
+        )
+        .unwrap();
+        // The input is truncated to keep room for the wrapping tokens
+        assert_eq!(encoding.input_ids, vec![7, 0, 6, 100013, 189061, 8]);
+        assert_eq!(encoding.token_type_ids, vec![0; 6]);
+        assert_eq!(encoding.position_ids, (0..6).collect::<Vec<_>>());
+        assert!(encoding.truncated);
+    }
+
+    #[test]
+    fn dual_input_char_limit() {
+        let query = "0123456789".to_string();
+        let passage = "a".repeat(1000);
+
+        // The passage uses the budget left by the short query
+        let mut inputs = EncodingInput::Dual(query.clone(), passage.clone());
+        assert!(inputs.apply_limit(100, DualSplit::Even));
+        let EncodingInput::Dual(s1, s2) = inputs else {
+            unreachable!()
+        };
+        assert_eq!(s1, query);
+        assert_eq!(s2.len(), 90);
+
+        // Both segments are clipped to half of the budget when they are both long
+        let mut inputs = EncodingInput::Dual(passage.clone(), passage);
+        assert!(inputs.apply_limit(101, DualSplit::Even));
+        let EncodingInput::Dual(s1, s2) = inputs else {
+            unreachable!()
+        };
+        assert_eq!(s1.len(), 50);
+        assert_eq!(s2.len(), 51);
+    }
+
+    #[test]
+    fn truncation_strategy_segments() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let encode = |inputs: EncodingInput, truncation_strategy: TruncationStrategy| {
+            encode_input(
+                inputs,
+                &EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    truncation_strategy,
+                    ..Default::default()
+                },
+                16,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                &tokenizer,
+            )
+        };
+        let query = "What is Deep Learning?";
+        let passage = "Deep Learning is a subset of machine learning. ".repeat(4);
+        let query_ids = get_tokenizer()
+            .encode(query, true)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+
+        // Only pairs have a segment to select
+        for truncation_strategy in [
+            TruncationStrategy::OnlyFirst,
+            TruncationStrategy::OnlySecond,
+        ] {
+            for inputs in [EncodingInput::from(query), EncodingInput::from(vec![0, 2])] {
+                assert!(matches!(
+                    encode(inputs, truncation_strategy),
+                    Err(TextEmbeddingsError::Validation(ValidationError::Other(message)))
+                        if message.contains("dual inputs")
+                ));
+            }
+        }
+        assert!(encode(EncodingInput::from(query), TruncationStrategy::LongestFirst).is_ok());
+
+        // The query is kept whole, at the start or at the end of the pair
+        let encoding = encode(
+            EncodingInput::from((query, passage.as_str())),
+            TruncationStrategy::OnlySecond,
+        )
+        .unwrap();
+        assert_eq!(encoding.input_ids.len(), 16);
+        assert_eq!(encoding.input_ids[..query_ids.len()], query_ids);
+        let encoding = encode(
+            EncodingInput::from((passage.as_str(), query)),
+            TruncationStrategy::OnlyFirst,
+        )
+        .unwrap();
+        assert_eq!(encoding.input_ids.len(), 16);
+        assert!(encoding.input_ids.ends_with(&query_ids[1..]));
+    }
+
+    #[test]
+    fn half_empty_dual() {
+        let encode = |half_empty_dual: HalfEmptyDual, s1: &str, s2: &str| {
+            let tokenizer = SharedTokenizer {
+                half_empty_dual,
+                ..SharedTokenizer::new(get_tokenizer())
+            };
+            encode_input(
+                EncodingInput::from((s1, s2)),
+                &EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    truncation_strategy: TruncationStrategy::OnlySecond,
+                    ..Default::default()
+                },
+                512,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                &tokenizer,
+            )
+        };
+        let single = get_tokenizer()
+            .encode("What is Deep Learning?", true)
+            .unwrap();
+
+        // Both half-empty pairs are encoded as their non-empty segment
+        for (s1, s2) in [
+            ("What is Deep Learning?", ""),
+            ("", "What is Deep Learning?"),
+        ] {
+            let encoding = encode(HalfEmptyDual::Collapse, s1, s2).unwrap();
+            assert_eq!(encoding.input_ids, single.get_ids());
+            assert!(encoding.token_type_ids.iter().all(|&type_id| type_id == 0));
+        }
+
+        for (s1, s2, segment) in [
+            ("What is Deep Learning?", "", 1),
+            ("", "What is Deep Learning?", 0),
+        ] {
+            let err = encode(HalfEmptyDual::Reject, s1, s2).unwrap_err();
+            assert!(matches!(
+                err,
+                TextEmbeddingsError::Validation(ValidationError::EmptySegment { segment: s })
+                    if s == segment
+            ));
+        }
+
+        // Full pairs are left as is
+        let encoding = encode(HalfEmptyDual::Reject, "query", "passage").unwrap();
+        let pair = get_tokenizer().encode(("query", "passage"), true).unwrap();
+        assert_eq!(encoding.input_ids, pair.get_ids());
+    }
+
+    #[test]
+    fn dual_split() {
+        assert_eq!(DualSplit::Even.budgets(30, 40, 100), (30, 40));
+        assert_eq!(DualSplit::Even.budgets(10, 200, 100), (10, 90));
+        assert_eq!(DualSplit::Proportional.budgets(100, 300, 100), (25, 75));
+        assert_eq!(
+            DualSplit::Fixed { first_max: 20 }.budgets(50, 300, 100),
+            (20, 80)
+        );
+        // The first segment does not get more than it needs
+        assert_eq!(
+            DualSplit::Fixed { first_max: 20 }.budgets(5, 300, 100),
+            (5, 95)
+        );
+        // Non-empty segments are never dropped entirely
+        assert_eq!(DualSplit::Proportional.budgets(1, 1000, 100), (1, 99));
+        assert_eq!(
+            DualSplit::Fixed { first_max: 0 }.budgets(5, 300, 100),
+            (1, 99)
+        );
+        assert_eq!(
+            DualSplit::Fixed { first_max: 200 }.budgets(300, 5, 100),
+            (99, 1)
+        );
+        assert_eq!(DualSplit::Proportional.budgets(0, 1000, 100), (0, 100));
+        assert_eq!(DualSplit::Proportional.budgets(1, 1000, 1), (0, 1));
+
+        let tokenizer = SharedTokenizer {
+            dual_split: Some(DualSplit::Fixed { first_max: 4 }),
+            ..SharedTokenizer::new(get_tokenizer())
+        };
+        let params = TruncationParams {
+            direction: TruncationDirection::Right,
+            max_length: 16,
+            strategy: TruncationStrategy::LongestFirst,
+            stride: 0,
+        };
+        let passage = "Deep Learning is a subset of machine learning. ".repeat(20);
+        let encoding = tokenizer
+            .encode::<(String, String)>(
+                ("What is Deep Learning?".to_string(), passage.clone()),
+                false,
+                Some(&params),
+            )
+            .unwrap();
+        let n_query_tokens = encoding
+            .get_sequence_ids()
+            .iter()
+            .filter(|&&sequence_id| sequence_id == Some(0))
+            .count();
+        assert_eq!(encoding.len(), 16);
+        assert_eq!(n_query_tokens, 4);
+
+        // A stride larger than the budget of a segment does not panic
+        let encoding = tokenizer
+            .encode::<(String, String)>(
+                ("What is Deep Learning?".to_string(), passage),
+                false,
+                Some(&TruncationParams {
+                    stride: 8,
+                    ..params
+                }),
+            )
+            .unwrap();
+        assert_eq!(encoding.len(), 16);
+    }
+
+    #[test]
+    fn estimated_tokens() {
+        assert_eq!(estimate_tokens(0, MAX_CHAR_MULTIPLIER), (0, 0));
+        assert_eq!(estimate_tokens(1000, MAX_CHAR_MULTIPLIER), (4, 4000));
+        assert_eq!(estimate_tokens(1001, MAX_CHAR_MULTIPLIER), (5, 4004));
+
+        // The actual number of tokens falls in the estimate
+        let text = "What is Deep Learning?";
+        let n_tokens = get_tokenizer().encode(text, false).unwrap().len();
+        let (min, max) = estimate_tokens(text.chars().count(), MAX_CHAR_MULTIPLIER);
+        assert!(min <= n_tokens && n_tokens <= max);
+    }
+
+    #[test]
+    fn offsets_after_normalization() {
+        let tokenizer = SharedTokenizer {
+            normalization: Some(UnicodeNormalization::Nfkc),
+            ..SharedTokenizer::new(get_tokenizer())
+        };
+        // NFKC turns the 3 bytes ligature into 2 ASCII bytes
+        let input = "e\u{fb01}ne Deep Learning";
+        let tokenized = tokenize_with_prompt(
+            EncodingInput::from(input),
+            true,
+            512,
+            MAX_CHAR_MULTIPLIER,
+            None,
+            None,
+            Some("query: ".to_string()),
+            None,
+            &tokenizer,
+        )
+        .unwrap();
+
+        let full_text = tokenized.full_text.unwrap();
+        assert_eq!(full_text, format!("query: {input}"));
+        assert!(!tokenized.offsets_approximate);
+        // The offsets after the ligature still point to the same words in the original input
+        let words: Vec<&str> = tokenized
+            .encoding
+            .get_offsets()
+            .iter()
+            .map(|&(start, end)| &full_text[start..end])
+            .collect();
+        assert!(words.contains(&"Learning"));
+        assert!(words.iter().any(|word| word.contains('\u{fb01}')));
+    }
+
+    #[test]
+    fn token_counts() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let counts = futures::executor::block_on(
+            tokenization.token_counts("Deep Learning, Deep Learning and Deep".into(), None),
+        )
+        .unwrap();
+
+        let deep = get_tokenizer().encode("Deep", false).unwrap().get_ids()[0];
+        assert_eq!(counts[&deep], 3);
+        // The special tokens are not counted
+        assert!(!counts.contains_key(&0) && !counts.contains_key(&2));
+    }
+
+    #[test]
+    fn truncation_hint() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let encode = |max_input_length: usize, prompt: &str| {
+            encode_input(
+                EncodingInput::from("Deep Learning is a subset of machine learning."),
+                &EncodeOptions::default(),
+                max_input_length,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                Some(prompt.to_string()),
+                None,
+                &tokenizer,
+            )
+        };
+
+        let Err(TextEmbeddingsError::Validation(ValidationError::TooManyTokens {
+            truncation_would_fit,
+            ..
+        })) = encode(8, "query: ")
+        else {
+            panic!("the input must be rejected")
+        };
+        assert!(truncation_would_fit);
+
+        // The prompt alone does not fit: truncating the input would not help
+        let long_prompt = "Represent this sentence for searching relevant passages: ";
+        let Err(TextEmbeddingsError::Validation(ValidationError::TooManyTokens {
+            truncation_would_fit,
+            ..
+        })) = encode(8, long_prompt)
+        else {
+            panic!("the input must be rejected")
+        };
+        assert!(!truncation_would_fit);
+    }
+
+    #[test]
+    fn char_limit_inside_codepoint() {
+        // Each char is 3 bytes long: the limit counts them once
+        let mut inputs = EncodingInput::from("这是一个文本");
+        assert!(!inputs.apply_limit(10, DualSplit::Even));
+        assert!(inputs.apply_limit(4, DualSplit::Even));
+        let EncodingInput::Single(s) = inputs else {
+            unreachable!()
+        };
+        assert_eq!(s, "这是一个");
+
+        // A short multi-byte query fits its char budget
+        let passage = "a".repeat(1000);
+        let mut inputs = EncodingInput::Dual("日本語".to_string(), passage);
+        assert!(inputs.apply_limit(100, DualSplit::Even));
+        let EncodingInput::Dual(s1, s2) = inputs else {
+            unreachable!()
+        };
+        assert_eq!(s1, "日本語");
+        assert_eq!(s2.len(), 97);
+    }
+
+    #[test]
+    fn encode_sentences() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let tokenizer = get_tokenizer();
+        let decode =
+            |encoding: &ValidEncoding| tokenizer.decode(&encoding.input_ids, true).unwrap();
+
+        assert_eq!(
+            sentence_ranges("Hi there. What is it?! Version 1.5 is out\n"),
+            vec![0..10, 10..23, 23..42]
+        );
+        assert_eq!(sentence_ranges("你好。谢谢"), vec![0..9, 9..15]);
+
+        let text = "Deep Learning is a subset of machine learning. It uses neural networks! \
+            Why does it work? Nobody knows.";
+        let encodings =
+            futures::executor::block_on(tokenization.encode_sentences(text, 16, None)).unwrap();
+        let chunks: Vec<_> = encodings.iter().map(decode).collect();
+        // Sentences are packed whole and in order
+        assert!(chunks.len() > 1 && chunks.len() < 4);
+        assert_eq!(chunks.join(" "), text);
+        for (chunk, encoding) in chunks.iter().zip(&encodings) {
+            assert!(chunk.ends_with(['.', '!', '?']));
+            assert!(encoding.input_ids.len() <= 16);
+        }
+
+        // A sentence longer than the limit is cut into windows
+        let long = "Deep Learning is a subset of machine learning that uses neural networks";
+        let encodings = futures::executor::block_on(tokenization.encode_sentences(
+            &format!("Hello. {long}"),
+            8,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(decode(&encodings[0]), "Hello.");
+        assert!(encodings.len() > 2);
+        assert!(encodings
+            .iter()
+            .all(|encoding| encoding.input_ids.len() <= 8));
+
+        // Chunks stay under the char limit instead of failing the request
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 16)
+            .max_char_multiplier(1)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let text = "Hi. ".repeat(20);
+        let encodings =
+            futures::executor::block_on(tokenization.encode_sentences(&text, 16, None)).unwrap();
+        let chunks: Vec<_> = encodings.iter().map(decode).collect();
+        assert_eq!(chunks.join(" "), text.trim());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 16));
+
+        assert!(
+            futures::executor::block_on(tokenization.encode_sentences(text.as_str(), 0, None))
+                .is_err()
+        );
+        assert!(futures::executor::block_on(tokenization.encode_sentences(
+            text.as_str(),
+            17,
+            None
+        ))
+        .is_err());
+        assert!(
+            futures::executor::block_on(tokenization.encode_sentences(" \n", 16, None)).is_err()
+        );
+    }
+
+    #[test]
+    fn encode_chunks_metadata() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let text = "Deep Learning is a subset of machine learning. ".repeat(10);
+        let n_tokens = tokenizer
+            .tokenizer
+            .encode(text.as_str(), false)
+            .unwrap()
+            .len();
+
+        let chunks = |position_mode, prompt_text: Option<&str>| {
+            encode_chunks(
+                EncodingInput::from(text.as_str()),
+                16,
+                4,
+                position_mode,
+                512,
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                prompt_text.map(str::to_string),
+                false,
+                None,
+                &tokenizer,
+            )
+            .unwrap()
+        };
+        let restarted = chunks(PositionMode::Restart, None);
+        assert!(restarted.len() > 1);
+
+        // 14 input tokens per window, 4 of them shared with the previous window
+        for (i, chunk) in restarted.iter().enumerate() {
+            assert_eq!(chunk.chunk_index, i);
+            assert_eq!(chunk.start_token, i * 10);
+            assert_eq!(chunk.is_last, i == restarted.len() - 1);
+            assert_eq!(chunk.valid.position_ids[0], 0);
+        }
+        let last = restarted.last().unwrap();
+        assert_eq!(last.start_token + last.valid.input_ids.len() - 2, n_tokens);
+
+        // Shared tokens keep the position they have in the previous window
+        let continuous = chunks(PositionMode::Continuous, None);
+        for (restart, chunk) in restarted.iter().zip(&continuous) {
+            assert_eq!(chunk.valid.input_ids, restart.valid.input_ids);
+            assert_eq!(chunk.valid.position_ids[0] as usize, chunk.start_token);
+        }
+        assert_eq!(continuous[0].valid.position_ids[11], 11);
+        assert_eq!(continuous[1].valid.position_ids[1], 11);
+
+        // The prompt repeated in every window does not count as input tokens
+        let n_prompt_tokens = tokenizer.tokenizer.encode("query: ", false).unwrap().len();
+        let window_content = 14 - n_prompt_tokens;
+        let prompted = chunks(PositionMode::Continuous, Some("query: "));
+        assert!(prompted.len() > 1);
+        for (i, chunk) in prompted.iter().enumerate() {
+            assert_eq!(chunk.start_token, i * (window_content - 4));
+            assert_eq!(chunk.valid.position_ids[0] as usize, chunk.start_token);
+        }
+    }
+
+    #[test]
+    fn encode_chunks_windows() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .position_offset(2)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let text = "Deep Learning is a subset of machine learning. ".repeat(10);
+        let encode_chunks = |text: &str, max_input_length: usize, stride: usize| {
+            futures::executor::block_on(tokenization.encode_chunks(
+                EncodingInput::from(text),
+                max_input_length,
+                stride,
+                PositionMode::Restart,
+                None,
+                false,
+            ))
+        };
+
+        let chunks = encode_chunks(&text, 16, 4).unwrap();
+        assert!(chunks.len() > 1);
+        for (chunk, next) in chunks.iter().zip(&chunks[1..]) {
+            // `<s>` and `</s>` around the 14 input tokens of each window
+            let content = |chunk: &ChunkEncoding| {
+                let ids = &chunk.valid.input_ids;
+                ids[1..ids.len() - 1].to_vec()
+            };
+            assert_eq!(chunk.valid.input_ids.len(), 16);
+            assert_eq!(content(chunk)[10..], content(next)[..4]);
+            assert_eq!(chunk.valid.position_ids[0], 2);
+        }
+
+        // An input that fits is a single window
+        let chunks = encode_chunks("What is Deep Learning?", 16, 4).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_last);
+
+        assert!(encode_chunks(&text, 0, 0).is_err());
+        assert!(encode_chunks(&text, 513, 4).is_err());
+        assert!(encode_chunks(&text, 16, 16).is_err());
+        assert!(matches!(
+            encode_chunks("", 16, 4),
+            Err(TextEmbeddingsError::Validation(ValidationError::Empty))
+        ));
+    }
+
+    #[test]
+    fn decode_clean_up_override() {
+        let tokens = ["What", "is", "it", "?"];
+        let mut tokenizer = Tokenizer::new(WordLevel::default());
+        tokenizer.add_tokens(&tokens.map(|token| AddedToken::from(token, false)));
+        tokenizer.with_decoder(Some(tokenizers::decoders::wordpiece::WordPiece::default()));
+        let ids: Vec<u32> = tokens
+            .iter()
+            .map(|&token| tokenizer.token_to_id(token).unwrap())
+            .collect();
+        let tokenizer = SharedTokenizer::new(tokenizer);
+        let decode = |clean_up_tokenization_spaces| {
+            decode_ids(ids.clone(), true, clean_up_tokenization_spaces, &tokenizer).unwrap()
+        };
+
+        // The WordPiece decoder cleans up by default
+        assert_eq!(decode(None), "What is it?");
+        assert_eq!(decode(Some(true)), "What is it?");
+        assert_eq!(decode(Some(false)), "What is it ?");
+    }
+
+    #[test]
+    fn decode_tokens_aligned() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let ids = tokenizer
+            .encode::<&str>("What is Deep Learning?", true, None)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+
+        let tokens = decode_tokens(&ids, true, &tokenizer).unwrap();
+        assert_eq!(tokens.len(), ids.len());
+        // Skipped special tokens keep their position
+        assert_eq!(tokens.first().map(String::as_str), Some(""));
+        assert_eq!(tokens.last().map(String::as_str), Some(""));
+        assert_eq!(tokens[1], "What");
+
+        let tokens = decode_tokens(&ids, false, &tokenizer).unwrap();
+        assert_eq!(tokens.first().map(String::as_str), Some("<s>"));
+    }
+
+    #[test]
+    fn decode_both() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let ids = get_tokenizer()
+            .encode("What is Deep Learning?", true)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+
+        let (with_special, without_special) =
+            futures::executor::block_on(tokenization.decode_both(ids)).unwrap();
+        assert_eq!(without_special, "What is Deep Learning?");
+        assert!(with_special.starts_with("<s>"));
+        assert!(with_special.ends_with("Deep Learning?</s>"));
+    }
+
+    #[test]
+    fn decode_without_decoder() {
+        let mut tokenizer = get_tokenizer();
+        tokenizer.with_decoder(None::<DecoderWrapper>);
+        let ids = tokenizer
+            .encode("What is Deep Learning?", true)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        let tokenization = Tokenization::builder(tokenizer, 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+
+        let unsupported = |result: Result<_, TextEmbeddingsError>| {
+            matches!(
+                result,
+                Err(TextEmbeddingsError::Validation(ValidationError::Other(message)))
+                    if message.contains("does not support decoding")
+            )
+        };
+        assert!(unsupported(futures::executor::block_on(
+            tokenization.decode(ids.clone(), true, None)
+        )));
+        assert!(unsupported(futures::executor::block_on(
+            tokenization.decode(ids.clone(), true, Some(true))
+        )));
+        assert!(unsupported(futures::executor::block_on(
+            tokenization.decode_both(ids.clone())
+        )));
+        assert!(unsupported(futures::executor::block_on(
+            tokenization.decode_tokens(ids.clone(), true)
+        )));
+        let pieces: Vec<_> =
+            futures::executor::block_on(tokenization.decode_stream(ids, true).collect());
+        assert_eq!(pieces.len(), 1);
+        assert!(unsupported(pieces.into_iter().next().unwrap()));
+
+        // Encoding does not need the decoder
+        assert!(futures::executor::block_on(tokenization.tokenize(
+            EncodingInput::from("What is Deep Learning?"),
+            true,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn count_tokens_batch() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let inputs = || {
+            vec![
+                EncodingInput::from("What is Deep Learning?"),
+                EncodingInput::from("Deep"),
+                EncodingInput::from(("query", "passage")),
+            ]
+        };
+
+        let counts =
+            futures::executor::block_on(tokenization.count_tokens_batch(inputs(), None)).unwrap();
+        let expected: Vec<_> = inputs()
+            .into_iter()
+            .map(|input| {
+                futures::executor::block_on(tokenization.count_tokens(input, None)).unwrap()
+            })
+            .collect();
+        assert_eq!(counts, expected);
+
+        assert!(matches!(
+            futures::executor::block_on(tokenization.count_tokens_batch(vec![], None)),
+            Err(TextEmbeddingsError::Validation(ValidationError::Empty))
+        ));
+    }
+
+    #[test]
+    fn encode_batch_duplicates() {
+        let inputs = || {
+            vec![
+                EncodingInput::from("query"),
+                EncodingInput::from("passage"),
+                EncodingInput::from("query"),
+                EncodingInput::from(("query", "passage")),
+                EncodingInput::from("passage"),
+            ]
+        };
+        let (unique, positions) = dedup_inputs(inputs());
+        assert_eq!(unique.len(), 3);
+        assert_eq!(positions, vec![0, 1, 0, 2, 1]);
+
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let (encodings, _) = futures::executor::block_on(tokenization.encode_batch(
+            inputs(),
+            false,
+            TruncationDirection::Right,
+            TruncationStrategy::LongestFirst,
+            None,
+            None,
+            false,
+            false,
+        ))
+        .unwrap();
+        // Every position gets the encoding of its input
+        assert_eq!(encodings.len(), 5);
+        assert_eq!(encodings[0].input_ids, encodings[2].input_ids);
+        assert_eq!(encodings[1].input_ids, encodings[4].input_ids);
+        assert_ne!(encodings[0].input_ids, encodings[1].input_ids);
+        assert!(encodings[3].token_type_ids.contains(&1));
+    }
+
+    #[test]
+    fn tokens_for_span() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .prompts(Some(HashMap::from([(
+                "query".to_string(),
+                "query: ".to_string(),
+            )])))
+            .metrics(false)
+            .build()
+            .unwrap();
+        let input = "What is Deep Learning?";
+        let span = |start, end, prompt_name: Option<&str>| {
+            futures::executor::block_on(tokenization.tokens_for_span(
+                input,
+                start,
+                end,
+                prompt_name.map(String::from),
+            ))
+        };
+        let ids = get_tokenizer()
+            .encode(input, true)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        let decode = |range: Range<usize>, prompt_tokens: usize| {
+            get_tokenizer()
+                .decode(
+                    &ids[range.start - prompt_tokens..range.end - prompt_tokens],
+                    true,
+                )
+                .unwrap()
+        };
+
+        // The special token at the start does not cover the first word
+        let what = span(0, 4, None).unwrap();
+        assert_eq!(what.start, 1);
+        assert_eq!(decode(what, 0), "What");
+
+        let deep = span(8, 12, None).unwrap();
+        assert_eq!(decode(deep.clone(), 0), "Deep");
+        // A span inside a token returns that token
+        assert_eq!(span(9, 10, None).unwrap(), deep);
+
+        // The prompt tokens shift the indices but not the span
+        let prompt_tokens = get_tokenizer()
+            .encode(format!("query: {input}"), true)
+            .unwrap()
+            .len()
+            - ids.len();
+        let deep_query = span(8, 12, Some("query")).unwrap();
+        assert_eq!(deep_query.start, deep.start + prompt_tokens);
+        assert_eq!(decode(deep_query, prompt_tokens), "Deep");
+
+        assert!(span(4, 4, None).is_err());
+        assert!(span(0, input.len() + 1, None).is_err());
+    }
+
+    #[test]
+    fn template_override() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let tokenize_encoding = |inputs: EncodingInput, template_override: Option<&str>| {
+            futures::executor::block_on(tokenization.tokenize(
                 inputs,
-                truncate,
-                truncation_direction,
-                prompt_name,
-                response_tx,
-                parent_span,
-            ) => {
-                parent_span.in_scope(|| {
-                    if !response_tx.is_closed() {
-                        let default_prompt_clone = match prompt_name {
-                            None => default_prompt.clone(),
-                            Some(_) => None,
-                        };
+                true,
+                None,
+                None,
+                template_override.map(String::from),
+                None,
+                None,
+            ))
+            .map(|tokenized| tokenized.encoding)
+        };
+        let tokenize = |template_override: Option<&str>| {
+            tokenize_encoding("What is Deep Learning?".into(), template_override)
+                .map(|encoding| encoding.get_tokens().to_vec())
+        };
 
-                        // It's possible that the user dropped its request resulting in a send error.
-                        // We just discard the error
-                        let _ = response_tx.send(encode_input(
-                            inputs,
-                            truncate,
-                            truncation_direction,
-                            max_input_length,
-                            position_offset,
-                            default_prompt_clone,
-                            prompt_name,
-                            prompts.as_ref(),
-                            &mut tokenizer,
-                        ));
-                    }
-                })
+        let tokens = tokenize(None).unwrap();
+        assert_eq!(tokens.first().unwrap(), "<s>");
+        assert_eq!(tokens.last().unwrap(), "</s>");
+
+        let overridden = tokenize(Some("$A:0 </s>:0")).unwrap();
+        assert_eq!(overridden[..], tokens[1..]);
+
+        // The override does not leak into the next requests of the worker
+        assert_eq!(tokenize(None).unwrap(), tokens);
+
+        assert!(tokenize(Some("[CLS] $A [SEP]")).is_err());
+        assert!(tokenize(Some("</s>")).is_err());
+
+        // Pair inputs repeat the special tokens following `$A` after `$B`
+        let tokenizer = get_tokenizer();
+        let query = tokenizer.encode("What is Deep Learning?", false).unwrap();
+        let passage = tokenizer
+            .encode("A subset of machine learning", false)
+            .unwrap();
+        let pair = tokenize_encoding(
+            EncodingInput::from(("What is Deep Learning?", "A subset of machine learning")),
+            Some("$A:0 </s>:0"),
+        )
+        .unwrap();
+        let expected: Vec<String> = query
+            .get_tokens()
+            .iter()
+            .chain(["</s>".to_string()].iter())
+            .chain(passage.get_tokens())
+            .chain(["</s>".to_string()].iter())
+            .cloned()
+            .collect();
+        assert_eq!(pair.get_tokens(), expected);
+        let n_first = query.len() + 1;
+        assert!(pair.get_type_ids()[..n_first]
+            .iter()
+            .all(|&type_id| type_id == 0));
+        assert!(pair.get_type_ids()[n_first..]
+            .iter()
+            .all(|&type_id| type_id == 1));
+    }
+
+    #[test]
+    fn decode_workers() {
+        /// Holds the encode worker until the test releases it
+        #[derive(Debug)]
+        struct Blocking(Arc<Barrier>);
+
+        impl Truncator for Blocking {
+            fn truncate(&self, encoding: RawEncoding, _max_len: usize) -> RawEncoding {
+                self.0.wait();
+                encoding
             }
-            TokenizerRequest::Tokenize(
-                inputs,
-                add_special_tokens,
-                prompt_name,
-                response_tx,
-                parent_span,
-            ) => {
-                parent_span.in_scope(|| {
-                    if !response_tx.is_closed() {
-                        let default_prompt_clone = match prompt_name {
-                            None => default_prompt.clone(),
-                            Some(_) => None,
-                        };
+        }
 
-                        // It's possible that the user dropped its request resulting in a send error.
-                        // We just discard the error
-                        let _ = response_tx.send(tokenize_input(
-                            inputs,
-                            add_special_tokens,
-                            max_input_length,
-                            None,
-                            default_prompt_clone,
-                            prompt_name,
-                            prompts.as_ref(),
-                            &mut tokenizer,
-                        ));
-                    }
-                })
+        let barrier = Arc::new(Barrier::new(2));
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 4)
+            .truncator(Box::new(Blocking(barrier.clone())))
+            .decode_workers(1)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let encoding = std::thread::spawn({
+            let tokenization = tokenization.clone();
+            move || {
+                tokenization.encode_blocking(
+                    EncodingInput::from("What is Deep Learning?"),
+                    EncodeOptions {
+                        overflow_policy: OverflowPolicy::Truncate,
+                        ..Default::default()
+                    },
+                )
             }
-            TokenizerRequest::Decode(ids, skip_special_tokens, response_tx, parent_span) => {
-                parent_span.in_scope(|| {
-                    if !response_tx.is_closed() {
-                        // It's possible that the user dropped its request resulting in a send error.
-                        // We just discard the error
-                        let _ =
-                            response_tx.send(decode_ids(ids, skip_special_tokens, &mut tokenizer));
-                    }
-                })
+        });
+
+        // The decode worker answers while the encode worker is busy
+        let ids = get_tokenizer()
+            .encode("What is Deep Learning?", true)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+        let decoded = futures::executor::block_on(tokenization.decode(ids, true, None)).unwrap();
+        assert_eq!(decoded, "What is Deep Learning?");
+
+        barrier.wait();
+        let _ = encoding.join();
+
+        // A panicked decode pool makes the tokenization unhealthy
+        assert!(tokenization.is_healthy());
+        tokenization.live_decode_workers.store(0, Ordering::Release);
+        assert!(!tokenization.is_healthy());
+        assert!(tokenization.shutdown(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn encode_timeout() {
+        /// Holds the worker on its first request until the test opens the gate
+        #[derive(Debug)]
+        struct Gated(Mutex<Option<std::sync::mpsc::Receiver<()>>>);
+
+        impl Truncator for Gated {
+            fn truncate(&self, mut encoding: RawEncoding, max_len: usize) -> RawEncoding {
+                let gate = self.0.lock().unwrap().take();
+                if let Some(gate) = gate {
+                    let _ = gate.recv();
+                }
+                encoding.truncate(max_len, 0, TruncationDirection::Right);
+                encoding
             }
         }
+
+        let (open, gate) = std::sync::mpsc::channel();
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 4)
+            .truncator(Box::new(Gated(Mutex::new(Some(gate)))))
+            .metrics(false)
+            .build()
+            .unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let encode = |timeout: Option<Duration>| {
+            runtime.block_on(tokenization.encode(
+                EncodingInput::from("What is Deep Learning?"),
+                EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    timeout,
+                    ..Default::default()
+                },
+            ))
+        };
+
+        assert!(matches!(
+            encode(Some(Duration::from_millis(50))),
+            Err(TextEmbeddingsError::Timeout)
+        ));
+
+        // The result of the timed out request is dropped and the worker keeps serving
+        open.send(()).unwrap();
+        let encoding = encode(Some(Duration::from_secs(10))).unwrap();
+        assert!(encoding.input_ids.len() <= 4);
+        assert!(tokenization.is_healthy());
+        assert!(encode(None).is_ok());
     }
-}
 
-fn decode_ids(
-    ids: Vec<u32>,
-    skip_special_tokens: bool,
-    tokenizer: &mut Tokenizer,
-) -> Result<String, TextEmbeddingsError> {
-    Ok(tokenizer
-        .with_truncation(None)?
-        .decode(&ids, skip_special_tokens)?)
-}
+    #[test]
+    fn shutdown_stops_clones() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let clone = tokenization.clone();
+        assert!(futures::executor::block_on(clone.decode(vec![0, 2], false, None)).is_ok());
 
-fn prepare_pre_prompt(
-    default_prompt: Option<String>,
-    prompt_name: Option<String>,
-    prompts: Option<&HashMap<String, String>>,
-) -> Result<Option<String>, TextEmbeddingsError> {
-    let pre_prompt = if let Some(prompt_name) = prompt_name.as_ref() {
-        match prompts {
-            None => {
-                return Err(TextEmbeddingsError::Validation(format!("`default-prompt-name` is set to `{prompt_name}` but no prompts were found in the Sentence Transformers configuration")));
-            }
-            Some(prompts) if !prompts.contains_key(prompt_name) => {
-                return Err(TextEmbeddingsError::Validation(format!("`default-prompt-name` is set to `{prompt_name}` but it was not found in the Sentence Transformers prompts. Available prompts: {:?}", prompts.keys())));
-            }
-            Some(prompts) => prompts.get(prompt_name).cloned(),
+        // The clone shares the workers that were stopped
+        assert!(tokenization.shutdown(Duration::from_secs(1)));
+        assert!(matches!(
+            futures::executor::block_on(clone.decode(vec![0, 2], false, None)),
+            Err(TextEmbeddingsError::WorkersGone)
+        ));
+    }
+
+    #[test]
+    fn decode_stream() {
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let ids = tokenizer
+            .encode::<&str>("这是一个文本向量化的测试句子 and some text", true, None)
+            .unwrap()
+            .get_ids()
+            .to_vec();
+
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        decode_stream_ids(&ids, true, &tokenizer, &response_tx);
+        drop(response_tx);
+
+        let mut pieces = Vec::new();
+        while let Ok(piece) = response_rx.try_recv() {
+            pieces.push(piece.unwrap());
         }
-    } else {
-        default_prompt
-    };
-    Ok(pre_prompt)
-}
+        assert!(pieces.len() > 1);
+        assert_eq!(pieces.concat(), tokenizer.decode(&ids, true).unwrap());
+    }
 
-#[allow(clippy::too_many_arguments)]
-fn tokenize_input(
-    mut inputs: EncodingInput,
-    add_special_tokens: bool,
-    max_input_length: usize,
-    truncate_params: Option<TruncationParams>,
-    default_prompt: Option<String>,
-    prompt_name: Option<String>,
-    prompts: Option<&HashMap<String, String>>,
-    tokenizer: &mut Tokenizer,
-) -> Result<(Option<String>, RawEncoding), TextEmbeddingsError> {
-    let pre_prompt = prepare_pre_prompt(default_prompt, prompt_name, prompts)?;
+    #[test]
+    fn empty_prompt() {
+        let prompts = HashMap::from([
+            ("query".to_string(), "query: ".to_string()),
+            ("passage".to_string(), String::new()),
+        ]);
 
-    let input_chars = inputs.count_chars();
-    let limit = max_input_length * MAX_CHAR_MULTIPLIER;
-    if input_chars > limit {
-        if truncate_params.is_none() {
-            return Err(TextEmbeddingsError::Validation(format!(
-                "`inputs` must have less than {limit} characters. Given: {input_chars}"
-            )));
+        // An empty prompt resolves to no change of the input
+        let prompt = prepare_pre_prompt(
+            None,
+            Some("passage".to_string()),
+            None,
+            Some(&prompts),
+            true,
+            false,
+        )
+        .unwrap();
+        assert_eq!(prompt.as_deref(), Some(""));
+        assert_eq!(
+            apply_prompt(prompt, "What is Deep Learning?".to_string()),
+            "What is Deep Learning?"
+        );
+
+        assert!(prepare_pre_prompt(
+            None,
+            Some("doc".to_string()),
+            None,
+            Some(&prompts),
+            true,
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn prompt_text_precedence() {
+        let prompts = HashMap::from([("query".to_string(), "query: ".to_string())]);
+        let resolve = |prompt_name: Option<&str>, prompt_text: Option<&str>| {
+            prepare_pre_prompt(
+                Some("default: ".to_string()),
+                prompt_name.map(str::to_string),
+                prompt_text.map(str::to_string),
+                Some(&prompts),
+                true,
+                false,
+            )
+            .unwrap()
+        };
+
+        // An explicit text wins over the name, which wins over the default
+        assert_eq!(
+            resolve(Some("query"), Some("Represent this question: ")).as_deref(),
+            Some("Represent this question: ")
+        );
+        assert_eq!(resolve(Some("query"), None).as_deref(), Some("query: "));
+        assert_eq!(resolve(None, None).as_deref(), Some("default: "));
+        // The name is not looked up when a text is given
+        assert_eq!(
+            resolve(Some("passage"), Some("passage: ")).as_deref(),
+            Some("passage: ")
+        );
+
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        let tokenized = tokenize_with_prompt(
+            EncodingInput::from("What is Deep Learning?"),
+            true,
+            512,
+            MAX_CHAR_MULTIPLIER,
+            Some("default: ".to_string()),
+            Some("query".to_string()),
+            Some("Represent this question: ".to_string()),
+            Some(&prompts),
+            &tokenizer,
+        )
+        .unwrap();
+        assert_eq!(
+            tokenized.full_text.as_deref(),
+            Some("Represent this question: What is Deep Learning?")
+        );
+    }
+
+    #[test]
+    fn multiple_prompt_names() {
+        let prompts = HashMap::from([
+            ("en".to_string(), "[EN] ".to_string()),
+            ("query".to_string(), "query: ".to_string()),
+            ("template".to_string(), "<q>{text}</q>".to_string()),
+        ]);
+        let resolve = |prompt_name: &str| {
+            prepare_pre_prompt(
+                None,
+                Some(prompt_name.to_string()),
+                None,
+                Some(&prompts),
+                true,
+                false,
+            )
+        };
+
+        assert_eq!(
+            resolve("en, query").unwrap().as_deref(),
+            Some("[EN] query: ")
+        );
+        assert_eq!(
+            resolve("en,template").unwrap().as_deref(),
+            Some("[EN] <q>{text}</q>")
+        );
+        assert!(resolve("template,template").is_err());
+
+        // Names holding a comma resolve as is or escaped
+        let prompts = HashMap::from([
+            ("en".to_string(), "[EN] ".to_string()),
+            ("query, short".to_string(), "q: ".to_string()),
+        ]);
+        let resolve = |prompt_name: &str| {
+            prepare_pre_prompt(
+                None,
+                Some(prompt_name.to_string()),
+                None,
+                Some(&prompts),
+                true,
+                false,
+            )
+        };
+        assert_eq!(resolve("query, short").unwrap().as_deref(), Some("q: "));
+        assert_eq!(
+            resolve(r"en, query\, short").unwrap().as_deref(),
+            Some("[EN] q: ")
+        );
+        assert_eq!(split_prompt_names(r"a\\b, c\,d ,"), vec![r"a\b", "c,d", ""]);
+
+        // The error names the first missing prompt
+        match resolve("en,passage,doc") {
+            Err(TextEmbeddingsError::Validation(ValidationError::UnknownPrompt {
+                name, ..
+            })) => assert_eq!(name, "passage"),
+            _ => panic!("expected an unknown prompt error"),
         }
-        inputs.apply_limit(limit);
     }
 
-    let encoding = match inputs {
-        // encode input
-        EncodingInput::Single(s) => {
-            let s = if let Some(mut pre_prompt) = pre_prompt {
-                pre_prompt.push_str(&s);
-                pre_prompt
-            } else {
-                s
-            };
+    #[test]
+    fn unknown_prompt_suggestion() {
+        let prompts = HashMap::from([
+            ("query".to_string(), "query: ".to_string()),
+            ("passage".to_string(), "passage: ".to_string()),
+            ("classification".to_string(), "Classify: ".to_string()),
+        ]);
+        let error = |prompt_name: &str| match prepare_pre_prompt(
+            None,
+            Some(prompt_name.to_string()),
+            None,
+            Some(&prompts),
+            false,
+            false,
+        ) {
+            Err(TextEmbeddingsError::Validation(error @ ValidationError::UnknownPrompt { .. })) => {
+                error
+            }
+            _ => panic!("expected an unknown prompt error"),
+        };
+
+        assert_eq!(edit_distance("query", "qeury"), 2);
+        assert_eq!(edit_distance("", "doc"), 3);
+        assert_eq!(edit_distance("passage", "passages"), 1);
+
+        let typo = error("quey");
+        assert!(matches!(
+            &typo,
+            ValidationError::UnknownPrompt { suggestion: Some(suggestion), .. } if suggestion == "query"
+        ));
+        assert!(typo.to_string().ends_with("Did you mean `query`?"));
+        assert!(!typo.to_string().contains("passage"));
+
+        let unrelated = error("summarization");
+        assert!(matches!(
+            &unrelated,
+            ValidationError::UnknownPrompt {
+                suggestion: None,
+                available: Some(available),
+                ..
+            } if available.len() == 3
+        ));
+        assert!(unrelated.to_string().contains("Available prompts"));
+    }
+
+    #[test]
+    fn prompt_template() {
+        let text = "What is Deep Learning?".to_string();
+        assert_eq!(apply_prompt(None, text.clone()), text);
+        assert_eq!(
+            apply_prompt(Some("query: ".to_string()), text.clone()),
+            "query: What is Deep Learning?"
+        );
+        assert_eq!(
+            apply_prompt(Some("<query>{text}</query>".to_string()), text.clone()),
+            "<query>What is Deep Learning?</query>"
+        );
+        assert_eq!(
+            apply_prompt(
+                Some("{text} Represent this question.".to_string()),
+                text.clone()
+            ),
+            "What is Deep Learning? Represent this question."
+        );
+        // A literal `{}` stays in the prompt
+        assert_eq!(
+            apply_prompt(Some("Answer in {} format: ".to_string()), text.clone()),
+            "Answer in {} format: What is Deep Learning?"
+        );
+        assert_eq!(
+            apply_prompt(
+                Some("Instruct: Retrieve passages\nQuery: {text}".to_string()),
+                text
+            ),
+            "Instruct: Retrieve passages\nQuery: What is Deep Learning?"
+        );
+
+        assert!(validate_prompt("query: ").is_ok());
+        assert!(validate_prompt("query: {text}").is_ok());
+        assert!(validate_prompt("{text} and {}").is_ok());
+        assert!(validate_prompt("{text} and {text}").is_err());
+    }
+
+    #[test]
+    fn pack_encodings_budget() {
+        let encoding = |seq_len: u32| ValidEncoding {
+            input_ids: (0..seq_len).collect(),
+            token_type_ids: vec![0; seq_len as usize],
+            position_ids: (0..seq_len).collect(),
+            attention_mask: vec![1; seq_len as usize],
+            truncated: false,
+            offsets: None,
+            special_tokens_mask: None,
+            applied_prompt: None,
+            chunks: Vec::new(),
+        };
 
-            let encoding = tokenizer
-                .with_truncation(truncate_params)?
-                .encode::<&str>(&s, add_special_tokens)?;
+        // Inputs that do not fit are deferred, smaller ones after them are still packed
+        let (packed, deferred) =
+            pack_encodings(vec![encoding(4), encoding(8), encoding(3), encoding(1)], 8);
+        let lengths: Vec<usize> = packed.iter().map(|e| e.input_ids.len()).collect();
+        assert_eq!(lengths, vec![4, 3, 1]);
+        assert_eq!(deferred, vec![1]);
+    }
 
-            (Some(s), encoding)
+    #[test]
+    fn builder_validation() {
+        for builder in [
+            Tokenization::builder(get_tokenizer(), 0, 512),
+            Tokenization::builder(get_tokenizer(), 1, 0),
+            Tokenization::builder(get_tokenizer(), 1, 512).max_char_multiplier(0),
+            Tokenization::builder(get_tokenizer(), 1, 512).batch_parallelism(0),
+            Tokenization::builder(get_tokenizer(), 1, 512).queue_capacity(0),
+            Tokenization::builder(get_tokenizer(), 1, 512)
+                .default_prompt(Some("{text} and {text}".to_string())),
+        ] {
+            assert!(matches!(
+                builder.metrics(false).build(),
+                Err(TextEmbeddingsError::Validation(_))
+            ));
         }
-        EncodingInput::Dual(s1, s2) => {
-            if pre_prompt.is_some() {
-                return Err(TextEmbeddingsError::Validation(
-                    "`prompt_name` cannot be set with dual inputs".to_string(),
-                ));
+    }
+
+    #[test]
+    fn set_max_input_length() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 2, 512)
+            .cache_capacity(8)
+            .metrics(false)
+            .build()
+            .unwrap();
+        let encode = |overflow_policy| {
+            futures::executor::block_on(tokenization.encode(
+                EncodingInput::from("What is Deep Learning?"),
+                EncodeOptions {
+                    overflow_policy,
+                    ..Default::default()
+                },
+            ))
+        };
+        let n_tokens = encode(OverflowPolicy::Truncate).unwrap().input_ids.len();
+        assert!(n_tokens > 4);
+
+        tokenization.set_max_input_length(4).unwrap();
+        // The cached encoding computed with the previous limit is not returned
+        assert_eq!(encode(OverflowPolicy::Truncate).unwrap().input_ids.len(), 4);
+        assert!(encode(OverflowPolicy::Error).is_err());
+
+        tokenization.set_max_input_length(512).unwrap();
+        assert_eq!(
+            encode(OverflowPolicy::Error).unwrap().input_ids.len(),
+            n_tokens
+        );
+
+        assert!(tokenization.set_max_input_length(0).is_err());
+    }
+
+    #[test]
+    fn build_warms_up() {
+        // The warm-up input is cut to the char limit instead of failing the build
+        let tokenization = Tokenization::builder(get_tokenizer(), 2, 2)
+            .max_char_multiplier(4)
+            .default_prompt(Some("query: ".to_string()))
+            .metrics(false)
+            .build()
+            .unwrap();
+        let count =
+            futures::executor::block_on(tokenization.count_tokens("Warm".into(), None)).unwrap();
+        assert!(count > 0);
+
+        let tokenizer = SharedTokenizer::new(get_tokenizer());
+        warm_up(&tokenizer, 512, MAX_CHAR_MULTIPLIER, None, None).unwrap();
+    }
+
+    #[test]
+    fn encode_blocking() {
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 512)
+            .metrics(false)
+            .build()
+            .unwrap();
+
+        let encoding = tokenization
+            .encode_blocking(
+                EncodingInput::from("What is Deep Learning?"),
+                EncodeOptions::default(),
+            )
+            .unwrap();
+        let expected = get_tokenizer()
+            .encode("What is Deep Learning?", true)
+            .unwrap();
+        assert_eq!(encoding.input_ids, expected.get_ids());
+
+        assert!(tokenization.shutdown(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn worker_panic() {
+        #[derive(Debug)]
+        struct Panicking;
+
+        impl Truncator for Panicking {
+            fn truncate(&self, _encoding: RawEncoding, _max_len: usize) -> RawEncoding {
+                panic!("truncator bug");
             }
+        }
 
-            (
-                None,
-                tokenizer
-                    .with_truncation(truncate_params)?
-                    .encode::<(String, String)>((s1, s2), add_special_tokens)?,
+        let tokenization = Tokenization::builder(get_tokenizer(), 1, 4)
+            .truncator(Box::new(Panicking))
+            .metrics(false)
+            .build()
+            .unwrap();
+        assert!(tokenization.is_healthy());
+
+        let encode = |text: &str| {
+            tokenization.encode_blocking(
+                EncodingInput::from(text),
+                EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    ..Default::default()
+                },
             )
+        };
+        assert!(matches!(
+            encode("What is Deep Learning?"),
+            Err(TextEmbeddingsError::WorkersGone)
+        ));
+
+        // The only worker is gone
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while tokenization.is_healthy() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
         }
-        // input is encoded -> convert to tokenizers Encoding
-        EncodingInput::Ids(ids) => {
-            if let Some(mut pre_prompt) = pre_prompt {
-                let text = tokenizer.decode(&ids, true)?;
-                pre_prompt.push_str(&text);
+        assert!(!tokenization.is_healthy());
+        assert!(encode("Deep").is_err());
+    }
+
+    #[test]
+    fn unicode_normalization() {
+        let mut input = EncodingInput::from(("ＡＢＣ １２３", "ﬁ"));
+        input.normalize(UnicodeNormalization::Nfkc);
+        let EncodingInput::Dual(s1, s2) = input else {
+            unreachable!()
+        };
+        assert_eq!(s1, "ABC 123");
+        assert_eq!(s2, "fi");
+
+        // Canonical forms keep compatibility characters
+        assert_eq!(UnicodeNormalization::Nfc.apply("ＡＢＣ"), "ＡＢＣ");
+        assert_eq!(UnicodeNormalization::Nfc.apply("e\u{301}"), "\u{e9}");
+        assert_eq!(UnicodeNormalization::Nfd.apply("\u{e9}"), "e\u{301}");
+    }
+
+    #[test]
+    fn encoding_input_summary() {
+        assert_eq!(
+            EncodingInput::from("这是一个文本").summary(2),
+            "\"这是...\""
+        );
+        assert_eq!(EncodingInput::from("short").summary(10), "\"short\"");
+        assert_eq!(
+            EncodingInput::from(("query", "passage")).summary(3),
+            "(\"que...\", \"pas...\")"
+        );
+        assert_eq!(EncodingInput::from(vec![1, 2, 3]).summary(1), "3 ids");
+    }
 
-                let encoding = tokenizer
-                    .with_truncation(truncate_params)?
-                    .encode::<&str>(&pre_prompt, true)?;
+    #[test]
+    fn encoding_cache() {
+        let key = |prompt_name: Option<&str>| {
+            encoding_cache_key(
+                &EncodingInput::from("What is Deep Learning?"),
+                &EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    prompt_name: prompt_name.map(str::to_string),
+                    ..Default::default()
+                },
+            )
+        };
+        let encoding = ValidEncoding {
+            input_ids: vec![0, 1, 2],
+            token_type_ids: vec![0, 0, 0],
+            position_ids: vec![0, 1, 2],
+            attention_mask: vec![1, 1, 1],
+            truncated: false,
+            offsets: None,
+            special_tokens_mask: None,
+            applied_prompt: None,
+            chunks: Vec::new(),
+        };
 
-                (Some(pre_prompt), encoding)
-            } else {
-                let text = tokenizer.decode(&ids, false)?;
+        let mut cache = EncodingCache::new(2);
+        cache.insert(key(Some("query")), encoding);
 
-                let encoding = tokenizer
-                    .with_truncation(truncate_params)?
-                    .encode::<&str>(&text, false)?;
+        // Identical inputs hit the cache
+        let cached = cache.get(&key(Some("query"))).unwrap();
+        assert_eq!(cached.input_ids, vec![0, 1, 2]);
 
-                (Some(text), encoding)
-            }
-        }
-    };
-    Ok(encoding)
-}
+        // A different prompt misses
+        assert!(cache.get(&key(Some("passage"))).is_none());
+        assert!(cache.get(&key(None)).is_none());
 
-/// Get input length and optionally truncate it
-#[allow(clippy::too_many_arguments)]
-fn encode_input(
-    inputs: EncodingInput,
-    truncate: bool,
-    truncation_direction: TruncationDirection,
-    max_input_length: usize,
-    position_offset: usize,
-    default_prompt: Option<String>,
-    prompt_name: Option<String>,
-    prompts: Option<&HashMap<String, String>>,
-    tokenizer: &mut Tokenizer,
-) -> Result<ValidEncoding, TextEmbeddingsError> {
-    // Default truncation params
-    let truncate_params = truncate.then_some(TruncationParams {
-        direction: truncation_direction,
-        max_length: max_input_length,
-        strategy: TruncationStrategy::LongestFirst,
-        stride: 0,
-    });
+        // The least recently used entry is evicted when the cache is full
+        cache.insert(key(None), cached.clone());
+        assert!(cache.get(&key(Some("query"))).is_some());
+        cache.insert(key(Some("passage")), cached.clone());
+        assert!(cache.get(&key(None)).is_none());
+        assert!(cache.get(&key(Some("query"))).is_some());
+        assert!(cache.get(&key(Some("passage"))).is_some());
+        assert_eq!(cache.entries.len(), cache.recency.len());
 
-    let (_, encoding) = tokenize_input(
-        inputs,
-        true,
-        max_input_length,
-        truncate_params,
-        default_prompt,
-        prompt_name,
-        prompts,
-        tokenizer,
-    )?;
-    let seq_len = encoding.len();
+        // Replacing an entry does not evict another one
+        cache.insert(key(Some("query")), cached);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.recency.len(), 2);
 
-    if seq_len > max_input_length {
-        return Err(TextEmbeddingsError::Validation(format!(
-            "`inputs` must have less than {max_input_length} tokens. Given: {seq_len}"
-        )));
+        // Clearing starts a new generation
+        cache.clear();
+        assert!(cache.get(&key(Some("query"))).is_none());
+        assert_eq!(cache.generation, 1);
     }
-    let histogram = metrics::histogram!("te_request_input_length");
-    histogram.record(seq_len as f64);
-    Ok(ValidEncoding {
-        input_ids: encoding.get_ids().to_vec(),
-        token_type_ids: encoding.get_type_ids().to_vec(),
-        position_ids: (position_offset as u32..(seq_len + position_offset) as u32)
-            .collect::<Vec<_>>(),
-    })
-}
 
-#[derive(Debug)]
-pub struct ValidEncoding {
-    pub input_ids: Vec<u32>,
-    pub token_type_ids: Vec<u32>,
-    pub position_ids: Vec<u32>,
-}
+    #[test]
+    fn validate_metrics() {
+        /// Collects the names of the histograms
+        #[derive(Default)]
+        struct Histograms(Mutex<Vec<String>>);
 
-#[derive(Debug)]
-pub enum EncodingInput {
-    Single(String),
-    Dual(String, String),
-    Ids(Vec<u32>),
-}
+        impl metrics::Recorder for Histograms {
+            fn describe_counter(
+                &self,
+                _: metrics::KeyName,
+                _: Option<metrics::Unit>,
+                _: metrics::SharedString,
+            ) {
+            }
 
-impl EncodingInput {
-    fn is_empty(&self) -> bool {
-        match self {
-            EncodingInput::Single(s) => s.is_empty(),
-            EncodingInput::Dual(s1, s2) => s1.is_empty() && s2.is_empty(),
-            EncodingInput::Ids(v) => v.is_empty(),
-        }
-    }
+            fn describe_gauge(
+                &self,
+                _: metrics::KeyName,
+                _: Option<metrics::Unit>,
+                _: metrics::SharedString,
+            ) {
+            }
 
-    fn count_chars(&self) -> usize {
-        match self {
-            EncodingInput::Single(s) => s.chars().count(),
-            EncodingInput::Dual(s1, s2) => s1.chars().count() + s2.chars().count(),
-            EncodingInput::Ids(v) => v.len(),
-        }
-    }
+            fn describe_histogram(
+                &self,
+                _: metrics::KeyName,
+                _: Option<metrics::Unit>,
+                _: metrics::SharedString,
+            ) {
+            }
 
-    fn apply_limit(&mut self, limit: usize) {
-        let truncate_string = |s: &mut String, limit: usize| {
-            if s.is_char_boundary(limit) {
-                s.truncate(limit)
+            fn register_counter(
+                &self,
+                _: &metrics::Key,
+                _: &metrics::Metadata<'_>,
+            ) -> metrics::Counter {
+                metrics::Counter::noop()
             }
-        };
 
-        match self {
-            EncodingInput::Single(s) => {
-                truncate_string(s, limit);
+            fn register_gauge(
+                &self,
+                _: &metrics::Key,
+                _: &metrics::Metadata<'_>,
+            ) -> metrics::Gauge {
+                metrics::Gauge::noop()
             }
-            EncodingInput::Dual(s1, s2) => {
-                truncate_string(s1, limit / 2);
-                truncate_string(s2, limit / 2);
+
+            fn register_histogram(
+                &self,
+                key: &metrics::Key,
+                _: &metrics::Metadata<'_>,
+            ) -> metrics::Histogram {
+                self.0.lock().unwrap().push(key.name().to_string());
+                metrics::Histogram::noop()
             }
-            EncodingInput::Ids(_) => {}
         }
-    }
-}
 
-impl From<String> for EncodingInput {
-    fn from(value: String) -> Self {
-        Self::Single(value)
-    }
-}
+        let (sender, receiver) = async_channel::bounded(1);
+        let (response_tx, response_rx) = oneshot::channel();
+        sender
+            .send_blocking(TokenizerRequest::Validate(
+                EncodingInput::from("What is Deep Learning?"),
+                false,
+                TruncationDirection::Right,
+                None,
+                response_tx,
+                Span::none(),
+            ))
+            .unwrap();
+        drop(sender);
 
-impl From<(String, String)> for EncodingInput {
-    fn from(value: (String, String)) -> Self {
-        Self::Dual(value.0, value.1)
+        // The worker runs on this thread until the channel is empty
+        let histograms = Histograms::default();
+        metrics::with_local_recorder(&histograms, || {
+            tokenizer_worker(
+                Arc::new(SharedTokenizer::new(get_tokenizer())),
+                None,
+                Arc::new(AtomicUsize::new(512)),
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                receiver,
+                "0".to_string(),
+                &RefCell::new(Span::none()),
+            )
+        });
+        assert!(futures::executor::block_on(response_rx).unwrap().is_ok());
+        let histograms = histograms.0.into_inner().unwrap();
+        assert!(histograms.contains(&"te_tokenization_duration_seconds".to_string()));
+        assert!(!histograms.contains(&"te_request_input_length".to_string()));
     }
-}
 
-enum TokenizerRequest {
-    Encode(
-        EncodingInput,
-        bool,
-        TruncationDirection,
-        Option<String>,
-        oneshot::Sender<Result<ValidEncoding, TextEmbeddingsError>>,
-        Span,
-    ),
-    Tokenize(
-        EncodingInput,
-        bool,
-        Option<String>,
-        oneshot::Sender<Result<(Option<String>, RawEncoding), TextEmbeddingsError>>,
-        Span,
-    ),
-    Decode(
-        Vec<u32>,
-        bool,
-        oneshot::Sender<Result<String, TextEmbeddingsError>>,
-        Span,
-    ),
-}
+    #[test]
+    fn request_id_span() {
+        /// Collects the `request_id` field of the new spans
+        #[derive(Clone, Default)]
+        struct RequestIds(Arc<Mutex<Vec<u64>>>);
 
-pub fn into_tokens(encoding: tokenizers::Encoding, input: &str) -> Vec<SimpleToken> {
-    encoding
-        .get_ids()
-        .iter()
-        .zip(encoding.get_offsets())
-        .zip(encoding.get_special_tokens_mask())
-        .zip(encoding.get_tokens())
-        .map(|(((&id, &(start, stop)), special), token)| {
-            let special = *special == 1;
-            match special {
-                true => SimpleToken {
-                    id,
-                    text: token.clone(),
-                    special,
-                    start: None,
-                    stop: None,
-                },
-                false => {
-                    let text: Vec<u8> = input.bytes().skip(start).take(stop - start).collect();
-                    let text: String = String::from_utf8_lossy(&text).to_string();
-                    SimpleToken {
-                        id,
-                        text,
-                        special,
-                        start: Some(start),
-                        stop: Some(stop),
-                    }
+        impl tracing::field::Visit for RequestIds {
+            fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+                if field.name() == "request_id" {
+                    self.0.lock().unwrap().push(value);
                 }
             }
-        })
-        .collect()
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use hf_hub::api::sync::ApiBuilder;
+            fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn fmt::Debug) {}
+        }
 
-    #[test]
-    fn tokenizer() {
-        let api = ApiBuilder::from_env().build().unwrap();
-        let filename = api
-            .model("BAAI/bge-m3".to_string())
-            .get("tokenizer.json")
-            .unwrap();
-        let string = "这是一个文本向量化的测试句子";
-        let tokenizer = Tokenizer::from_file(filename).unwrap();
+        impl tracing::Subscriber for RequestIds {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
 
-        let encoded = tokenizer.encode(string, true).unwrap();
-        assert_eq!(
-            encoded.get_offsets(),
-            vec![
-                (0, 0),
-                (0, 3),
-                (0, 12),
-                (12, 18),
-                (18, 21),
-                (21, 24),
-                (24, 30),
-                (30, 36),
-                (36, 39),
-                (39, 42),
-                (0, 0)
-            ]
-        );
+            fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                span.record(&mut self.clone());
+                tracing::span::Id::from_u64(1)
+            }
 
-        let tokens = into_tokens(encoded, &string);
-        assert_eq!(
-            tokens,
-            vec![
-                SimpleToken {
-                    id: 0,
-                    text: "<s>".to_string(),
-                    special: true,
-                    start: None,
-                    stop: None
-                },
-                SimpleToken {
-                    id: 6,
-                    text: "这".to_string(),
-                    special: false,
-                    start: Some(0),
-                    stop: Some(3)
-                },
-                SimpleToken {
-                    id: 100013,
-                    text: "这是一个".to_string(),
-                    special: false,
-                    start: Some(0),
-                    stop: Some(12)
-                },
-                SimpleToken {
-                    id: 189061,
-                    text: "文本".to_string(),
-                    special: false,
-                    start: Some(12),
-                    stop: Some(18)
-                },
-                SimpleToken {
-                    id: 2110,
-                    text: "向".to_string(),
-                    special: false,
-                    start: Some(18),
-                    stop: Some(21)
-                },
-                SimpleToken {
-                    id: 3272,
-                    text: "量".to_string(),
-                    special: false,
-                    start: Some(21),
-                    stop: Some(24)
-                },
-                SimpleToken {
-                    id: 41904,
-                    text: "化的".to_string(),
-                    special: false,
-                    start: Some(24),
-                    stop: Some(30)
-                },
-                SimpleToken {
-                    id: 49125,
-                    text: "测试".to_string(),
-                    special: false,
-                    start: Some(30),
-                    stop: Some(36)
-                },
-                SimpleToken {
-                    id: 27683,
-                    text: "句".to_string(),
-                    special: false,
-                    start: Some(36),
-                    stop: Some(39)
-                },
-                SimpleToken {
-                    id: 1344,
-                    text: "子".to_string(),
-                    special: false,
-                    start: Some(39),
-                    stop: Some(42)
+            fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+            fn event(&self, _: &tracing::Event<'_>) {}
+
+            fn enter(&self, _: &tracing::span::Id) {}
+
+            fn exit(&self, _: &tracing::span::Id) {}
+        }
+
+        let request_id = RequestId::next();
+        assert_ne!(RequestId::next(), request_id);
+
+        let (sender, receiver) = async_channel::bounded(1);
+        let (response_tx, response_rx) = oneshot::channel();
+        sender
+            .send_blocking(TokenizerRequest::Encode(
+                EncodingInput::from("What is Deep Learning?"),
+                EncodeOptions {
+                    overflow_policy: OverflowPolicy::Truncate,
+                    request_id: Some(request_id),
+                    ..Default::default()
                 },
-                SimpleToken {
-                    id: 2,
-                    text: "</s>".to_string(),
-                    special: true,
-                    start: None,
-                    stop: None
-                }
-            ]
-        );
+                response_tx,
+                Span::none(),
+            ))
+            .unwrap();
+        drop(sender);
+
+        // The worker runs on this thread until the channel is empty
+        let request_ids = RequestIds::default();
+        tracing::subscriber::with_default(request_ids.clone(), || {
+            tokenizer_worker(
+                Arc::new(SharedTokenizer {
+                    metrics: false,
+                    ..SharedTokenizer::new(get_tokenizer())
+                }),
+                None,
+                Arc::new(AtomicUsize::new(512)),
+                MAX_CHAR_MULTIPLIER,
+                0,
+                None,
+                None,
+                receiver,
+                "0".to_string(),
+                &RefCell::new(Span::none()),
+            )
+        });
+        assert!(futures::executor::block_on(response_rx).unwrap().is_ok());
+        assert_eq!(*request_ids.0.lock().unwrap(), vec![request_id.0]);
+    }
+
+    #[test]
+    fn load_prompts_from_st_config() {
+        let dir = std::env::temp_dir().join(format!("tei-st-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config_sentence_transformers.json");
+
+        std::fs::write(
+            &path,
+            r#"{
+                "__version__": {"sentence_transformers": "3.0.1"},
+                "prompts": {"query": "query: ", "passage": ""},
+                "default_prompt_name": "query",
+                "similarity_fn_name": "cosine"
+            }"#,
+        )
+        .unwrap();
+        let (default_prompt_name, prompts) =
+            Tokenization::load_prompts_from_st_config(&path).unwrap();
+        assert_eq!(default_prompt_name.as_deref(), Some("query"));
+        assert_eq!(prompts.len(), 2);
+        assert_eq!(prompts["query"], "query: ");
+        assert_eq!(prompts["passage"], "");
+
+        // Older configs have neither field
+        std::fs::write(&path, r#"{"default_prompt_name": null}"#).unwrap();
+        let (default_prompt_name, prompts) =
+            Tokenization::load_prompts_from_st_config(&path).unwrap();
+        assert!(default_prompt_name.is_none());
+        assert!(prompts.is_empty());
+
+        std::fs::write(&path, r#"{"prompts": {"query": 1}}"#).unwrap();
+        assert!(Tokenization::load_prompts_from_st_config(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Tokenization::load_prompts_from_st_config(&path).is_err());
     }
 }
@@ -16,7 +16,7 @@ use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use text_embeddings_core::infer::Infer;
 use text_embeddings_core::tokenization::{
-    into_tokens, EncodingInput, SimpleToken as CoreSimpleToken,
+    into_tokens, EncodingInput, InvalidUtf8Policy, RequestId, SimpleToken as CoreSimpleToken,
 };
 use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
 use tokio_stream::wrappers::UnboundedReceiverStream;
@@ -65,6 +65,7 @@ impl TextEmbeddingsService {
     #[instrument(
         skip_all,
         fields(
+            request_id,
             compute_chars,
             compute_tokens,
             total_time,
@@ -79,6 +80,8 @@ impl TextEmbeddingsService {
         permit: OwnedSemaphorePermit,
     ) -> Result<(EmbedResponse, ResponseMetadata), Status> {
         let span = Span::current();
+        let request_id = RequestId::next();
+        span.record("request_id", request_id.0);
         let start_time = Instant::now();
 
         let compute_chars = request.inputs.chars().count();
@@ -91,6 +94,7 @@ impl TextEmbeddingsService {
                 truncation_direction,
                 request.prompt_name,
                 request.normalize,
+                Some(request_id),
                 permit,
             )
             .await
@@ -121,6 +125,7 @@ impl TextEmbeddingsService {
     #[instrument(
         skip_all,
         fields(
+            request_id,
             compute_chars,
             compute_tokens,
             total_time,
@@ -135,6 +140,8 @@ impl TextEmbeddingsService {
         permit: OwnedSemaphorePermit,
     ) -> Result<(EmbedSparseResponse, ResponseMetadata), Status> {
         let span = Span::current();
+        let request_id = RequestId::next();
+        span.record("request_id", request_id.0);
         let start_time = Instant::now();
 
         let compute_chars = request.inputs.chars().count();
@@ -146,6 +153,7 @@ impl TextEmbeddingsService {
                 request.truncate,
                 truncation_direction,
                 request.prompt_name,
+                Some(request_id),
                 permit,
             )
             .await
@@ -187,6 +195,7 @@ impl TextEmbeddingsService {
     #[instrument(
         skip_all,
         fields(
+            request_id,
             compute_chars,
             compute_tokens,
             total_time,
@@ -201,6 +210,8 @@ impl TextEmbeddingsService {
         permit: OwnedSemaphorePermit,
     ) -> Result<(EmbedAllResponse, ResponseMetadata), Status> {
         let span = Span::current();
+        let request_id = RequestId::next();
+        span.record("request_id", request_id.0);
         let start_time = Instant::now();
 
         let compute_chars = request.inputs.chars().count();
@@ -212,6 +223,7 @@ impl TextEmbeddingsService {
                 request.truncate,
                 truncation_direction,
                 request.prompt_name,
+                Some(request_id),
                 permit,
             )
             .await
@@ -248,6 +260,7 @@ impl TextEmbeddingsService {
     #[instrument(
         skip_all,
         fields(
+            request_id,
             compute_chars,
             compute_tokens,
             total_time,
@@ -265,18 +278,27 @@ impl TextEmbeddingsService {
         permit: OwnedSemaphorePermit,
     ) -> Result<(PredictResponse, ResponseMetadata), Status> {
         let span = Span::current();
+        let request_id = RequestId::next();
+        span.record("request_id", request_id.0);
         let start_time = Instant::now();
 
         let inputs = inputs.into();
         let compute_chars = match &inputs {
             EncodingInput::Single(s) => s.chars().count(),
             EncodingInput::Dual(s1, s2) => s1.chars().count() + s2.chars().count(),
-            EncodingInput::Ids(_) => unreachable!(),
+            EncodingInput::Ids(_) | EncodingInput::PreTokenized { .. } => unreachable!(),
         };
 
         let response = self
             .infer
-            .predict(inputs, truncate, truncation_direction, raw_scores, permit)
+            .predict(
+                inputs,
+                truncate,
+                truncation_direction,
+                raw_scores,
+                Some(request_id),
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
@@ -328,39 +350,53 @@ impl TextEmbeddingsService {
         ))
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(request_id))]
     async fn tokenize_inner(&self, request: EncodeRequest) -> Result<EncodeResponse, Status> {
+        let request_id = RequestId::next();
+        Span::current().record("request_id", request_id.0);
+
         let inputs = request.inputs;
-        let (encoded_inputs, encoding) = self
+        let tokenized = self
             .infer
             .tokenize(
                 inputs.clone(),
                 request.add_special_tokens,
                 request.prompt_name,
+                Some(request_id),
             )
             .await
             .map_err(ErrorResponse::from)?;
-        let inputs = encoded_inputs.unwrap_or(inputs);
+        let inputs = tokenized.full_text.unwrap_or(inputs);
 
-        let tokens: Vec<SimpleToken> = into_tokens(encoding, &inputs)
-            .into_iter()
-            .map(|t| {
-                let CoreSimpleToken {
-                    id,
-                    text,
-                    special,
-                    start,
-                    stop,
-                } = t;
-                SimpleToken {
-                    id,
-                    text,
-                    special,
-                    start: start.map(|s| s as u32),
-                    stop: stop.map(|s| s as u32),
-                }
-            })
-            .collect();
+        let tokens: Vec<SimpleToken> = into_tokens(
+            tokenized.encoding,
+            &inputs,
+            request.char_offsets,
+            InvalidUtf8Policy::default(),
+        )
+        .map_err(ErrorResponse::from)?
+        .into_iter()
+        .map(|t| {
+            let CoreSimpleToken {
+                id,
+                text,
+                special,
+                start,
+                stop,
+                word_id,
+                type_id,
+            } = t;
+            SimpleToken {
+                id,
+                text,
+                special,
+                start: start.map(|s| s as u32),
+                stop: stop.map(|s| s as u32),
+                word_id,
+                type_id,
+            }
+        })
+        .collect();
         Ok(EncodeResponse { tokens })
     }
 
@@ -868,6 +904,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
     #[instrument(
         skip_all,
         fields(
+            request_id,
             compute_chars,
             compute_tokens,
             total_time,
@@ -881,6 +918,8 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
         request: Request<RerankRequest>,
     ) -> Result<Response<RerankResponse>, Status> {
         let span = Span::current();
+        let request_id = RequestId::next();
+        span.record("request_id", request_id.0);
         let start_time = Instant::now();
 
         let request = request.into_inner();
@@ -930,6 +969,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
                     truncate,
                     truncation_direction,
                     raw_scores,
+                    Some(request_id),
                     permit,
                 )
                 .await
@@ -1059,6 +1099,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
     #[instrument(
         skip_all,
         fields(
+            request_id,
             compute_chars,
             compute_tokens,
             total_time,
@@ -1072,6 +1113,8 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
         request: Request<Streaming<RerankStreamRequest>>,
     ) -> Result<Response<RerankResponse>, Status> {
         let span = Span::current();
+        let request_id = RequestId::next();
+        span.record("request_id", request_id.0);
         let start_time = Instant::now();
 
         // Check model type
@@ -1108,6 +1151,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
                     truncate,
                     truncation_direction,
                     raw_scores,
+                    Some(request_id),
                     permit,
                 )
                 .await
@@ -2,12 +2,12 @@
 use crate::http::types::{
     DecodeRequest, DecodeResponse, EmbedAllRequest, EmbedAllResponse, EmbedRequest, EmbedResponse,
     EmbedSparseRequest, EmbedSparseResponse, Embedding, EncodingFormat, Input, InputIds, InputType,
-    OpenAICompatEmbedding, OpenAICompatErrorResponse, OpenAICompatRequest, OpenAICompatResponse,
-    OpenAICompatUsage, PredictInput, PredictRequest, PredictResponse, Prediction, Rank,
-    RerankRequest, RerankResponse, Sequence, SimilarityInput, SimilarityParameters,
-    SimilarityRequest, SimilarityResponse, SimpleToken, SparseValue, TokenizeInput,
-    TokenizeRequest, TokenizeResponse, TruncationDirection, VertexPrediction, VertexRequest,
-    VertexResponse,
+    InvalidUtf8Policy, OpenAICompatEmbedding, OpenAICompatErrorResponse, OpenAICompatRequest,
+    OpenAICompatResponse, OpenAICompatUsage, PredictInput, PredictRequest, PredictResponse,
+    Prediction, Rank, RerankRequest, RerankResponse, Sequence, SimilarityInput,
+    SimilarityParameters, SimilarityRequest, SimilarityResponse, SimpleToken, SparseValue,
+    TokenizeInput, TokenizeRequest, TokenizeResponse, TruncationDirection, VertexPrediction,
+    VertexRequest, VertexResponse,
 };
 use crate::{
     logging, shutdown, ClassifierModel, EmbeddingModel, ErrorResponse, ErrorType, Info, ModelType,
@@ -34,7 +34,7 @@ use text_embeddings_backend::BackendError;
 use text_embeddings_core::infer::{
     AllEmbeddingsInferResponse, Infer, InferMetadata, PooledEmbeddingsInferResponse,
 };
-use text_embeddings_core::tokenization::{into_tokens, SimpleToken as CoreSimpleToken};
+use text_embeddings_core::tokenization::{into_tokens, RequestId, SimpleToken as CoreSimpleToken};
 use text_embeddings_core::TextEmbeddingsError;
 use tokio::sync::OwnedSemaphorePermit;
 use tower_http::cors::{AllowOrigin, CorsLayer};
@@ -99,7 +99,7 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 )]
 #[instrument(
     skip_all,
-    fields(total_time, tokenization_time, queue_time, inference_time,)
+    fields(request_id, total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn predict(
     infer: Extension<Infer>,
@@ -108,6 +108,8 @@ async fn predict(
     Json(req): Json<PredictRequest>,
 ) -> Result<(HeaderMap, Json<PredictResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
+    let request_id = RequestId::next();
+    span.record("request_id", request_id.0);
     if let Some(context) = context {
         span.set_parent(context);
     }
@@ -131,6 +133,7 @@ async fn predict(
                 truncate,
                 req.truncation_direction.into(),
                 req.raw_scores,
+                Some(request_id),
                 permit,
             )
             .await
@@ -302,7 +305,7 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 )]
 #[instrument(
     skip_all,
-    fields(total_time, tokenization_time, queue_time, inference_time,)
+    fields(request_id, total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn rerank(
     infer: Extension<Infer>,
@@ -311,6 +314,8 @@ async fn rerank(
     Json(req): Json<RerankRequest>,
 ) -> Result<(HeaderMap, Json<RerankResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
+    let request_id = RequestId::next();
+    span.record("request_id", request_id.0);
     if let Some(context) = context {
         span.set_parent(context);
     }
@@ -355,6 +360,7 @@ async fn rerank(
                 truncate,
                 req.truncation_direction.into(),
                 req.raw_scores,
+                Some(request_id),
                 permit,
             )
             .await
@@ -580,7 +586,7 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 )]
 #[instrument(
     skip_all,
-    fields(total_time, tokenization_time, queue_time, inference_time,)
+    fields(request_id, total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn embed(
     infer: Extension<Infer>,
@@ -589,6 +595,8 @@ async fn embed(
     Json(req): Json<EmbedRequest>,
 ) -> Result<(HeaderMap, Json<EmbedResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
+    let request_id = RequestId::next();
+    span.record("request_id", request_id.0);
     if let Some(context) = context {
         span.set_parent(context);
     }
@@ -611,6 +619,7 @@ async fn embed(
                     req.truncation_direction.into(),
                     req.prompt_name,
                     req.normalize,
+                    Some(request_id),
                     permit,
                 )
                 .await
@@ -679,6 +688,7 @@ async fn embed(
                             req.truncation_direction.into(),
                             prompt_name,
                             req.normalize,
+                            Some(request_id),
                             permit,
                         )
                         .await
@@ -754,7 +764,7 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 )]
 #[instrument(
     skip_all,
-    fields(total_time, tokenization_time, queue_time, inference_time,)
+    fields(request_id, total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn embed_sparse(
     infer: Extension<Infer>,
@@ -763,6 +773,8 @@ async fn embed_sparse(
     Json(req): Json<EmbedSparseRequest>,
 ) -> Result<(HeaderMap, Json<EmbedSparseResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
+    let request_id = RequestId::next();
+    span.record("request_id", request_id.0);
     if let Some(context) = context {
         span.set_parent(context);
     }
@@ -793,6 +805,7 @@ async fn embed_sparse(
                     truncate,
                     req.truncation_direction.into(),
                     req.prompt_name,
+                    Some(request_id),
                     permit,
                 )
                 .await
@@ -860,6 +873,7 @@ async fn embed_sparse(
                             truncate,
                             req.truncation_direction.into(),
                             prompt_name,
+                            Some(request_id),
                             permit,
                         )
                         .await?;
@@ -937,7 +951,7 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 )]
 #[instrument(
     skip_all,
-    fields(total_time, tokenization_time, queue_time, inference_time,)
+    fields(request_id, total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn embed_all(
     infer: Extension<Infer>,
@@ -946,6 +960,8 @@ async fn embed_all(
     Json(req): Json<EmbedAllRequest>,
 ) -> Result<(HeaderMap, Json<EmbedAllResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
+    let request_id = RequestId::next();
+    span.record("request_id", request_id.0);
     if let Some(context) = context {
         span.set_parent(context);
     }
@@ -967,6 +983,7 @@ async fn embed_all(
                     truncate,
                     req.truncation_direction.into(),
                     req.prompt_name,
+                    Some(request_id),
                     permit,
                 )
                 .await
@@ -1034,6 +1051,7 @@ async fn embed_all(
                             truncate,
                             req.truncation_direction.into(),
                             prompt_name,
+                            Some(request_id),
                             permit,
                         )
                         .await
@@ -1109,7 +1127,7 @@ example = json ! ({"message": "Batch size error", "type": "validation"})),
 )]
 #[instrument(
     skip_all,
-    fields(total_time, tokenization_time, queue_time, inference_time,)
+    fields(request_id, total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn openai_embed(
     infer: Extension<Infer>,
@@ -1118,6 +1136,9 @@ async fn openai_embed(
     Json(req): Json<OpenAICompatRequest>,
 ) -> Result<(HeaderMap, Json<OpenAICompatResponse>), (StatusCode, Json<OpenAICompatErrorResponse>)>
 {
+    let request_id = RequestId::next();
+    tracing::Span::current().record("request_id", request_id.0);
+
     let encode_embedding = |array: Vec<f32>| {
         match req.encoding_format {
             EncodingFormat::Float => Embedding::Float(array),
@@ -1156,6 +1177,7 @@ async fn openai_embed(
                     tokenizers::TruncationDirection::Right,
                     None,
                     true,
+                    Some(request_id),
                     permit,
                 )
                 .await
@@ -1228,6 +1250,7 @@ async fn openai_embed(
                             tokenizers::TruncationDirection::Right,
                             None,
                             true,
+                            Some(request_id),
                             permit,
                         )
                         .await
@@ -1312,47 +1335,77 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
 )
 )]
-#[instrument(skip_all)]
+#[instrument(skip_all, fields(request_id))]
 async fn tokenize(
     infer: Extension<Infer>,
     info: Extension<Info>,
     Json(req): Json<TokenizeRequest>,
 ) -> Result<Json<TokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let request_id = RequestId::next();
+    tracing::Span::current().record("request_id", request_id.0);
+
     let tokenize_inner = move |input: String,
                                add_special_tokens: bool,
                                prompt_name: Option<String>,
+                               char_offsets: bool,
+                               invalid_utf8: InvalidUtf8Policy,
                                infer: Infer| async move {
-        let (encoded_input, encoding) = infer
-            .tokenize(input.clone(), add_special_tokens, prompt_name)
+        let tokenized = infer
+            .tokenize(
+                input.clone(),
+                add_special_tokens,
+                prompt_name,
+                Some(request_id),
+            )
             .await
             .map_err(ErrorResponse::from)?;
-        let input = encoded_input.unwrap_or(input);
+        let input = tokenized.full_text.unwrap_or(input);
 
-        let tokens: Vec<SimpleToken> = into_tokens(encoding, &input)
-            .into_iter()
-            .map(|t| {
-                let CoreSimpleToken {
-                    id,
-                    text,
-                    special,
-                    start,
-                    stop,
-                } = t;
-                SimpleToken {
-                    id,
-                    text,
-                    special,
-                    start,
-                    stop,
-                }
-            })
-            .collect();
+        let tokens: Vec<SimpleToken> = into_tokens(
+            tokenized.encoding,
+            &input,
+            char_offsets,
+            invalid_utf8.into(),
+        )
+        .map_err(ErrorResponse::from)?
+        .into_iter()
+        .map(|t| {
+            let CoreSimpleToken {
+                id,
+                text,
+                special,
+                start,
+                stop,
+                word_id,
+                type_id,
+            } = t;
+            SimpleToken {
+                id,
+                text,
+                special,
+                start,
+                stop,
+                word_id,
+                type_id,
+            }
+        })
+        .collect();
         Ok::<Vec<SimpleToken>, ErrorResponse>(tokens)
     };
 
     let tokens = match req.inputs {
         TokenizeInput::Single(input) => {
-            vec![tokenize_inner(input, req.add_special_tokens, req.prompt_name, infer.0).await?]
+            vec![
+                tokenize_inner(
+                    input,
+                    req.add_special_tokens,
+                    req.prompt_name,
+                    req.char_offsets,
+                    req.invalid_utf8,
+                    infer.0,
+                )
+                .await?,
+            ]
         }
         TokenizeInput::Batch(inputs) => {
             if inputs.is_empty() {
@@ -1389,6 +1442,8 @@ async fn tokenize(
                     input,
                     req.add_special_tokens,
                     req.prompt_name.clone(),
+                    req.char_offsets,
+                    req.invalid_utf8,
                     infer.0.clone(),
                 ));
             }
@@ -1650,6 +1705,7 @@ pub async fn run(
     TokenizeRequest,
     TokenizeResponse,
     TruncationDirection,
+    InvalidUtf8Policy,
     SimilarityInput,
     SimilarityParameters,
     SimilarityRequest,
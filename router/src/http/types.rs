@@ -387,6 +387,9 @@ pub(crate) struct SimilarityParameters {
     /// then the sentence "What is the capital of France?" will be encoded as
     /// "query: What is the capital of France?" because the prompt text will be prepended before
     /// any text to encode.
+    ///
+    /// Several comma separated names can be given, their prompts are prepended in order.
+    /// Escape a comma inside a name as `\,`.
     #[schema(default = "null", example = "null", nullable = true)]
     pub prompt_name: Option<String>,
 }
@@ -421,6 +424,9 @@ pub(crate) struct EmbedRequest {
     /// then the sentence "What is the capital of France?" will be encoded as
     /// "query: What is the capital of France?" because the prompt text will be prepended before
     /// any text to encode.
+    ///
+    /// Several comma separated names can be given, their prompts are prepended in order.
+    /// Escape a comma inside a name as `\,`.
     #[schema(default = "null", example = "null", nullable = true)]
     pub prompt_name: Option<String>,
     #[serde(default = "default_normalize")]
@@ -454,6 +460,9 @@ pub(crate) struct EmbedSparseRequest {
     /// then the sentence "What is the capital of France?" will be encoded as
     /// "query: What is the capital of France?" because the prompt text will be prepended before
     /// any text to encode.
+    ///
+    /// Several comma separated names can be given, their prompts are prepended in order.
+    /// Escape a comma inside a name as `\,`.
     #[schema(default = "null", example = "null", nullable = true)]
     pub prompt_name: Option<String>,
 }
@@ -485,6 +494,9 @@ pub(crate) struct EmbedAllRequest {
     /// then the sentence "What is the capital of France?" will be encoded as
     /// "query: What is the capital of France?" because the prompt text will be prepended before
     /// any text to encode.
+    ///
+    /// Several comma separated names can be given, their prompts are prepended in order.
+    /// Escape a comma inside a name as `\,`.
     #[schema(default = "null", example = "null", nullable = true)]
     pub prompt_name: Option<String>,
 }
@@ -523,8 +535,44 @@ pub(crate) struct TokenizeRequest {
     /// then the sentence "What is the capital of France?" will be encoded as
     /// "query: What is the capital of France?" because the prompt text will be prepended before
     /// any text to encode.
+    ///
+    /// Several comma separated names can be given, their prompts are prepended in order.
+    /// Escape a comma inside a name as `\,`.
     #[schema(default = "null", example = "null", nullable = true)]
     pub prompt_name: Option<String>,
+    /// Return `start` and `stop` as char offsets instead of byte offsets.
+    /// This requires an additional pass over the input.
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub char_offsets: bool,
+    /// How to fill `text` for the tokens whose offsets split a multi-byte character.
+    #[serde(default)]
+    #[schema(default = "Widen", example = "Widen")]
+    pub invalid_utf8: InvalidUtf8Policy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ToSchema, Eq, Default)]
+pub(crate) enum InvalidUtf8Policy {
+    /// Widen the offsets to the enclosing characters
+    #[default]
+    Widen,
+    /// Replace the partial characters with U+FFFD
+    Lossy,
+    /// Escape the bytes of the partial characters as `\xNN`
+    Escape,
+    /// Reject the request
+    Error,
+}
+
+impl From<InvalidUtf8Policy> for text_embeddings_core::tokenization::InvalidUtf8Policy {
+    fn from(value: InvalidUtf8Policy) -> Self {
+        match value {
+            InvalidUtf8Policy::Widen => Self::Widen,
+            InvalidUtf8Policy::Lossy => Self::Lossy,
+            InvalidUtf8Policy::Escape => Self::Escape,
+            InvalidUtf8Policy::Error => Self::Error,
+        }
+    }
 }
 
 fn default_add_special_tokens() -> bool {
@@ -543,10 +591,14 @@ pub(crate) struct SimpleToken {
     pub start: Option<usize>,
     #[schema(example = 2)]
     pub stop: Option<usize>,
+    #[schema(example = 0)]
+    pub word_id: Option<u32>,
+    #[schema(example = 0)]
+    pub type_id: u32,
 }
 
 #[derive(Serialize, ToSchema)]
-#[schema(example = json!([[{"id": 0, "text": "test", "special": false, "start": 0, "stop": 2}]]))]
+#[schema(example = json!([[{"id": 0, "text": "test", "special": false, "start": 0, "stop": 2, "word_id": 0, "type_id": 0}]]))]
 pub(crate) struct TokenizeResponse(pub Vec<Vec<SimpleToken>>);
 
 #[derive(Deserialize, ToSchema)]
@@ -30,8 +30,8 @@ use text_embeddings_backend::{DType, Pool};
 use text_embeddings_core::download::{download_artifacts, ST_CONFIG_NAMES};
 use text_embeddings_core::infer::Infer;
 use text_embeddings_core::queue::Queue;
-use text_embeddings_core::tokenization::Tokenization;
-use text_embeddings_core::TextEmbeddingsError;
+use text_embeddings_core::tokenization::{Tokenization, UnicodeNormalization};
+use text_embeddings_core::{TextEmbeddingsError, ValidationError};
 use tokenizers::processors::sequence::Sequence;
 use tokenizers::processors::template::TemplateProcessing;
 use tokenizers::{PostProcessorWrapper, Tokenizer};
@@ -45,6 +45,12 @@ pub async fn run(
     model_id: String,
     revision: Option<String>,
     tokenization_workers: Option<usize>,
+    tokenization_cache_size: usize,
+    tokenization_batch_parallelism: usize,
+    tokenization_normalization: Option<UnicodeNormalization>,
+    tokenization_stack_size: Option<usize>,
+    tokenization_queue_capacity: Option<usize>,
+    tokenization_decode_workers: usize,
     dtype: Option<DType>,
     pooling: Option<text_embeddings_backend::Pool>,
     max_concurrent_requests: usize,
@@ -139,7 +145,7 @@ pub async fn run(
     let mut tokenizer = Tokenizer::from_file(tokenizer_path).expect(
         "tokenizer.json not found. text-embeddings-inference only supports fast tokenizers",
     );
-    tokenizer.with_padding(None);
+    // The padding configuration is kept: `Tokenization` only pads when a request asks for it
     // Qwen2 updates the post processor manually instead of into the tokenizer.json...
     // https://huggingface.co/Alibaba-NLP/gte-Qwen2-1.5B-instruct/blob/main/tokenization_qwen.py#L246
     if config.model_type == "qwen2" {
@@ -195,15 +201,13 @@ pub async fn run(
     let tokenization_workers = tokenization_workers.unwrap_or_else(num_cpus::get);
 
     // Try to load new ST Config
-    let mut new_st_config: Option<NewSTConfig> = None;
     let config_path = model_root.join("config_sentence_transformers.json");
-    if let Ok(config) = fs::read_to_string(config_path) {
-        new_st_config = Some(
-            serde_json::from_str(&config)
-                .context("Failed to parse `config_sentence_transformers.json`")?,
-        );
-    }
-    let prompts = new_st_config.and_then(|c| c.prompts);
+    let prompts = if config_path.exists() {
+        let (_, prompts) = Tokenization::load_prompts_from_st_config(&config_path)?;
+        Some(prompts).filter(|prompts| !prompts.is_empty())
+    } else {
+        None
+    };
     let default_prompt = if let Some(default_prompt_name) = default_prompt_name.as_ref() {
         match &prompts {
             None => {
@@ -219,14 +223,20 @@ pub async fn run(
     };
 
     // Tokenization logic
-    let tokenization = Tokenization::new(
-        tokenization_workers,
-        tokenizer,
-        max_input_length,
-        position_offset,
-        default_prompt,
-        prompts,
-    );
+    let mut tokenization = Tokenization::builder(tokenizer, tokenization_workers, max_input_length)
+        .position_offset(position_offset)
+        .max_model_length((config.max_position_embeddings - position_offset).max(max_input_length))
+        .default_prompt(default_prompt)
+        .prompts(prompts)
+        .cache_capacity(tokenization_cache_size)
+        .batch_parallelism(tokenization_batch_parallelism)
+        .normalization(tokenization_normalization)
+        .stack_size(tokenization_stack_size)
+        .decode_workers(tokenization_decode_workers);
+    if let Some(queue_capacity) = tokenization_queue_capacity {
+        tokenization = tokenization.queue_capacity(queue_capacity);
+    }
+    let tokenization = tokenization.build()?;
 
     // Get dtype
     let dtype = dtype.unwrap_or_default();
@@ -452,11 +462,6 @@ pub struct STConfig {
     pub max_seq_length: usize,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct NewSTConfig {
-    pub prompts: Option<HashMap<String, String>>,
-}
-
 #[derive(Clone, Debug, Serialize)]
 #[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
 pub struct EmbeddingModel {
@@ -544,9 +549,13 @@ impl From<TextEmbeddingsError> for ErrorResponse {
     fn from(err: TextEmbeddingsError) -> Self {
         let error_type = match err {
             TextEmbeddingsError::Tokenizer(_) => ErrorType::Tokenizer,
+            TextEmbeddingsError::Validation(ValidationError::Empty) => ErrorType::Empty,
             TextEmbeddingsError::Validation(_) => ErrorType::Validation,
             TextEmbeddingsError::Overloaded(_) => ErrorType::Overloaded,
             TextEmbeddingsError::Backend(_) => ErrorType::Backend,
+            TextEmbeddingsError::WorkersGone => ErrorType::Unhealthy,
+            // The workers are saturated
+            TextEmbeddingsError::Timeout => ErrorType::Overloaded,
         };
         Self {
             error: err.to_string(),
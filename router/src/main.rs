@@ -2,6 +2,7 @@ use anyhow::Result;
 use clap::Parser;
 use opentelemetry::global;
 use text_embeddings_backend::DType;
+use text_embeddings_core::tokenization::UnicodeNormalization;
 use veil::Redact;
 
 #[cfg(not(target_os = "linux"))]
@@ -32,6 +33,46 @@ struct Args {
     #[clap(long, env)]
     tokenization_workers: Option<usize>,
 
+    /// Optionally cache the encodings of the most recent inputs to skip tokenizing inputs that
+    /// are sent repeatedly.
+    /// Default to 0, which disables the cache.
+    #[clap(default_value = "0", long, env)]
+    tokenization_cache_size: usize,
+
+    /// Optionally split large `encode_batch` batches across a pool of threads in a single worker.
+    /// Small batches are faster sequentially as the hand-off to the pool dominates.
+    /// Default to 1, which encodes batches sequentially.
+    #[clap(default_value = "1", long, env)]
+    tokenization_batch_parallelism: usize,
+
+    /// Optionally normalize the Unicode of the text inputs before tokenization, so that visually
+    /// identical inputs get the same tokens (e.g. full-width and half-width characters with `nfkc`).
+    #[clap(long, env, value_enum)]
+    tokenization_normalization: Option<UnicodeNormalization>,
+
+    /// Optionally set the stack size in bytes of the tokenization threads, for tokenizers whose
+    /// normalizers recurse deeply on some inputs.
+    /// Default to the Rust default stack size.
+    #[clap(long, env)]
+    tokenization_stack_size: Option<usize>,
+
+    /// Optionally control the number of requests waiting for a tokenization worker.
+    /// A small queue applies backpressure to the clients sooner, a large one uses more memory and
+    /// increases the tail latency.
+    /// Default to 4 times the number of tokenization workers.
+    #[clap(
+        long,
+        env,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    tokenization_queue_capacity: Option<usize>,
+
+    /// Optionally start tokenization workers dedicated to the `/decode` requests, with their own
+    /// queue, so that their latency does not depend on the embedding traffic.
+    /// Default to 0, which sends them to the tokenization workers.
+    #[clap(default_value = "0", long, env)]
+    tokenization_decode_workers: usize,
+
     /// The dtype to be forced upon the model.
     #[clap(long, env, value_enum)]
     dtype: Option<DType>,
@@ -87,7 +128,8 @@ struct Args {
     /// For example if ``default_prompt_name`` is "query" and the ``prompts`` is {"query": "query: ", ...},
     /// then the sentence "What is the capital of France?" will be encoded as
     /// "query: What is the capital of France?" because the prompt text will be prepended before
-    /// any text to encode.
+    /// any text to encode. If the prompt contains a `{text}` placeholder, the text replaces it
+    /// instead, so that the prompt can be placed before and/or after the text.
     ///
     /// The argument '--default-prompt-name <DEFAULT_PROMPT_NAME>' cannot be used with
     /// '--default-prompt <DEFAULT_PROMPT>`
@@ -99,7 +141,9 @@ struct Args {
     ///
     /// For example if ``default_prompt`` is "query: " then the sentence "What is the capital of
     /// France?" will be encoded as "query: What is the capital of France?" because the prompt
-    /// text will be prepended before any text to encode.
+    /// text will be prepended before any text to encode. If the prompt contains a `{text}`
+    /// placeholder, the text replaces it instead, so that the prompt can be placed before and/or
+    /// after the text.
     ///
     /// The argument '--default-prompt <DEFAULT_PROMPT>' cannot be used with
     /// '--default-prompt-name <DEFAULT_PROMPT_NAME>`
@@ -213,6 +257,12 @@ async fn main() -> Result<()> {
         args.model_id,
         args.revision,
         args.tokenization_workers,
+        args.tokenization_cache_size,
+        args.tokenization_batch_parallelism,
+        args.tokenization_normalization,
+        args.tokenization_stack_size,
+        args.tokenization_queue_capacity,
+        args.tokenization_decode_workers,
         args.dtype,
         args.pooling,
         args.max_concurrent_requests,
@@ -1,6 +1,7 @@
 use std::net::SocketAddr;
 
 use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+use text_embeddings_core::tokenization::MAX_CHAR_MULTIPLIER;
 
 pub(crate) fn prometheus_builer(
     addr: SocketAddr,
@@ -29,6 +30,13 @@ pub(crate) fn prometheus_builer(
         .filter(|x| (*x as usize) <= max_input_length)
         .collect();
 
+    // Input chars buckets
+    let input_chars_matcher = Matcher::Full(String::from("te_request_input_chars"));
+    let input_chars_buckets: Vec<f64> = (0..24)
+        .map(|x| 2.0_f64.powi(x))
+        .filter(|x| (*x as usize) <= max_input_length * MAX_CHAR_MULTIPLIER)
+        .collect();
+
     // Batch size buckets
     let batch_size_matcher = Matcher::Full(String::from("te_batch_next_size"));
     let batch_size_buckets: Vec<f64> = (0..13).map(|x| 2.0_f64.powi(x)).collect();
@@ -37,11 +45,17 @@ pub(crate) fn prometheus_builer(
     let batch_tokens_matcher = Matcher::Full(String::from("te_batch_next_tokens"));
     let batch_tokens_buckets: Vec<f64> = (0..21).map(|x| 2.0_f64.powi(x)).collect();
 
+    // Batch dedup ratio buckets
+    let batch_dedup_ratio_matcher = Matcher::Full(String::from("te_batch_dedup_ratio"));
+    let batch_dedup_ratio_buckets: Vec<f64> = (0..=10).map(|x| x as f64 / 10.0).collect();
+
     // Prometheus handler
     PrometheusBuilder::new()
         .with_http_listener(addr)
         .set_buckets_for_metric(duration_matcher, &duration_buckets)?
         .set_buckets_for_metric(input_length_matcher, &input_length_buckets)?
+        .set_buckets_for_metric(input_chars_matcher, &input_chars_buckets)?
         .set_buckets_for_metric(batch_size_matcher, &batch_size_buckets)?
-        .set_buckets_for_metric(batch_tokens_matcher, &batch_tokens_buckets)
+        .set_buckets_for_metric(batch_tokens_matcher, &batch_tokens_buckets)?
+        .set_buckets_for_metric(batch_dedup_ratio_matcher, &batch_dedup_ratio_buckets)
 }
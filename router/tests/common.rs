@@ -49,6 +49,12 @@ pub async fn start_server(model_id: String, revision: Option<String>, dtype: DTy
             model_id,
             revision,
             Some(1),
+            0,
+            1,
+            None,
+            None,
+            None,
+            0,
             Some(dtype),
             None,
             4,